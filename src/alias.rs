@@ -0,0 +1,350 @@
+use std::convert::TryInto;
+
+use super::hfs::{CatalogKind, HfsVolume};
+use super::util;
+
+/// Alias record version written by Mac OS X
+const ALIAS_VERSION: u16 = 2;
+
+/// Size of the fixed part of a version 2 alias record
+const ALIAS_HEADER_SIZE: usize = 150;
+
+/// kEjectableDisk - what Finder uses for attached disk images
+const EJECTABLE_DISK: u16 = 5;
+
+/// Extra data in an alias record, after the fixed part
+const TAG_FOLDER_NAME: i16 = 0;
+const TAG_CNID_PATH: i16 = 1;
+const TAG_CARBON_PATH: i16 = 2;
+const TAG_UNICODE_FILENAME: i16 = 14;
+const TAG_UNICODE_VOLUME_NAME: i16 = 15;
+const TAG_HIGH_RES_VOLUME_DATE: i16 = 16;
+const TAG_HIGH_RES_CREATE_DATE: i16 = 17;
+const TAG_POSIX_PATH: i16 = 18;
+const TAG_POSIX_MOUNT_POINT: i16 = 19;
+const TAG_END: i16 = -1;
+
+/// A classic Mac OS alias record ("alis"), as used by Finder for the
+/// background picture of a window. Finder resolves it by matching the
+/// volume name and creation date, then the CNIDs, then the paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alias {
+    /// true if the target is a folder
+    pub folder: bool,
+    /// Name of the volume containing the target
+    pub volume_name: String,
+    /// Volume creation date, seconds since 1904
+    pub volume_date: u32,
+    /// CNID of the folder containing the target
+    pub parent_id: u32,
+    /// Name of the target
+    pub filename: String,
+    /// CNID of the target
+    pub target_id: u32,
+    /// Target creation date, seconds since 1904
+    pub create_date: u32,
+    pub file_type: [u8; 4],
+    pub creator: [u8; 4],
+    /// Name of the folder containing the target
+    pub folder_name: String,
+    /// CNIDs of the folders containing the target, nearest first
+    pub cnid_path: Vec<u32>,
+    /// Path of the target from the root of the volume, e.g. "/.background/background.tiff"
+    pub path: String,
+}
+
+impl Alias {
+    /// Build an alias to a file or folder on an HFS+ volume, using the
+    /// CNIDs and dates the volume was created with
+    pub fn for_path(volume: &HfsVolume, path: &str) -> Result<Alias, String> {
+        let target = volume
+            .lookup(path)
+            .ok_or_else(|| format!("{} does not exist on the volume", path))?;
+
+        let cnid_path = volume.ancestors(target);
+        let folder_name = match cnid_path.first() {
+            Some(parent) => volume.records.iter().find(|r| r.id == *parent).map(|r| r.name.clone()).unwrap_or_default(),
+            None => volume.name(),
+        };
+
+        // use the names as stored on the volume, which may differ in case
+        let mut components: Vec<String> = cnid_path
+            .iter()
+            .rev()
+            .filter_map(|id| volume.records.iter().find(|r| r.id == *id).map(|r| r.name.clone()))
+            .collect();
+        components.push(target.name.clone());
+
+        Ok(Alias {
+            folder: target.kind == CatalogKind::Folder,
+            volume_name: volume.name(),
+            volume_date: volume.header.create_date,
+            parent_id: target.parent_id,
+            filename: target.name.clone(),
+            target_id: target.id,
+            create_date: target.create_date,
+            file_type: target.file_type,
+            creator: target.creator,
+            folder_name,
+            cnid_path,
+            path: format!("/{}", components.join("/")),
+        })
+    }
+
+    /// Build an alias without a volume to look things up in. The CNIDs
+    /// and dates are zero, so Finder has to fall back to the paths;
+    /// use convert --layout to fill them in from the final volume.
+    pub fn placeholder(volume_name: &str, path: &str) -> Alias {
+        let mut components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let path = format!("/{}", components.join("/"));
+        let filename = components.pop().unwrap_or_default().to_string();
+
+        Alias {
+            folder: false,
+            volume_name: volume_name.to_string(),
+            volume_date: 0,
+            parent_id: 0,
+            filename,
+            target_id: 0,
+            create_date: 0,
+            file_type: [0u8; 4],
+            creator: [0u8; 4],
+            folder_name: components.last().map(|c| c.to_string()).unwrap_or_else(|| volume_name.to_string()),
+            cnid_path: vec![0; components.len()],
+            path,
+        }
+    }
+
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        // application specific info, then the record size (filled in below)
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&ALIAS_VERSION.to_be_bytes());
+        buffer.extend_from_slice(&u16::from(self.folder).to_be_bytes());
+        buffer.append(&mut pascal_string(&self.volume_name, 28));
+        buffer.extend_from_slice(&self.volume_date.to_be_bytes());
+        buffer.extend_from_slice(b"H+");
+        buffer.extend_from_slice(&EJECTABLE_DISK.to_be_bytes());
+        buffer.extend_from_slice(&self.parent_id.to_be_bytes());
+        buffer.append(&mut pascal_string(&self.filename, 64));
+        buffer.extend_from_slice(&self.target_id.to_be_bytes());
+        buffer.extend_from_slice(&self.create_date.to_be_bytes());
+        buffer.extend_from_slice(&self.file_type);
+        buffer.extend_from_slice(&self.creator);
+        // levels from / to, unknown for an alias created outside of Finder
+        buffer.extend_from_slice(&(-1i16).to_be_bytes());
+        buffer.extend_from_slice(&(-1i16).to_be_bytes());
+        // volume attributes and file system id
+        buffer.extend_from_slice(&0u32.to_be_bytes());
+        buffer.extend_from_slice(&[0u8; 2]);
+        buffer.extend_from_slice(&[0u8; 10]);
+
+        let cnids: Vec<u8> = self.cnid_path.iter().flat_map(|id| id.to_be_bytes().to_vec()).collect();
+        let folders: Vec<&str> = self.path.split('/').filter(|c| !c.is_empty()).collect();
+        let carbon_path = match folders.split_last() {
+            Some((_, parents)) if !parents.is_empty() => format!("{}:{}:\0{}", self.volume_name, parents.join(":"), self.filename),
+            _ => format!("{}:\0{}", self.volume_name, self.filename),
+        };
+
+        push_tag(&mut buffer, TAG_FOLDER_NAME, &util::to_mac_roman(&self.folder_name));
+        push_tag(&mut buffer, TAG_CNID_PATH, &cnids);
+        push_tag(&mut buffer, TAG_CARBON_PATH, &util::to_mac_roman(&carbon_path));
+        push_tag(&mut buffer, TAG_UNICODE_FILENAME, &unicode_string(&self.filename));
+        push_tag(&mut buffer, TAG_UNICODE_VOLUME_NAME, &unicode_string(&self.volume_name));
+        push_tag(&mut buffer, TAG_HIGH_RES_VOLUME_DATE, &(u64::from(self.volume_date) << 16).to_be_bytes());
+        push_tag(&mut buffer, TAG_HIGH_RES_CREATE_DATE, &(u64::from(self.create_date) << 16).to_be_bytes());
+        push_tag(&mut buffer, TAG_POSIX_PATH, self.path.as_bytes());
+        push_tag(&mut buffer, TAG_POSIX_MOUNT_POINT, format!("/Volumes/{}", self.volume_name).as_bytes());
+        push_tag(&mut buffer, TAG_END, &[]);
+
+        let size = buffer.len() as u16;
+        buffer[4..6].copy_from_slice(&size.to_be_bytes());
+
+        buffer
+    }
+
+    /// Parse an alias record. Only the fields we write are kept.
+    pub fn from_be_bytes(buffer: &[u8]) -> Result<Alias, String> {
+        if buffer.len() < ALIAS_HEADER_SIZE {
+            return Err("Truncated alias record".to_string());
+        }
+        if util::read_be_u16(&mut &buffer[6..8]) != ALIAS_VERSION {
+            return Err("Unsupported alias record version".to_string());
+        }
+
+        let mut alias = Alias {
+            folder: util::read_be_u16(&mut &buffer[8..10]) == 1,
+            volume_name: read_pascal_string(&buffer[10..38]),
+            volume_date: util::read_be_u32(&mut &buffer[38..42]),
+            parent_id: util::read_be_u32(&mut &buffer[46..50]),
+            filename: read_pascal_string(&buffer[50..114]),
+            target_id: util::read_be_u32(&mut &buffer[114..118]),
+            create_date: util::read_be_u32(&mut &buffer[118..122]),
+            file_type: buffer[122..126].try_into().unwrap(),
+            creator: buffer[126..130].try_into().unwrap(),
+            folder_name: String::new(),
+            cnid_path: Vec::new(),
+            path: String::new(),
+        };
+
+        let mut position = ALIAS_HEADER_SIZE;
+        while position + 4 <= buffer.len() {
+            let tag = util::read_be_u16(&mut &buffer[position..position + 2]) as i16;
+            let length = util::read_be_u16(&mut &buffer[position + 2..position + 4]) as usize;
+            if tag == TAG_END {
+                break;
+            }
+
+            let data = buffer
+                .get(position + 4..position + 4 + length)
+                .ok_or("Alias record tag is out of bounds")?;
+
+            match tag {
+                TAG_FOLDER_NAME => alias.folder_name = util::from_mac_roman(data),
+                TAG_CNID_PATH => alias.cnid_path = data.chunks_exact(4).map(|id| util::read_be_u32(&mut &id[..])).collect(),
+                TAG_UNICODE_FILENAME => alias.filename = read_unicode_string(data),
+                TAG_UNICODE_VOLUME_NAME => alias.volume_name = read_unicode_string(data),
+                TAG_POSIX_PATH => alias.path = String::from_utf8_lossy(data).to_string(),
+                _ => (),
+            }
+
+            // tag data is padded to an even length
+            position += 4 + length + length % 2;
+        }
+
+        if alias.path.is_empty() {
+            return Err("Alias record has no POSIX path".to_string());
+        }
+
+        Ok(alias)
+    }
+}
+
+/// Mac OS Roman Pascal string in a fixed size field, length byte included
+fn pascal_string(string: &str, size: usize) -> Vec<u8> {
+    let mut encoded = util::to_mac_roman(string);
+    encoded.truncate(size - 1);
+
+    let mut field = vec![encoded.len() as u8];
+    field.append(&mut encoded);
+    field.resize(size, 0);
+    field
+}
+
+fn read_pascal_string(field: &[u8]) -> String {
+    let length = std::cmp::min(field[0] as usize, field.len() - 1);
+    util::from_mac_roman(&field[1..=length])
+}
+
+/// Character count, then UTF-16
+fn unicode_string(string: &str) -> Vec<u8> {
+    let units: Vec<u16> = string.encode_utf16().collect();
+    let mut data = (units.len() as u16).to_be_bytes().to_vec();
+    for unit in units {
+        data.extend_from_slice(&unit.to_be_bytes());
+    }
+    data
+}
+
+fn read_unicode_string(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|u| u16::from_be_bytes([u[0], u[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn push_tag(buffer: &mut Vec<u8>, tag: i16, data: &[u8]) {
+    buffer.extend_from_slice(&tag.to_be_bytes());
+    buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buffer.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias() -> Alias {
+        Alias {
+            folder: false,
+            volume_name: "Bitcoin-Core".to_string(),
+            volume_date: 3_000_000_000,
+            parent_id: 18,
+            filename: "background.tiff".to_string(),
+            target_id: 19,
+            create_date: 3_000_000_001,
+            file_type: *b"TIFF",
+            creator: *b"prvw",
+            folder_name: ".background".to_string(),
+            cnid_path: vec![18],
+            path: "/.background/background.tiff".to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let alias = alias();
+        assert_eq!(Alias::from_be_bytes(&alias.to_be_bytes()).unwrap(), alias);
+    }
+
+    #[test]
+    fn round_trip_non_ascii_names() {
+        let mut alias = alias();
+        alias.volume_name = "Café".to_string();
+        alias.filename = "fond d’écran.png".to_string();
+        alias.path = "/.background/fond d’écran.png".to_string();
+        assert_eq!(Alias::from_be_bytes(&alias.to_be_bytes()).unwrap(), alias);
+    }
+
+    #[test]
+    fn placeholder_paths() {
+        let alias = Alias::placeholder("Bitcoin-Core", ".background/background.tiff");
+        assert_eq!(alias.path, "/.background/background.tiff");
+        assert_eq!(alias.filename, "background.tiff");
+        assert_eq!(alias.folder_name, ".background");
+        assert_eq!(alias.cnid_path, vec![0]);
+
+        let parsed = Alias::from_be_bytes(&alias.to_be_bytes()).unwrap();
+        assert_eq!(parsed, alias);
+    }
+
+    #[test]
+    fn record_size() {
+        let bytes = alias().to_be_bytes();
+        assert_eq!(util::read_be_u16(&mut &bytes[4..6]) as usize, bytes.len());
+    }
+
+    #[test]
+    fn truncated_record() {
+        let bytes = alias().to_be_bytes();
+        assert!(Alias::from_be_bytes(&bytes[..ALIAS_HEADER_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn tag_out_of_bounds() {
+        let mut bytes = alias().to_be_bytes();
+        // the length of the first tag, the folder name
+        bytes[ALIAS_HEADER_SIZE + 2..ALIAS_HEADER_SIZE + 4].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(Alias::from_be_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn no_posix_path() {
+        let bytes = alias().to_be_bytes();
+        assert!(Alias::from_be_bytes(&bytes[..ALIAS_HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = alias().to_be_bytes();
+        bytes[6..8].copy_from_slice(&3u16.to_be_bytes());
+        assert!(Alias::from_be_bytes(&bytes).is_err());
+    }
+}
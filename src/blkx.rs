@@ -4,7 +4,7 @@ use super::xml::XMLError;
 pub const BLKX_CHUNK_ENTRY_SIZE: usize = 40;
 
 /// DMG blxx types
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DmgBlxx {
     /// Zero fill - 0x00000000
     ZeroFill,
@@ -18,6 +18,8 @@ pub enum DmgBlxx {
     ZLibCompression,
     /// bz2lib data compression - 0x80000006
     Bz2Compression,
+    /// LZFSE data compression - 0x80000007
+    LzfseCompression,
     /// No blocks - Comment: +beg and +end - 0x7FFFFFFE
     Comment,
     /// No blocks - Identifies the last blxx entry - 0xFFFFFFFF
@@ -36,6 +38,7 @@ impl DmgBlxx {
             2_147_483_652 => Some(DmgBlxx::AppleCompression),
             2_147_483_653 => Some(DmgBlxx::ZLibCompression),
             2_147_483_654 => Some(DmgBlxx::Bz2Compression),
+            2_147_483_655 => Some(DmgBlxx::LzfseCompression),
             4_294_967_294 => Some(DmgBlxx::Comment),
             4_294_967_295 => Some(DmgBlxx::LastEntry),
             _ => None,
@@ -50,6 +53,7 @@ impl DmgBlxx {
             DmgBlxx::AppleCompression => 2_147_483_652u32.to_be_bytes(),
             DmgBlxx::ZLibCompression => 2_147_483_653u32.to_be_bytes(),
             DmgBlxx::Bz2Compression => 2_147_483_654u32.to_be_bytes(),
+            DmgBlxx::LzfseCompression => 2_147_483_655u32.to_be_bytes(),
             DmgBlxx::Comment => 4_294_967_294u32.to_be_bytes(),
             DmgBlxx::LastEntry => 4_294_967_295u32.to_be_bytes(),
         };
@@ -57,7 +61,7 @@ impl DmgBlxx {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BlkxChunkEntry {
     /// Compression type used or entry type
     pub entry_type: DmgBlxx,
@@ -0,0 +1,468 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+use super::util;
+use super::xml::XMLError;
+
+/// "bplist00" in ASCII
+pub const BPLIST_MAGIC: &[u8; 8] = b"bplist00";
+
+/// A value in a binary property list
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Real(f64),
+    /// Seconds since 2001-01-01 00:00:00 UTC
+    Date(f64),
+    Data(Vec<u8>),
+    String(String),
+    Array(Vec<Value>),
+    Dictionary(BTreeMap<String, Value>),
+}
+
+/// Serialize a value as a bplist00 binary property list.
+/// Objects are written depth first, with the root object first
+/// and no deduplication.
+pub fn write(root: &Value) -> Vec<u8> {
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    let count = count_objects(root);
+    let ref_size = if count < 256 { 1 } else if count < 65_536 { 2 } else { 4 };
+
+    flatten(root, ref_size, &mut objects);
+
+    let mut buffer = BPLIST_MAGIC.to_vec();
+    let mut offsets = Vec::new();
+    for object in objects {
+        offsets.push(buffer.len() as u64);
+        buffer.extend_from_slice(&object);
+    }
+
+    let offset_table_offset = buffer.len() as u64;
+    let offset_size = int_size(offset_table_offset);
+    for offset in &offsets {
+        buffer.extend_from_slice(&offset.to_be_bytes()[8 - offset_size..]);
+    }
+
+    // trailer - 6 unused bytes, offset size, object ref size,
+    // object count, top object, offset table offset
+    buffer.extend_from_slice(&[0u8; 6]);
+    buffer.push(offset_size as u8);
+    buffer.push(ref_size as u8);
+    buffer.extend_from_slice(&(offsets.len() as u64).to_be_bytes());
+    buffer.extend_from_slice(&0u64.to_be_bytes());
+    buffer.extend_from_slice(&offset_table_offset.to_be_bytes());
+
+    buffer
+}
+
+fn count_objects(value: &Value) -> usize {
+    match value {
+        Value::Array(values) => 1 + values.iter().map(count_objects).sum::<usize>(),
+        Value::Dictionary(dict) => 1 + dict.len() + dict.values().map(count_objects).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+/// Append the object for value (and then its children) to objects,
+/// returning its object reference
+fn flatten(value: &Value, ref_size: usize, objects: &mut Vec<Vec<u8>>) -> usize {
+    let index = objects.len();
+    objects.push(Vec::new());
+
+    let object = match value {
+        Value::Bool(false) => vec![0x08],
+        Value::Bool(true) => vec![0x09],
+        Value::Integer(int) => encode_int(*int),
+        Value::Real(real) => {
+            let mut object = vec![0x23];
+            object.extend_from_slice(&real.to_be_bytes());
+            object
+        }
+        Value::Date(date) => {
+            let mut object = vec![0x33];
+            object.extend_from_slice(&date.to_be_bytes());
+            object
+        }
+        Value::Data(data) => {
+            let mut object = marker(0x40, data.len());
+            object.extend_from_slice(data);
+            object
+        }
+        Value::String(string) => encode_string(string),
+        Value::Array(values) => {
+            let refs: Vec<usize> = values.iter().map(|v| flatten(v, ref_size, objects)).collect();
+            let mut object = marker(0xA0, refs.len());
+            for r in refs {
+                object.extend_from_slice(&(r as u64).to_be_bytes()[8 - ref_size..]);
+            }
+            object
+        }
+        Value::Dictionary(dict) => {
+            let keys: Vec<usize> = dict.keys().map(|k| flatten(&Value::String(k.clone()), ref_size, objects)).collect();
+            let values: Vec<usize> = dict.values().map(|v| flatten(v, ref_size, objects)).collect();
+            let mut object = marker(0xD0, keys.len());
+            for r in keys.into_iter().chain(values) {
+                object.extend_from_slice(&(r as u64).to_be_bytes()[8 - ref_size..]);
+            }
+            object
+        }
+    };
+
+    objects[index] = object;
+    index
+}
+
+/// Object marker, with the length inline or as a following integer
+fn marker(kind: u8, length: usize) -> Vec<u8> {
+    if length < 15 {
+        vec![kind | length as u8]
+    } else {
+        let mut object = vec![kind | 0x0F];
+        object.append(&mut encode_int(length as i64));
+        object
+    }
+}
+
+fn encode_int(int: i64) -> Vec<u8> {
+    // negative numbers are always stored in 8 bytes
+    let (marker, bytes) = match int {
+        0..=0xFF => (0x10, 1),
+        0x100..=0xFFFF => (0x11, 2),
+        0x1_0000..=0xFFFF_FFFF => (0x12, 4),
+        _ => (0x13, 8),
+    };
+
+    let mut object = vec![marker];
+    object.extend_from_slice(&int.to_be_bytes()[8 - bytes..]);
+    object
+}
+
+fn encode_string(string: &str) -> Vec<u8> {
+    if string.is_ascii() {
+        let mut object = marker(0x50, string.len());
+        object.extend_from_slice(string.as_bytes());
+        return object;
+    }
+
+    let units: Vec<u16> = string.encode_utf16().collect();
+    let mut object = marker(0x60, units.len());
+    for unit in units {
+        object.extend_from_slice(&unit.to_be_bytes());
+    }
+    object
+}
+
+fn int_size(max: u64) -> usize {
+    match max {
+        0..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        0x1_0000..=0xFFFF_FFFF => 4,
+        _ => 8,
+    }
+}
+
+/// Parse a bplist00 binary property list
+pub fn read(buffer: &[u8]) -> Result<Value, XMLError> {
+    if buffer.len() < 8 + 32 || &buffer[0..8] != BPLIST_MAGIC {
+        return Err(invalid("Invalid bplist magic bytes"));
+    }
+
+    let trailer = &buffer[buffer.len() - 32..];
+    let offset_size = trailer[6] as usize;
+    let ref_size = trailer[7] as usize;
+    let object_count = util::read_be_u64(&mut &trailer[8..16]);
+    let top_object = util::read_be_u64(&mut &trailer[16..24]);
+    let offset_table_offset = util::read_be_u64(&mut &trailer[24..32]);
+
+    if !(1..=8).contains(&offset_size) || !(1..=8).contains(&ref_size) {
+        return Err(invalid("Invalid bplist trailer"));
+    }
+
+    let table_end = object_count
+        .checked_mul(offset_size as u64)
+        .and_then(|size| size.checked_add(offset_table_offset))
+        .ok_or_else(|| invalid("Invalid bplist offset table"))?;
+    if table_end > (buffer.len() - 32) as u64 {
+        return Err(invalid("bplist offset table is out of bounds"));
+    }
+
+    let table = &buffer[offset_table_offset as usize..table_end as usize];
+    let offsets: Vec<u64> = table.chunks_exact(offset_size).map(read_sized).collect();
+
+    let reader = Reader { buffer, offsets, ref_size, decoded: RefCell::new(HashMap::new()), values: Cell::new(0) };
+    reader.object(top_object, 0).map(|(value, _)| value)
+}
+
+/// Objects can reference each other, so limit how deep we go
+const MAX_DEPTH: usize = 256;
+
+/// Objects can be referenced many times over, and each reference becomes
+/// its own copy, so limit how many values are made in all
+const MAX_VALUES: usize = 1_000_000;
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offsets: Vec<u64>,
+    ref_size: usize,
+    /// Objects already decoded, and how many values each holds
+    decoded: RefCell<HashMap<u64, (Value, usize)>>,
+    /// Values made so far
+    values: Cell<usize>,
+}
+
+impl<'a> Reader<'a> {
+    /// Decode an object once, copying it for any later references.
+    /// Returns the value, and how many values it holds.
+    fn object(&self, reference: u64, depth: usize) -> Result<(Value, usize), XMLError> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("bplist is nested too deeply"));
+        }
+
+        if let Some((value, size)) = self.decoded.borrow().get(&reference) {
+            self.count(*size)?;
+            return Ok((value.clone(), *size));
+        }
+
+        let (value, size) = self.decode(reference, depth)?;
+        self.count(1)?;
+        self.decoded.borrow_mut().insert(reference, (value.clone(), size));
+        Ok((value, size))
+    }
+
+    fn count(&self, values: usize) -> Result<(), XMLError> {
+        let total = self.values.get() + values;
+        if total > MAX_VALUES {
+            return Err(invalid("bplist has too many values"));
+        }
+        self.values.set(total);
+        Ok(())
+    }
+
+    fn decode(&self, reference: u64, depth: usize) -> Result<(Value, usize), XMLError> {
+        let offset = *self.offsets
+            .get(reference as usize)
+            .ok_or_else(|| invalid(&format!("bplist object {} does not exist", reference)))? as usize;
+        let marker = *self.buffer.get(offset).ok_or_else(|| invalid("bplist object is out of bounds"))?;
+        let low = (marker & 0x0F) as usize;
+        // this object and everything in it
+        let mut size = 1;
+
+        let value = match marker >> 4 {
+            0x0 => match marker {
+                0x08 => Ok(Value::Bool(false)),
+                0x09 => Ok(Value::Bool(true)),
+                _ => Err(invalid(&format!("Unsupported bplist marker {:#X}", marker))),
+            },
+            0x1 => {
+                let bytes = self.slice(offset + 1, 1 << low)?;
+                Ok(Value::Integer(read_sized(bytes) as i64))
+            }
+            0x2 | 0x3 => {
+                let bytes = self.slice(offset + 1, 1 << low)?;
+                let real = match bytes.len() {
+                    4 => f64::from(f32::from_be_bytes(bytes.try_into().unwrap())),
+                    8 => f64::from_be_bytes(bytes.try_into().unwrap()),
+                    _ => return Err(invalid("Unsupported bplist real size")),
+                };
+                if marker >> 4 == 0x3 {
+                    Ok(Value::Date(real))
+                } else {
+                    Ok(Value::Real(real))
+                }
+            }
+            0x4 => {
+                let (start, length) = self.length(offset, low)?;
+                Ok(Value::Data(self.slice(start, length)?.to_vec()))
+            }
+            0x5 => {
+                let (start, length) = self.length(offset, low)?;
+                Ok(Value::String(String::from_utf8_lossy(self.slice(start, length)?).to_string()))
+            }
+            0x6 => {
+                let (start, length) = self.length(offset, low)?;
+                let units: Vec<u16> = self.slice(start, length * 2)?
+                    .chunks_exact(2)
+                    .map(|u| u16::from_be_bytes([u[0], u[1]]))
+                    .collect();
+                Ok(Value::String(String::from_utf16_lossy(&units)))
+            }
+            0xA => {
+                let (start, length) = self.length(offset, low)?;
+                let mut values = Vec::new();
+                for r in self.slice(start, length * self.ref_size)?.chunks_exact(self.ref_size) {
+                    let (value, value_size) = self.object(read_sized(r), depth + 1)?;
+                    values.push(value);
+                    size += value_size;
+                }
+                Ok(Value::Array(values))
+            }
+            0xD => {
+                let (start, length) = self.length(offset, low)?;
+                let refs = self.slice(start, length * 2 * self.ref_size)?;
+                let (keys, values) = refs.split_at(length * self.ref_size);
+
+                let mut dict = BTreeMap::new();
+                for (key, value) in keys.chunks_exact(self.ref_size).zip(values.chunks_exact(self.ref_size)) {
+                    let key = match self.object(read_sized(key), depth + 1)? {
+                        (Value::String(key), _) => key,
+                        _ => return Err(invalid("bplist dictionary key is not a string")),
+                    };
+                    let (value, value_size) = self.object(read_sized(value), depth + 1)?;
+                    dict.insert(key, value);
+                    size += 1 + value_size;
+                }
+                Ok(Value::Dictionary(dict))
+            }
+            _ => Err(invalid(&format!("Unsupported bplist marker {:#X}", marker))),
+        };
+
+        Ok((value?, size))
+    }
+
+    /// Length of a data, string or collection object, and where its contents start
+    fn length(&self, offset: usize, low: usize) -> Result<(usize, usize), XMLError> {
+        if low != 0x0F {
+            return Ok((offset + 1, low));
+        }
+
+        let marker = *self.buffer.get(offset + 1).ok_or_else(|| invalid("bplist object is out of bounds"))?;
+        if marker >> 4 != 0x1 {
+            return Err(invalid("bplist length is not an integer"));
+        }
+
+        let size = 1 << (marker & 0x0F);
+        let length = read_sized(self.slice(offset + 2, size)?) as usize;
+        if length > self.buffer.len() {
+            return Err(invalid("bplist object is out of bounds"));
+        }
+
+        Ok((offset + 2 + size, length))
+    }
+
+    fn slice(&self, start: usize, length: usize) -> Result<&'a [u8], XMLError> {
+        start
+            .checked_add(length)
+            .and_then(|end| self.buffer.get(start..end))
+            .ok_or_else(|| invalid("bplist object is out of bounds"))
+    }
+}
+
+fn invalid(message: &str) -> XMLError {
+    XMLError::BPList(message.to_string())
+}
+
+/// Read a big endian integer of 1 to 8 bytes
+fn read_sized(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+impl Value {
+    /// Convert into JSON, data is base64 encoded
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Bool(b) => serde_json::Value::from(*b),
+            Value::Integer(int) => serde_json::Value::from(*int),
+            Value::Real(real) => serde_json::Value::from(*real),
+            Value::Date(date) => serde_json::json!({ "date": date }),
+            Value::Data(data) => serde_json::json!({ "data": base64::encode(data) }),
+            Value::String(string) => serde_json::Value::from(string.clone()),
+            Value::Array(values) => serde_json::Value::Array(values.iter().map(Value::to_json).collect()),
+            Value::Dictionary(dict) => serde_json::Value::Object(
+                dict.iter().map(|(k, v)| (k.clone(), v.to_json())).collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bplist of objects written as they are, with 1 byte references
+    fn raw(objects: &[Vec<u8>], top: u64) -> Vec<u8> {
+        let mut buffer = BPLIST_MAGIC.to_vec();
+        let mut offsets = Vec::new();
+        for object in objects {
+            offsets.push(buffer.len() as u16);
+            buffer.extend_from_slice(object);
+        }
+
+        let table = buffer.len() as u64;
+        for offset in offsets {
+            buffer.extend_from_slice(&offset.to_be_bytes());
+        }
+        buffer.extend_from_slice(&[0u8; 6]);
+        buffer.push(2);
+        buffer.push(1);
+        buffer.extend_from_slice(&(objects.len() as u64).to_be_bytes());
+        buffer.extend_from_slice(&top.to_be_bytes());
+        buffer.extend_from_slice(&table.to_be_bytes());
+        buffer
+    }
+
+    /// Object 0 is true, every other is an array holding the one
+    /// before it twice, so the top one expands to 2^levels values
+    fn shared(levels: u8) -> Vec<u8> {
+        let mut objects = vec![vec![0x09]];
+        for level in 1..=levels {
+            objects.push(vec![0xA2, level - 1, level - 1]);
+        }
+        raw(&objects, u64::from(levels))
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut dict = BTreeMap::new();
+        dict.insert("bool".to_string(), Value::Bool(true));
+        dict.insert("small".to_string(), Value::Integer(7));
+        dict.insert("large".to_string(), Value::Integer(0x1_0000_0000));
+        dict.insert("negative".to_string(), Value::Integer(-1));
+        dict.insert("real".to_string(), Value::Real(0.5));
+        dict.insert("date".to_string(), Value::Date(700_000_000.0));
+        dict.insert("data".to_string(), Value::Data(vec![0xAB; 40]));
+        dict.insert("ascii".to_string(), Value::String("a string longer than fifteen".to_string()));
+        dict.insert("unicode".to_string(), Value::String("caf\u{e9}".to_string()));
+        dict.insert("array".to_string(), Value::Array(vec![Value::Bool(false), Value::Array(Vec::new())]));
+        let root = Value::Dictionary(dict);
+
+        assert_eq!(read(&write(&root)).unwrap(), root);
+    }
+
+    #[test]
+    fn many_objects() {
+        // two byte references
+        let root = Value::Array((0..1000).map(Value::Integer).collect());
+        assert_eq!(read(&write(&root)).unwrap(), root);
+    }
+
+    #[test]
+    fn shared_references() {
+        let value = read(&shared(10)).unwrap();
+
+        let mut expected = Value::Bool(true);
+        for _ in 0..10 {
+            expected = Value::Array(vec![expected.clone(), expected]);
+        }
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn too_many_values() {
+        assert!(read(&shared(100)).is_err());
+    }
+
+    #[test]
+    fn reference_cycle() {
+        // an array holding itself
+        assert!(read(&raw(&[vec![0xA1, 0]], 0)).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let bplist = write(&Value::String("truncated".to_string()));
+        assert!(read(&bplist[..bplist.len() - 1]).is_err());
+        assert!(matches!(read(&bplist[..20]), Err(XMLError::BPList(_))));
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::io::{self, prelude::{Read, Seek, Write}};
+
+use base64::encode;
+use libflate::zlib::Encoder;
+
+use super::blkx::{BlkxChunkEntry, DmgBlxx};
+use super::convert::{build_koly, build_mish, final_blkx};
+use super::encryption::{self, Encryption};
+use super::format::Format;
+use super::resource::ResourceEntry;
+use super::resource_fork;
+use super::sla::{self, License};
+use super::xml::PList;
+
+/// Name hdiutil gives a partition covering the whole disk
+pub const DEFAULT_PARTITION_NAME: &str = "whole disk (unknown partition : 0)";
+
+/// Sectors per chunk, as libdmg-hfsplus uses
+pub const DEFAULT_CHUNK_SIZE: u64 = 512;
+
+/// Most sectors a chunk with data may hold. hdiutil writes at most 2048,
+/// so this only stops a corrupt entry from asking for gigabytes
+pub const MAX_CHUNK_SIZE: u64 = 0x20000;
+
+/// How each chunk of sectors is stored in the data fork
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// zlib compressed, with header and Adler-32 checksum (UDZO)
+    Zlib,
+    /// Stored as-is (UDRO)
+    Raw,
+    /// bzip2 compressed (UDBZ)
+    Bzip2,
+    /// LZFSE compressed, needs macOS 10.11 or later (ULFO)
+    Lzfse,
+}
+
+impl Compression {
+    /// Encode a chunk, returning its type for the mish block
+    pub fn compress(self, chunk: &[u8]) -> Result<(DmgBlxx, Vec<u8>), io::Error> {
+        match self {
+            Compression::Zlib => {
+                let mut encoder = Encoder::new(Vec::new())?;
+                encoder.write_all(chunk)?;
+                Ok((DmgBlxx::ZLibCompression, encoder.finish().into_result()?))
+            }
+            Compression::Raw => Ok((DmgBlxx::RawOrNullCompression, chunk.to_vec())),
+            Compression::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+                encoder.write_all(chunk)?;
+                Ok((DmgBlxx::Bz2Compression, encoder.finish()?))
+            }
+            Compression::Lzfse => {
+                let mut encoded = Vec::new();
+                lzfse_rust::encode_bytes(chunk, &mut encoded)?;
+                Ok((DmgBlxx::LzfseCompression, encoded))
+            }
+        }
+    }
+}
+
+/// What DmgBuilder wrote
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmgSummary {
+    /// Sectors in the partition
+    pub sector_count: u64,
+    /// Chunk entries in the mish block, not counting the last entry
+    pub chunk_count: usize,
+    /// Length of the (compressed) data fork
+    pub data_fork_length: u64,
+    /// Length of the XML plist
+    pub xml_length: u64,
+    /// Length of the classic resource fork, 0 if there isn't one
+    pub resource_fork_length: u64,
+    /// Total bytes written to the sink
+    pub length: u64,
+}
+
+/// The data fork and XML plist of a DMG, without the koly block
+pub struct DmgParts {
+    pub data_fork: Vec<u8>,
+    pub xml: Vec<u8>,
+    /// Empty unless DmgBuilder::resource_fork was set
+    pub resource_fork: Vec<u8>,
+    pub summary: DmgSummary,
+}
+
+/// Creates a DMG containing a single partition read from any Read
+/// source, e.g. an ISO or HFS+ image.
+///
+/// ```ignore
+/// let summary = DmgBuilder::new()
+///     .compression(Compression::Zlib)
+///     .chunk_size(512)
+///     .partition("disk image", File::open("uncompressed.dmg")?)
+///     .write_to(File::create("Bitcoin-Core.dmg")?)?;
+/// ```
+pub struct DmgBuilder<R> {
+    compression: Compression,
+    image_variant: u32,
+    chunk_size: u64,
+    partition: Option<(String, R)>,
+    resources: BTreeMap<String, Vec<ResourceEntry>>,
+    encryption: Option<Encryption>,
+    resource_fork: bool,
+    binary_plist: bool,
+}
+
+impl<R: Read> Default for DmgBuilder<R> {
+    fn default() -> Self {
+        DmgBuilder {
+            compression: Compression::Zlib,
+            image_variant: Format::Udzo.image_variant(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            partition: None,
+            resources: BTreeMap::new(),
+            encryption: None,
+            resource_fork: false,
+            binary_plist: false,
+        }
+    }
+}
+
+impl<R: Read> DmgBuilder<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write an image of this format, e.g. Format::Udrw for an image that
+    /// can be modified in place with Dmg::write_at
+    pub fn format(mut self, format: Format) -> Self {
+        self.compression = format.compression();
+        self.image_variant = format.image_variant();
+        self
+    }
+
+    /// Number of 512 byte sectors compressed together
+    pub fn chunk_size(mut self, sectors: u64) -> Self {
+        self.chunk_size = sectors;
+        self
+    }
+
+    /// The partition contents, and its name in the plist
+    pub fn partition(mut self, name: &str, source: R) -> Self {
+        self.partition = Some((name.to_string(), source));
+        self
+    }
+
+    /// License agreements shown when the DMG is attached, the first is the default
+    pub fn licenses(mut self, licenses: &[License]) -> Self {
+        self.resources = sla::resources(licenses);
+        self
+    }
+
+    /// Wrap the DMG in an encrcdsa container
+    pub fn encryption(mut self, encryption: Encryption) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Also describe the partition with a classic resource fork after the
+    /// plist, for versions of Mac OS X that predate the plist
+    pub fn resource_fork(mut self, resource_fork: bool) -> Self {
+        self.resource_fork = resource_fork;
+        self
+    }
+
+    /// Write the plist as a bplist00 binary plist instead of XML
+    pub fn binary_plist(mut self, binary_plist: bool) -> Self {
+        self.binary_plist = binary_plist;
+        self
+    }
+
+    /// Write the DMG, starting at the current position of sink
+    pub fn write_to<W: Write + Seek>(mut self, mut sink: W) -> Result<DmgSummary, io::Error> {
+        let image_variant = self.image_variant;
+
+        // the whole UDIF image is encrypted, so it has to be built first
+        if let Some(encryption) = self.encryption.take() {
+            let parts = self.build()?;
+            let mut koly = build_koly(parts.summary.xml_length, parts.summary.data_fork_length, parts.summary.sector_count);
+            koly.source_fork_offset = if parts.resource_fork.is_empty() { 0 } else { koly.xml_offset + koly.xml_length };
+            koly.source_fork_length = parts.summary.resource_fork_length;
+            koly.image_variant = image_variant;
+
+            let mut image = parts.data_fork;
+            image.extend_from_slice(&parts.xml);
+            image.extend_from_slice(&parts.resource_fork);
+            image.append(&mut koly.to_be_bytes());
+
+            let encrypted = encryption::encrypt(&image, &encryption)?;
+            sink.write_all(&encrypted)?;
+
+            return Ok(DmgSummary { length: encrypted.len() as u64, ..parts.summary });
+        }
+
+        let start = sink.stream_position()?;
+        let (mut summary, xml, resource_fork) = self.write_data_fork(&mut sink)?;
+        sink.write_all(&xml)?;
+        sink.write_all(&resource_fork)?;
+
+        // offsets in the koly block are from the start of the file
+        let mut koly = build_koly(summary.xml_length, summary.data_fork_length, summary.sector_count);
+        koly.data_fork_offset = start;
+        koly.xml_offset = start + summary.data_fork_length;
+        koly.image_variant = image_variant;
+        if !resource_fork.is_empty() {
+            koly.source_fork_offset = koly.xml_offset + summary.xml_length;
+            koly.source_fork_length = summary.resource_fork_length;
+        }
+        sink.write_all(&koly.to_be_bytes())?;
+
+        summary.length = sink.stream_position()? - start;
+        Ok(summary)
+    }
+
+    /// Build the data fork and XML in memory, e.g. to split into segments
+    pub fn build(self) -> Result<DmgParts, io::Error> {
+        if self.encryption.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Use write_to for encrypted DMGs"));
+        }
+
+        let mut data_fork = Vec::new();
+        let (summary, xml, resource_fork) = self.write_data_fork(&mut data_fork)?;
+        Ok(DmgParts { data_fork, xml, resource_fork, summary })
+    }
+
+    /// Compress the partition into out chunk by chunk, returning the
+    /// summary so far, and the XML plist and resource fork describing the chunks
+    fn write_data_fork<W: Write>(self, out: &mut W) -> Result<(DmgSummary, Vec<u8>, Vec<u8>), io::Error> {
+        let (name, mut source) = self
+            .partition
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No partition to write"))?;
+        if self.chunk_size == 0 || self.chunk_size > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk size must be between 1 and {} sectors", MAX_CHUNK_SIZE),
+            ));
+        }
+
+        let mut entries: Vec<BlkxChunkEntry> = Vec::new();
+        let mut sectors = 0;
+        let mut offset = 0;
+        let mut buffer = vec![0u8; (self.chunk_size * 512) as usize];
+
+        loop {
+            let read = read_full(&mut source, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+
+            // a partial last sector is padded with zeros
+            let sector_count = read.div_ceil(512);
+            let chunk = &mut buffer[..sector_count * 512];
+            chunk[read..].iter_mut().for_each(|b| *b = 0);
+
+            let (entry_type, stored) = self.compression.compress(chunk)?;
+            out.write_all(&stored)?;
+
+            entries.push(BlkxChunkEntry {
+                entry_type,
+                comment: 0,
+                sector_number: sectors,
+                sector_count: sector_count as u64,
+                compressed_offset: offset,
+                compressed_length: stored.len() as u64,
+            });
+
+            sectors += sector_count as u64;
+            offset += stored.len() as u64;
+
+            if read < buffer.len() {
+                break;
+            }
+        }
+
+        let chunk_count = entries.len();
+        entries.push(final_blkx(sectors as usize, offset as usize));
+
+        let mish = build_mish(sectors, entries).to_be_bytes();
+        let xml = PList::build(encode(&mish), &name, &self.resources);
+        // parsed back, so the binary plist holds exactly what the XML does
+        let xml = match self.binary_plist {
+            true => PList::from_bytes(&xml)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+                .to_bplist(),
+            false => xml,
+        };
+
+        let resource_fork = match self.resource_fork {
+            true => {
+                let mut resources = self.resources.clone();
+                resources.insert(String::from("blkx"), vec![ResourceEntry {
+                    attributes: String::from("0x0050"),
+                    data: mish,
+                    id: 0,
+                    name,
+                }]);
+                resource_fork::build(&resources).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?
+            }
+            false => Vec::new(),
+        };
+
+        let summary = DmgSummary {
+            sector_count: sectors,
+            chunk_count,
+            data_fork_length: offset,
+            xml_length: xml.len() as u64,
+            resource_fork_length: resource_fork.len() as u64,
+            length: offset + xml.len() as u64 + resource_fork.len() as u64,
+        };
+
+        Ok((summary, xml, resource_fork))
+    }
+}
+
+/// Read until buffer is full or the source ends
+fn read_full<R: Read>(source: &mut R, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zlib_chunks_have_header_and_checksum() {
+        let chunk = b"libdmg ".repeat(512);
+        let (entry_type, compressed) = Compression::Zlib.compress(&chunk).unwrap();
+        assert!(matches!(entry_type, DmgBlxx::ZLibCompression));
+
+        // CMF says deflate, and CMF/FLG is a multiple of 31
+        assert_eq!(compressed[0] & 0x0F, 8);
+        assert_eq!((u16::from(compressed[0]) << 8 | u16::from(compressed[1])) % 31, 0);
+        let (a, b) = chunk.iter().fold((1u32, 0u32), |(a, b), &byte| {
+            let a = (a + u32::from(byte)) % 65521;
+            (a, (b + a) % 65521)
+        });
+        let adler = b << 16 | a;
+        assert_eq!(compressed[compressed.len() - 4..], adler.to_be_bytes());
+
+        let mut decoded = Vec::new();
+        libflate::zlib::Decoder::new(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, chunk);
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Decompressed chunks kept by default, 64 MiB
+pub const DEFAULT_CACHE_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Decompressed chunks, least recently used first out once they take
+/// more than the budget. The chunk used last is always kept, even if
+/// it alone is over the budget, so reading on through it is cheap.
+pub struct ChunkCache {
+    budget: usize,
+    /// Bytes of chunk data held
+    used: usize,
+    /// Incremented on every use, to order the chunks
+    tick: u64,
+    /// Chunk data and when it was last used, by key
+    chunks: HashMap<u64, (u64, Vec<u8>)>,
+    /// Keys by when they were last used
+    order: BTreeMap<u64, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ChunkCache {
+    pub fn new(budget: usize) -> ChunkCache {
+        ChunkCache {
+            budget,
+            used: 0,
+            tick: 0,
+            chunks: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Change the budget, dropping chunks until they fit
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Lookups that found their chunk, and ones that didn't
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Whether key is cached, counting it as a hit or a miss
+    pub fn lookup(&mut self, key: u64) -> bool {
+        let found = self.chunks.contains_key(&key);
+        if found {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        found
+    }
+
+    /// The chunk cached under key, marking it as the most recently used
+    pub fn get(&mut self, key: u64) -> Option<&[u8]> {
+        self.tick += 1;
+        let (used, data) = self.chunks.get_mut(&key)?;
+        self.order.remove(used);
+        self.order.insert(self.tick, key);
+        *used = self.tick;
+        Some(data)
+    }
+
+    /// Cache a chunk, dropping the least recently used ones if needed
+    pub fn insert(&mut self, key: u64, data: Vec<u8>) {
+        self.remove(key);
+        self.tick += 1;
+        self.used += data.len();
+        self.order.insert(self.tick, key);
+        self.chunks.insert(key, (self.tick, data));
+        self.evict();
+    }
+
+    /// Forget a chunk, e.g. because it was written to
+    pub fn remove(&mut self, key: u64) {
+        if let Some((used, data)) = self.chunks.remove(&key) {
+            self.order.remove(&used);
+            self.used -= data.len();
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.budget && self.chunks.len() > 1 {
+            let (_, key) = self.order.pop_first().expect("Cache order matches its chunks");
+            let (_, data) = self.chunks.remove(&key).expect("Cache order matches its chunks");
+            self.used -= data.len();
+        }
+    }
+}
+
+impl Default for ChunkCache {
+    fn default() -> ChunkCache {
+        ChunkCache::new(DEFAULT_CACHE_BUDGET)
+    }
+}
+
+// the chunk data is far too long to print
+impl fmt::Debug for ChunkCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkCache")
+            .field("budget", &self.budget)
+            .field("used", &self.used)
+            .field("chunks", &self.chunks.len())
+            .field("hits", &self.hits)
+            .field("misses", &self.misses)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(cache: &ChunkCache) -> Vec<u64> {
+        let mut keys: Vec<u64> = cache.order.values().copied().collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ChunkCache::new(300);
+        cache.insert(1, vec![1; 100]);
+        cache.insert(2, vec![2; 100]);
+        cache.insert(3, vec![3; 100]);
+
+        // 1 is now used after 2, so 2 goes first
+        assert_eq!(cache.get(1), Some(&[1u8; 100][..]));
+        cache.insert(4, vec![4; 100]);
+        assert_eq!(cached(&cache), vec![1, 3, 4]);
+        assert_eq!(cache.get(2), None);
+
+        // two chunks' worth
+        cache.insert(5, vec![5; 200]);
+        assert_eq!(cached(&cache), vec![4, 5]);
+        assert_eq!(cache.used, 300);
+    }
+
+    #[test]
+    fn keeps_the_last_chunk() {
+        let mut cache = ChunkCache::new(100);
+        cache.insert(1, vec![1; 50]);
+        cache.insert(2, vec![2; 500]);
+
+        assert_eq!(cached(&cache), vec![2]);
+        assert_eq!(cache.get(2).map(<[u8]>::len), Some(500));
+    }
+
+    #[test]
+    fn set_budget_evicts() {
+        let mut cache = ChunkCache::new(1000);
+        for key in 0..10 {
+            cache.insert(key, vec![0; 100]);
+        }
+        assert_eq!(cache.used, 1000);
+
+        cache.set_budget(250);
+        assert_eq!(cached(&cache), vec![8, 9]);
+        assert_eq!(cache.used, 200);
+
+        cache.set_budget(0);
+        assert_eq!(cached(&cache), vec![9]);
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut cache = ChunkCache::new(1000);
+        cache.insert(1, vec![1; 100]);
+        cache.insert(1, vec![2; 300]);
+        assert_eq!(cache.used, 300);
+        assert_eq!(cache.get(1), Some(&[2u8; 300][..]));
+
+        cache.remove(1);
+        cache.remove(1);
+        assert_eq!(cache.used, 0);
+        assert!(cache.order.is_empty());
+        assert_eq!(cache.get(1), None);
+    }
+
+    #[test]
+    fn counts_lookups() {
+        let mut cache = ChunkCache::default();
+        assert!(!cache.lookup(1));
+        cache.insert(1, vec![1]);
+        assert!(cache.lookup(1));
+        assert!(cache.lookup(1));
+        assert_eq!(cache.stats(), (2, 1));
+    }
+}
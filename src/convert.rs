@@ -2,119 +2,202 @@
 use std::fs::{File};
 use std::io::{self};
 use std::io::{prelude::{Read}};
-use std::io::Write;
 
 use super::blkx::{BlkxChunkEntry, DmgBlxx};
+use super::builder::{DmgBuilder, DEFAULT_PARTITION_NAME};
+use super::dsstore;
+use super::encryption::Encryption;
+use super::format::Format;
+use super::hfs::{self, HfsVolume};
 use super::koly::KolyBlock;
+use super::layout::Layout;
 use super::mish::MishBlock;
-use super::util::UDIFChecksum;
-use super::xml::PList;
-
-use base64::{encode};
-use libflate::deflate::{Encoder};
+use super::segment;
+use super::sla::License;
+use super::sparse::{self, SparseImage};
+use super::sparsebundle::{self, SparseBundle};
+use super::util::{self, UDIFChecksum};
+
+use sha1::{Digest, Sha1};
+
+/// Optional behaviour for conversion
+#[derive(Debug, Default)]
+pub struct ConvertOptions {
+    /// Split the output into segments no larger than this many bytes
+    pub segment_size: Option<u64>,
+    /// Wrap the DMG in an encrcdsa container
+    pub encryption: Option<Encryption>,
+    /// License agreements shown when the DMG is attached, the first is the default
+    pub licenses: Vec<License>,
+    /// Finder layout to rewrite /.DS_Store with, for HFS+ images
+    pub layout: Option<Layout>,
+    /// Contents for /.VolumeIcon.icns, for HFS+ images
+    pub volume_icon: Option<Vec<u8>>,
+    /// Paths on an HFS+ volume to mark invisible
+    pub hidden: Vec<String>,
+    /// Unix timestamp to pin every date to, for reproducible output
+    pub timestamp: Option<u64>,
+    /// Also write a classic resource fork, for very old versions of Mac OS X
+    pub resource_fork: bool,
+    /// Image format, UDZO if not set
+    pub format: Option<Format>,
+    /// Write the plist as a binary plist instead of XML
+    pub binary_plist: bool,
+}
 
 /// Mimics the behaviour of libdmg-hfsplus compress function
 /// Takes the ISO generated by genisoimage (containing Bitcoin-Core.app) and
-/// converts it into a "proper" DMG image.
-pub fn conversion(iso: std::path::PathBuf, dmg: std::path::PathBuf) -> Result<(), io::Error> {
+/// converts it into a "proper" DMG image. The image itself is written by
+/// DmgBuilder, this handles the command line options around it.
+pub fn conversion(iso: std::path::PathBuf, dmg: std::path::PathBuf, options: ConvertOptions) -> Result<(), io::Error> {
     println!("converting: {:#?}, to dmg: {:#?}", iso, dmg);
 
-    let mut f = File::open(iso)?;
-    //println!("metadata: {:#?}", f.metadata()?);
+    if options.segment_size.is_some() && options.encryption.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encrypted DMGs can not be segmented"));
+    }
+    if options.segment_size.is_some() && options.format == Some(Format::Udrw) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "UDRW DMGs can not be segmented"));
+    }
+    if options.timestamp.is_some() && options.encryption.as_ref().is_some_and(|e| !e.deterministic) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Reproducible encrypted DMGs need --deterministic-encryption",
+        ));
+    }
 
     // read the entire incoming ISO image into a buffer.
+    // A .sparseimage or .sparsebundle is expanded into the disk it holds.
     let mut incoming = Vec::new();
-    f.read_to_end(&mut incoming)?;
-    let incoming_size = incoming.len();
-    println!("Incoming ISO size: {:#?}", incoming_size);
-
-    // build the block entries
-    // zlib deflate in 512 byte chunks
-
-    // number of sectors required is incoming_size / 512
-    let mut sectors_required = incoming_size / 512;
-    println!("sectors required: {:#?}", sectors_required);
-
-    let mut entries: Vec<BlkxChunkEntry> = Vec::new();
+    if sparsebundle::is_sparse_bundle(&iso) {
+        SparseBundle::open(&iso)?.read_to_end(&mut incoming)?;
+    } else {
+        let mut f = File::open(iso)?;
+        //println!("metadata: {:#?}", f.metadata()?);
+        if sparse::is_sparse(&mut f)? {
+            SparseImage::new(f)?.read_to_end(&mut incoming)?;
+        } else {
+            f.read_to_end(&mut incoming)?;
+        }
+    }
+    println!("Incoming ISO size: {:#?}", incoming.len());
 
-    let mut current_run = 0; // TODO - can be removed
-    let mut sectors_processed = 0;
+    // pin the dates first, as the background alias records them
+    if let Some(timestamp) = options.timestamp {
+        pin_volume(&mut incoming, timestamp)?;
+    }
+    if let Some(layout) = &options.layout {
+        apply_layout(&mut incoming, layout)?;
+    }
+    if let Some(icon) = &options.volume_icon {
+        apply_volume_icon(&mut incoming, icon)?;
+    }
+    if !options.hidden.is_empty() {
+        hide_paths(&mut incoming, &options.hidden)?;
+    }
 
-    // buffer that zlib compressed data is being written into
-    let mut processed_buffer: Vec<u8> = Vec::new();
+    let builder = DmgBuilder::new()
+        .partition(DEFAULT_PARTITION_NAME, &incoming[..])
+        .licenses(&options.licenses)
+        .resource_fork(options.resource_fork)
+        .binary_plist(options.binary_plist);
+    let builder = match options.format {
+        Some(format) => builder.format(format),
+        None => builder,
+    };
 
+    if let Some(segment_size) = options.segment_size {
+        let parts = builder.build()?;
 
-    while sectors_required > 0 {
-        let sector_count = if sectors_required > 512 { 512 } else { sectors_required };
-        println!("start of run {}: sectors={}, left={}, processed: {}", current_run, sector_count, sectors_required, sectors_processed);
+        // a reproducible segment id comes from the contents instead
+        let segment_id = match options.timestamp {
+            Some(_) => {
+                let digest = Sha1::new().chain_update(&parts.data_fork).chain_update(&parts.xml).finalize();
+                util::read_be_u128(&mut &digest[..16])
+            }
+            None => util::random_u128(),
+        };
 
-        let start = sectors_processed * 512;
-        let end = (sectors_processed + sector_count) * 512;
+        let paths = segment::write_segments(&dmg, &parts.data_fork, parts.xml, &parts.resource_fork, parts.summary.sector_count, segment_size, segment_id)?;
+        println!("{:#?}", parts.summary);
+        println!("segments: {:#?}", paths);
+        return Ok(());
+    }
 
-        // zlib compress bytes, and put them into processed buffer
-        let mut encoder = Encoder::new(Vec::new());
-        io::copy(&mut &incoming[start..end], &mut encoder).unwrap();
-        let encoded = encoder.finish().into_result().unwrap();
-        processed_buffer.append(&mut encoded.clone());
+    let builder = match options.encryption {
+        Some(encryption) => builder.encryption(encryption),
+        None => builder,
+    };
 
-        // add the number of sectors just processed
-        sectors_processed += sector_count;
+    let summary = builder.write_to(File::create(dmg)?)?;
+    println!("{:#?}", summary);
 
-        let compressed_length = encoded.len() as u64;
+    Ok(())
+}
 
-        // build a BlkxChunkEntry
-        let entry = BlkxChunkEntry {
-            entry_type: DmgBlxx::ZLibCompression,
-            comment: 0,
-            sector_number: (sectors_processed - sector_count) as u64,
-            sector_count: sector_count as u64,
-            compressed_offset: (processed_buffer.len() as u64) - compressed_length,
-            compressed_length,
-        };
+/// Set every date on an HFS+ image to timestamp (Unix seconds), list
+/// directory entries in canonical order and zero unused blocks, so the
+/// same files always give the same image. Other file systems (e.g.
+/// ISO 9660) can't be pinned, and are an error.
+pub fn pin_volume(image: &mut Vec<u8>, timestamp: u64) -> Result<(), io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
 
-        entries.push(entry);
+    let volume = HfsVolume::read(&mut io::Cursor::new(&image[..]))
+        .map_err(|e| invalid(format!("Only HFS+ volumes can be made reproducible: {}", e)))?;
 
-        sectors_required -= sector_count;
-        current_run += 1;
+    let mut writer = io::Cursor::new(image);
+    let volume = match volume.order_catalog(&mut writer).map_err(invalid)? {
+        true => HfsVolume::read(&mut writer).map_err(invalid)?,
+        false => volume,
     };
+    volume.set_dates(&mut writer, hfs::hfs_date(timestamp).map_err(invalid)?).map_err(invalid)?;
+    volume.zero_unused(&mut writer).map_err(invalid)
+}
 
-    // build the final blkx chunk entry
-    let final_entry = final_blkx(incoming_size / 512, processed_buffer.len());
-    entries.push(final_entry);
-
-    println!("entries: {:#?}", entries.len());
-    //println!("built entries: {:#?}", entries);
-    println!("Original buffer was: {:#?}", incoming_size);
-    // 15148687 default usage
-    // TODO: Need to investigate using Best compression level
-    println!("zlib buffer size: {:#?}", processed_buffer.len());
-
-    // Now we've got blkx chunk entries, build a mish block
-    let mish_block = build_mish((incoming_size / 512) as u64, entries);
-    println!("{:#?}", mish_block);
-
-    let be_mish_bytes = mish_block.to_be_bytes();
+/// Rewrite the /.DS_Store in an HFS+ image for a layout, so the background
+/// alias has the CNIDs and dates of this volume. The .DS_Store has to exist
+/// already (e.g. from write-ds-store) as it is updated in place.
+pub fn apply_layout(image: &mut Vec<u8>, layout: &Layout) -> Result<(), io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
 
-    let encoded = encode(&be_mish_bytes);
-    //println!("base64: {:#?}", encoded);
+    let volume = HfsVolume::read(&mut io::Cursor::new(&image[..])).map_err(invalid)?;
+    let record = volume
+        .lookup(".DS_Store")
+        .ok_or_else(|| invalid("The volume has no /.DS_Store, create one with write-ds-store".to_string()))?;
 
-    // construct the XML plist data, including the mish block
-    let mut xml = PList::build(encoded);
-    //println!("xml: {:#?}", String::from_utf8(xml.clone()));
+    let ds_store = dsstore::write(layout.records(Some(&volume)).map_err(invalid)?).map_err(invalid)?;
+    volume.overwrite_file(&mut io::Cursor::new(image), record, &ds_store).map_err(invalid)
+}
 
-    // generate the koly block
-    let koly = build_koly(xml.len() as u64, processed_buffer.len() as u64, (incoming_size / 512) as u64);
+/// Write the volume icon into /.VolumeIcon.icns of an HFS+ image, hide it
+/// and set kHasCustomIcon on the root folder so Finder shows it.
+/// There is no HFS+ writer here, so the file has to exist already with
+/// enough space, e.g. a copy of the icon in the source folder.
+pub fn apply_volume_icon(image: &mut Vec<u8>, icon: &[u8]) -> Result<(), io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+    let volume = HfsVolume::read(&mut io::Cursor::new(&image[..])).map_err(invalid)?;
+    let file = volume.lookup(hfs::VOLUME_ICON_NAME).ok_or_else(|| {
+        invalid(format!("The volume has no /{}, copy the icon into the source folder first", hfs::VOLUME_ICON_NAME))
+    })?;
+    let root = volume.lookup("/").ok_or_else(|| invalid("The volume has no root folder".to_string()))?;
+
+    let mut writer = io::Cursor::new(image);
+    volume.overwrite_file(&mut writer, file, icon).map_err(invalid)?;
+    volume.set_finder_flags(&mut writer, file, hfs::K_IS_INVISIBLE).map_err(invalid)?;
+    volume.set_finder_flags(&mut writer, root, hfs::K_HAS_CUSTOM_ICON).map_err(invalid)
+}
 
-    // append the XML to the processed buffer
-    processed_buffer.append(&mut xml);
+/// Set kIsInvisible on files and folders of an HFS+ image
+pub fn hide_paths(image: &mut Vec<u8>, paths: &[String]) -> Result<(), io::Error> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
 
-    // serialize and append the koly block to the end of the buffer
-    let mut koly_be = koly.to_be_bytes();
-    processed_buffer.append(&mut koly_be);
+    let volume = HfsVolume::read(&mut io::Cursor::new(&image[..])).map_err(invalid)?;
+    let mut writer = io::Cursor::new(image);
 
-    // write out the progressed buffer to disk
-    let mut result = std::fs::File::create(dmg).unwrap();
-    result.write_all(&processed_buffer).unwrap();
+    for path in paths {
+        let record = volume.lookup(path).ok_or_else(|| invalid(format!("{} does not exist on the volume", path)))?;
+        volume.set_finder_flags(&mut writer, record, hfs::K_IS_INVISIBLE).map_err(invalid)?;
+    }
 
     Ok(())
 }
@@ -175,7 +258,8 @@ pub fn build_mish(sectors: u64, entries: Vec<BlkxChunkEntry>) -> MishBlock {
             data: vec![0u8; 128], // TODO - actually calculate and set
         },
         number_block_chunks: (entries.len() as u32),
-        block_entries: entries
+        block_entries: entries,
+        trailing: Vec::new(),
     }
 }
 
@@ -0,0 +1,91 @@
+use std::io::{self, prelude::Read};
+
+use super::blkx::DmgBlxx;
+
+/// Decode a single chunk of a data fork into expected_len bytes
+pub fn decompress_chunk(entry_type: &DmgBlxx, data: &[u8], expected_len: usize) -> Result<Vec<u8>, io::Error> {
+    let mut decoded = match entry_type {
+        DmgBlxx::ZeroFill | DmgBlxx::IgnoredOrUnknown => vec![0u8; expected_len],
+        DmgBlxx::RawOrNullCompression => data.to_vec(),
+        DmgBlxx::ZLibCompression => inflate(data)?,
+        DmgBlxx::AppleCompression => adc(data)?,
+        DmgBlxx::Bz2Compression => {
+            let mut decoded = Vec::new();
+            bzip2::read::BzDecoder::new(data).read_to_end(&mut decoded)?;
+            decoded
+        }
+        DmgBlxx::LzfseCompression => {
+            let mut decoded = Vec::new();
+            lzfse_rust::decode_bytes(data, &mut decoded)?;
+            decoded
+        }
+        DmgBlxx::Comment | DmgBlxx::LastEntry => Vec::new(),
+    };
+
+    if decoded.len() < expected_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Chunk decompressed to {} bytes, expected {}", decoded.len(), expected_len),
+        ));
+    }
+    decoded.truncate(expected_len);
+
+    Ok(decoded)
+}
+
+/// Inflate a zlib stream, as hdiutil and libdmg-hfsplus write. Older versions
+/// of this crate wrote raw deflate data instead, so fall back to that.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let zlib_header = data.len() >= 2 && data[0] & 0x0F == 8 && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+
+    if zlib_header {
+        let mut decoded = Vec::new();
+        if let Ok(mut decoder) = libflate::zlib::Decoder::new(data) {
+            if decoder.read_to_end(&mut decoded).is_ok() {
+                return Ok(decoded);
+            }
+        }
+    }
+
+    let mut decoded = Vec::new();
+    libflate::deflate::Decoder::new(data).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Apple Data Compression, a simple LZ77 variant
+fn adc(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid ADC data");
+    let mut out: Vec<u8> = Vec::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let byte = data[position];
+
+        let (length, offset) = if byte & 0x80 != 0 {
+            // literal run
+            let length = (byte & 0x7F) as usize + 1;
+            let literal = data.get(position + 1..position + 1 + length).ok_or_else(invalid)?;
+            out.extend_from_slice(literal);
+            position += 1 + length;
+            continue;
+        } else if byte & 0x40 != 0 {
+            // three byte code, 16 bit offset
+            let bytes = data.get(position + 1..position + 3).ok_or_else(invalid)?;
+            position += 3;
+            ((byte & 0x3F) as usize + 4, (usize::from(bytes[0]) << 8) | usize::from(bytes[1]))
+        } else {
+            // two byte code, 10 bit offset
+            let low = *data.get(position + 1).ok_or_else(invalid)?;
+            position += 2;
+            (((byte & 0x3C) >> 2) as usize + 3, (usize::from(byte & 0x03) << 8) | usize::from(low))
+        };
+
+        // copies may overlap what they produce, so go a byte at a time
+        let start = out.len().checked_sub(offset + 1).ok_or_else(invalid)?;
+        for index in 0..length {
+            out.push(out[start + index]);
+        }
+    }
+
+    Ok(out)
+}
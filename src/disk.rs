@@ -0,0 +1,18 @@
+use std::io::prelude::{Read, Seek};
+
+use super::dmg::PartitionReader;
+
+/// A disk image read as a flat run of bytes, whatever format it is
+/// stored in: a UDIF partition or disk (Dmg::open_partition and
+/// Dmg::open_disk), a .sparseimage (sparse::SparseImage) or a
+/// .sparsebundle (sparsebundle::SparseBundle)
+pub trait VirtualDisk: Read + Seek {
+    /// Length of the disk in bytes
+    fn size(&self) -> u64;
+}
+
+impl<'a, R: Read + Seek> VirtualDisk for PartitionReader<'a, R> {
+    fn size(&self) -> u64 {
+        self.len()
+    }
+}
@@ -0,0 +1,1018 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::{Read, Seek, Write}, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use super::blkx::{BlkxChunkEntry, DmgBlxx};
+use super::builder::{DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE};
+use super::cache::ChunkCache;
+use super::decompress;
+use super::encryption::{self, EncryptedReader, Unlock};
+use super::index::SectorIndex;
+use super::koly::KolyBlock;
+use super::mish::MishBlock;
+use super::partition::PartitionEntry;
+use super::segment::SegmentedImage;
+use super::util::{self, UDIFChecksum};
+use super::xml::PList;
+
+/// An opened DMG: its koly block, its parsed plist, and the data
+/// needed to read the partitions in it
+#[derive(Debug)]
+pub struct Dmg<R> {
+    inner: R,
+    koly: KolyBlock,
+    plist: PList,
+    /// Where the data fork starts in inner
+    data_fork_offset: u64,
+    /// The plist was a bplist00 one, so it is rewritten as one
+    binary_plist: bool,
+    /// Every chunk, sorted by where it is on the disk
+    index: SectorIndex,
+    /// Decompressed chunks, shared by every PartitionReader
+    cache: ChunkCache,
+}
+
+impl Dmg<SegmentedImage> {
+    /// Open a DMG file, and any .dmgpart segments that belong to it
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        if encryption::is_encrypted(&mut File::open(path)?)? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "DMG is encrypted, use Dmg::open_encrypted"));
+        }
+
+        let mut image = SegmentedImage::open(path)?;
+        let koly = image.koly().clone();
+        let xml = image.xml()?;
+        let resource_fork = image.resource_fork()?;
+
+        // SegmentedImage reads the logical data fork, not the file
+        Dmg::from_parts(image, koly, &xml, &resource_fork, 0)
+    }
+}
+
+impl Dmg<EncryptedReader<File>> {
+    /// Open an encrypted DMG with a passphrase or key
+    pub fn open_encrypted(path: &Path, unlock: &Unlock) -> Result<Self, io::Error> {
+        Dmg::from_reader(EncryptedReader::new(File::open(path)?, unlock)?)
+    }
+}
+
+impl Dmg<File> {
+    /// Open a single file DMG for reading and writing, to modify a
+    /// UDRW image in place with write_at
+    pub fn open_writable(path: &Path) -> Result<Self, io::Error> {
+        Dmg::from_reader(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Dmg<io::Cursor<memmap2::Mmap>> {
+    /// Open a single file DMG by mapping it into memory, so chunks are
+    /// read without a system call each. The file must not be changed
+    /// while it is open.
+    pub fn open_mmap(path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        if encryption::is_encrypted(&mut &file)? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "DMG is encrypted, use Dmg::open_encrypted"));
+        }
+
+        // SAFETY: the mapping is read-only, and like any reader we rely
+        // on nobody truncating or rewriting the file underneath us
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let dmg = Dmg::from_reader(io::Cursor::new(map))?;
+        if dmg.koly.segment_count > 1 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Segmented DMGs can't be memory mapped, use Dmg::open"));
+        }
+        Ok(dmg)
+    }
+}
+
+impl<'a> Dmg<io::Cursor<&'a [u8]>> {
+    /// Open a DMG that is already in memory
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, io::Error> {
+        Dmg::from_reader(io::Cursor::new(bytes))
+    }
+}
+
+impl<R: Read + Seek> Dmg<R> {
+    /// Open a single file DMG from any seekable source, e.g. an
+    /// archive member or a reader doing HTTP range requests
+    pub fn from_reader(mut reader: R) -> Result<Self, io::Error> {
+        let koly = KolyBlock::from_reader(&mut reader)?;
+        let length = reader.seek(SeekFrom::End(0))?;
+
+        let xml = util::read_fork(&mut reader, "XML plist", koly.xml_offset, koly.xml_length, length)?;
+        let resource_fork = util::read_fork(&mut reader, "resource fork", koly.source_fork_offset, koly.source_fork_length, length)?;
+
+        let data_fork_offset = koly.data_fork_offset;
+        Dmg::from_parts(reader, koly, &xml, &resource_fork, data_fork_offset)
+    }
+
+    /// The partitions are described by the XML plist, or by a classic
+    /// resource fork in images made before the plist was introduced
+    fn from_parts(inner: R, koly: KolyBlock, xml: &[u8], resource_fork: &[u8], data_fork_offset: u64) -> Result<Self, io::Error> {
+        let plist = match (xml.is_empty(), resource_fork.is_empty()) {
+            (false, _) => PList::from_bytes(xml),
+            (true, false) => PList::from_resource_fork(resource_fork),
+            (true, true) => return Err(io::Error::new(io::ErrorKind::InvalidData, "DMG has no plist or resource fork")),
+        };
+        let plist = plist.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        // so the disk's length in bytes can't overflow later
+        sector_bytes(koly.sector_count)?;
+        let index = SectorIndex::build(&plist.partitions, koly.data_fork_length).map_err(|problems| {
+            let more = match problems.len() {
+                1 => String::new(),
+                n => format!(" (and {} more)", n - 1),
+            };
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid chunk table: {}{}", problems[0], more))
+        })?;
+
+        let binary_plist = xml.starts_with(b"bplist00");
+        Ok(Dmg { inner, koly, plist, data_fork_offset, binary_plist, index, cache: ChunkCache::default() })
+    }
+
+    pub fn koly(&self) -> &KolyBlock {
+        &self.koly
+    }
+
+    pub fn plist(&self) -> &PList {
+        &self.plist
+    }
+
+    /// The blkx partitions, in plist order
+    pub fn partitions(&self) -> impl Iterator<Item = &PartitionEntry> {
+        self.plist.partitions.iter()
+    }
+
+    /// Sectors in the whole image
+    pub fn sector_count(&self) -> u64 {
+        self.koly.sector_count
+    }
+
+    /// Every chunk of every partition, in disk order
+    pub fn sector_index(&self) -> &SectorIndex {
+        &self.index
+    }
+
+    /// The cache of decompressed chunks, e.g. to change its budget
+    pub fn cache(&mut self) -> &mut ChunkCache {
+        &mut self.cache
+    }
+
+    /// The underlying reader, e.g. to look at the segments
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Write the whole disk as a flat, uncompressed image, like a UDTO
+    /// (.cdr) or raw .img. Each partition is written at its starting
+    /// sector, and anything between them as zeros.
+    pub fn write_raw<W: Write>(&mut self, out: &mut W) -> Result<u64, io::Error> {
+        io::copy(&mut self.open_disk(), out)
+    }
+
+    /// Decompress a single chunk of a partition, e.g. to re-encode it
+    pub fn read_chunk(&mut self, mish: &MishBlock, entry: &BlkxChunkEntry) -> Result<Vec<u8>, io::Error> {
+        read_chunk(&mut self.inner, self.data_fork_offset + mish.data_offset, entry)
+    }
+
+    /// Read the decompressed contents of the partition with this ID
+    pub fn open_partition(&mut self, id: i32) -> Result<PartitionReader<'_, R>, io::Error> {
+        let (index, partition) = self
+            .plist
+            .partitions
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No partition with ID {}", id)))?;
+
+        let mish = &partition.data;
+        let base = self.data_fork_offset + mish.data_offset;
+        let chunks = self
+            .index
+            .chunks()
+            .iter()
+            .filter(|c| c.partition == index)
+            .map(|c| Chunk {
+                sector: c.sector - mish.sector_number,
+                entry: &mish.block_entries[c.entry],
+                base,
+                key: cache_key(index, c.entry),
+            })
+            .collect();
+
+        Ok(PartitionReader {
+            inner: &mut self.inner,
+            chunks,
+            length: sector_bytes(mish.sector_count)?,
+            position: 0,
+            cache: &mut self.cache,
+        })
+    }
+
+    /// Read the decompressed contents of the whole disk, with each
+    /// partition at its starting sector. Sectors outside of every
+    /// partition read as zeros.
+    pub fn open_disk(&mut self) -> PartitionReader<'_, R> {
+        let (partitions, data_fork_offset) = (&self.plist.partitions, self.data_fork_offset);
+        let chunks = self
+            .index
+            .chunks()
+            .iter()
+            .map(|c| {
+                let mish = &partitions[c.partition].data;
+                Chunk {
+                    sector: c.sector,
+                    entry: &mish.block_entries[c.entry],
+                    base: data_fork_offset + mish.data_offset,
+                    key: cache_key(c.partition, c.entry),
+                }
+            })
+            .collect();
+
+        PartitionReader {
+            inner: &mut self.inner,
+            chunks,
+            // checked when the image was opened
+            length: self.koly.sector_count * 512,
+            position: 0,
+            cache: &mut self.cache,
+        }
+    }
+}
+
+impl<R: Read + Write + Seek> Dmg<R> {
+    /// Overwrite part of a partition, starting offset bytes into it.
+    /// Every chunk written to has to be uncompressed, as in a UDRW image.
+    /// Sectors of ZeroFill chunks are given space at the end of the data
+    /// fork the first time they are written. The checksums of the data
+    /// fork and the partition would no longer match, so they are cleared,
+    /// rewriting the plist and koly block after the data fork.
+    pub fn write_at(&mut self, id: i32, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let index = self
+            .plist
+            .partitions
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No partition with ID {}", id)))?;
+        let mish = &self.plist.partitions[index].data;
+
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= mish.sector_count * 512)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Write goes past the end of the partition"))?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // check every chunk before writing anything
+        let sectors = offset / 512..end.div_ceil(512);
+        let mut allocate = false;
+        let mut sector = sectors.start;
+        while sector < sectors.end {
+            let chunk = self
+                .index
+                .find(mish.sector_number + sector)
+                .filter(|c| c.partition == index)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Sector {} is not in any chunk", sector)))?;
+            match mish.block_entries[chunk.entry].entry_type {
+                DmgBlxx::RawOrNullCompression => (),
+                DmgBlxx::ZeroFill | DmgBlxx::IgnoredOrUnknown => allocate = true,
+                _ => return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Sector {} is compressed, convert the DMG to UDRW first", sector),
+                )),
+            }
+            sector = chunk.end() - mish.sector_number;
+        }
+
+        let checksummed = [&self.koly.data_fork_checksum, &self.koly.master_checksum, &mish.checksum]
+            .iter()
+            .any(|c| c.fork_type != 0);
+        if allocate || checksummed {
+            // fail before the data fork is changed
+            self.check_trailer()?;
+            self.koly.data_fork_checksum = UDIFChecksum::none();
+            self.koly.master_checksum = UDIFChecksum::none();
+            self.plist.partitions[index].data.checksum = UDIFChecksum::none();
+
+            // the image as it is, to fall back on while the data fork grows
+            let current = (self.koly.clone(), self.encode_trailer()?);
+            let zeros = match allocate {
+                true => self.allocate(index, sectors)?,
+                false => 0..0,
+            };
+            self.write_trailer(current, zeros)?;
+        }
+
+        let mish = &self.plist.partitions[index].data;
+        let base = self.data_fork_offset + mish.data_offset;
+        let mut position = offset;
+        let mut remaining = data;
+
+        while !remaining.is_empty() {
+            let i = self
+                .index
+                .find(mish.sector_number + position / 512)
+                .filter(|c| c.partition == index)
+                .map(|c| c.entry)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Sector {} is not in any chunk", position / 512)))?;
+            let entry = &mish.block_entries[i];
+
+            let start = position - entry.sector_number * 512;
+            let count = std::cmp::min(remaining.len() as u64, entry.sector_count * 512 - start) as usize;
+            self.inner.seek(SeekFrom::Start(base + entry.compressed_offset + start))?;
+            self.inner.write_all(&remaining[..count])?;
+            self.cache.remove(cache_key(index, i));
+
+            position += count as u64;
+            remaining = &remaining[count..];
+        }
+
+        self.inner.flush()
+    }
+
+    /// Store the sectors of the partition at index that are in ZeroFill
+    /// chunks as raw chunks appended to the data fork. Only the plist
+    /// and koly block in memory change, the returned range of inner is
+    /// left for write_trailer to fill with zeros.
+    fn allocate(&mut self, index: usize, sectors: Range<u64>) -> Result<Range<u64>, io::Error> {
+        let data_fork_end = self.data_fork_offset + self.koly.data_fork_length;
+        let mish = &mut self.plist.partitions[index].data;
+        // chunk offsets are from the start of the partition's data
+        let stored_at = self.koly.data_fork_length.checked_sub(mish.data_offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Partition data starts past the end of the data fork")
+        })?;
+
+        let mut entries = Vec::new();
+        let mut allocated = 0;
+        for entry in std::mem::take(&mut mish.block_entries) {
+            let entry_end = entry.sector_number + entry.sector_count;
+            let start = std::cmp::max(entry.sector_number, sectors.start);
+            let end = std::cmp::min(entry_end, sectors.end);
+            if !matches!(entry.entry_type, DmgBlxx::ZeroFill | DmgBlxx::IgnoredOrUnknown) || start >= end {
+                entries.push(entry);
+                continue;
+            }
+
+            if start > entry.sector_number {
+                entries.push(BlkxChunkEntry { sector_count: start - entry.sector_number, ..entry.clone() });
+            }
+            // in chunks of the usual size, so no chunk is too big to cache
+            let mut sector = start;
+            while sector < end {
+                let sector_count = std::cmp::min(DEFAULT_CHUNK_SIZE, end - sector);
+                entries.push(BlkxChunkEntry {
+                    entry_type: DmgBlxx::RawOrNullCompression,
+                    comment: 0,
+                    sector_number: sector,
+                    sector_count,
+                    compressed_offset: stored_at + allocated,
+                    compressed_length: sector_count * 512,
+                });
+                sector += sector_count;
+                allocated += sector_count * 512;
+            }
+            if end < entry_end {
+                entries.push(BlkxChunkEntry { sector_number: end, sector_count: entry_end - end, ..entry });
+            }
+        }
+
+        mish.number_block_chunks = entries.len() as u32;
+        mish.block_entries = entries;
+        self.koly.data_fork_length += allocated;
+        self.index = SectorIndex::build(&self.plist.partitions, self.koly.data_fork_length)
+            .map_err(|problems| io::Error::new(io::ErrorKind::InvalidData, problems.join(", ")))?;
+        // entries moved, so the cache keys of this partition did too
+        self.cache = ChunkCache::new(self.cache.budget());
+        Ok(data_fork_end..data_fork_end + allocated)
+    }
+
+    /// Check that the plist is after the data fork, so it can be
+    /// rewritten, and the data fork extended, without moving anything else
+    fn check_trailer(&self) -> Result<(), io::Error> {
+        if self.koly.segment_count > 1 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Segmented DMGs can't be written to"));
+        }
+
+        let offset = self.data_fork_offset + self.koly.data_fork_length;
+        let forks = [
+            (self.koly.xml_offset, self.koly.xml_length),
+            (self.koly.source_fork_offset, self.koly.source_fork_length),
+        ];
+        if forks.iter().any(|(fork_offset, length)| *length > 0 && *fork_offset < offset) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "The plist is not after the data fork, so it can't be rewritten"));
+        }
+        Ok(())
+    }
+
+    /// The plist, in the encoding the image had, and the resource fork
+    /// if the image had one
+    fn encode_trailer(&self) -> Result<(Vec<u8>, Vec<u8>), io::Error> {
+        let xml = match self.binary_plist {
+            true => self.plist.to_bplist(),
+            false => self.plist.to_xml(),
+        };
+        let resource_fork = match self.koly.source_fork_length {
+            0 => Vec::new(),
+            _ => self.plist.to_resource_fork().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+        };
+        Ok((xml, resource_fork))
+    }
+
+    /// Write the plist and resource fork after the data fork, zeroing
+    /// the zeros range of it first. current is the koly block and trailer
+    /// of the image before the data fork grew, so that however far this
+    /// gets, the koly block at the end of the file describes a whole
+    /// image: current is written somewhere nothing else is about to be
+    /// written, then the data fork grows, then the final plist is written
+    /// after it. The koly block has to stay at the end of the file, so
+    /// the space current took is left as zeros before it.
+    fn write_trailer(&mut self, current: (KolyBlock, (Vec<u8>, Vec<u8>)), zeros: Range<u64>) -> Result<(), io::Error> {
+        let (mut current_koly, (current_xml, current_fork)) = current;
+        let length = self.inner.seek(SeekFrom::End(0))?;
+        let koly_at = length - 512;
+        // read_fork checked these against the length when the image was opened
+        let old_end = std::cmp::max(
+            current_koly.xml_offset + current_koly.xml_length,
+            current_koly.source_fork_offset + current_koly.source_fork_length,
+        );
+
+        let (xml, resource_fork) = self.encode_trailer()?;
+        let offset = self.data_fork_offset + self.koly.data_fork_length;
+        let end = offset + xml.len() as u64 + resource_fork.len() as u64;
+
+        // current goes between the final plist and the koly block if it
+        // fits, otherwise after the koly block, which is first copied to
+        // the new end of the file
+        let current_length = current_xml.len() as u64 + current_fork.len() as u64;
+        let at = std::cmp::max(end, old_end);
+        let (at, new_koly_at) = match at + current_length <= koly_at {
+            true => (at, koly_at),
+            false => (std::cmp::max(at, length), std::cmp::max(at, length) + current_length),
+        };
+        if new_koly_at != koly_at {
+            let mut koly = [0u8; 512];
+            self.inner.seek(SeekFrom::Start(koly_at))?;
+            self.inner.read_exact(&mut koly)?;
+            self.inner.seek(SeekFrom::Start(new_koly_at))?;
+            self.inner.write_all(&koly)?;
+            self.inner.flush()?;
+        }
+        place_trailer(&mut current_koly, at, current_xml.len() as u64, current_fork.len() as u64);
+        self.inner.seek(SeekFrom::Start(at))?;
+        self.inner.write_all(&current_xml)?;
+        self.inner.write_all(&current_fork)?;
+        self.inner.flush()?;
+        self.inner.seek(SeekFrom::Start(new_koly_at))?;
+        self.inner.write_all(&current_koly.to_be_bytes())?;
+        self.inner.flush()?;
+
+        self.inner.seek(SeekFrom::Start(zeros.start))?;
+        io::copy(&mut io::repeat(0).take(zeros.end - zeros.start), &mut self.inner)?;
+        self.inner.flush()?;
+
+        place_trailer(&mut self.koly, offset, xml.len() as u64, resource_fork.len() as u64);
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.write_all(&xml)?;
+        self.inner.write_all(&resource_fork)?;
+        self.inner.flush()?;
+        self.inner.seek(SeekFrom::Start(new_koly_at))?;
+        self.inner.write_all(&self.koly.clone().to_be_bytes())?;
+        self.inner.flush()?;
+
+        // and the rest, including current, is no longer needed
+        self.inner.seek(SeekFrom::Start(end))?;
+        io::copy(&mut io::repeat(0).take(new_koly_at - end), &mut self.inner)?;
+        self.inner.flush()
+    }
+}
+
+/// Point a koly block at a plist of xml_length bytes at offset,
+/// followed by a resource fork of fork_length bytes if there is one
+fn place_trailer(koly: &mut KolyBlock, offset: u64, xml_length: u64, fork_length: u64) {
+    koly.xml_offset = offset;
+    koly.xml_length = xml_length;
+    koly.source_fork_offset = if fork_length == 0 { 0 } else { offset + xml_length };
+    koly.source_fork_length = fork_length;
+}
+
+/// A chunk entry, placed relative to the start of a PartitionReader.
+/// PartitionReader keeps them sorted by sector.
+struct Chunk<'a> {
+    /// First sector of the chunk
+    sector: u64,
+    entry: &'a BlkxChunkEntry,
+    /// Where the chunk offset is measured from in inner
+    base: u64,
+    /// Identifies the chunk in the cache
+    key: u64,
+}
+
+/// Cache key of entry i of the partition at index in the plist
+fn cache_key(index: usize, i: usize) -> u64 {
+    (index as u64) << 32 | i as u64
+}
+
+/// Decompressing reader over a single partition, or the whole disk
+pub struct PartitionReader<'a, R> {
+    inner: &'a mut R,
+    chunks: Vec<Chunk<'a>>,
+    length: u64,
+    position: u64,
+    cache: &'a mut ChunkCache,
+}
+
+impl<'a, R: Read + Seek> PartitionReader<'a, R> {
+    /// Length of the partition in bytes
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Decompress the chunk at index, unless it is cached already
+    fn load(&mut self, index: usize) -> Result<&[u8], io::Error> {
+        let chunk = &self.chunks[index];
+        if !self.cache.lookup(chunk.key) {
+            let decoded = read_chunk(self.inner, chunk.base, chunk.entry)?;
+            self.cache.insert(chunk.key, decoded);
+        }
+
+        Ok(self.cache.get(chunk.key).expect("Chunk was just cached"))
+    }
+}
+
+/// Read and decompress a chunk, with offsets measured from base
+fn read_chunk<R: Read + Seek>(inner: &mut R, base: u64, entry: &BlkxChunkEntry) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::new();
+    if has_data(&entry.entry_type) {
+        if entry.sector_count > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk of {} sectors is more than {}", entry.sector_count, MAX_CHUNK_SIZE),
+            ));
+        }
+        let offset = base
+            .checked_add(entry.compressed_offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Chunk offset overflows"))?;
+        inner.seek(SeekFrom::Start(offset))?;
+        // read_to_end rather than a buffer of compressed_length, so a bad length
+        // fails at the end of the file instead of allocating it all up front
+        inner.take(entry.compressed_length).read_to_end(&mut data)?;
+        if (data.len() as u64) < entry.compressed_length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Chunk runs past the end of the image"));
+        }
+    }
+
+    decompress::decompress_chunk(&entry.entry_type, &data, sector_bytes(entry.sector_count)? as usize)
+}
+
+/// The length of sectors in bytes, if it fits in a u64
+fn sector_bytes(sectors: u64) -> Result<u64, io::Error> {
+    sectors
+        .checked_mul(512)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{} sectors is too many", sectors)))
+}
+
+/// Whether a chunk has bytes in the data fork
+pub fn has_data(entry_type: &DmgBlxx) -> bool {
+    matches!(
+        entry_type,
+        DmgBlxx::RawOrNullCompression
+            | DmgBlxx::AppleCompression
+            | DmgBlxx::ZLibCompression
+            | DmgBlxx::Bz2Compression
+            | DmgBlxx::LzfseCompression
+    )
+}
+
+impl<'a, R: Read + Seek> Read for PartitionReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let sector = self.position / 512;
+        let found = Some(self.chunks.partition_point(|c| c.sector + c.entry.sector_count <= sector))
+            .filter(|i| self.chunks.get(*i).map(|c| c.sector <= sector).unwrap_or(false));
+
+        let count = match found {
+            // zeros take no decompressing, so don't take up the cache either
+            Some(index) if !has_data(&self.chunks[index].entry.entry_type) => {
+                let chunk = &self.chunks[index];
+                let end = (chunk.sector + chunk.entry.sector_count) * 512;
+                let count = std::cmp::min(buf.len() as u64, end - self.position) as usize;
+                buf[..count].iter_mut().for_each(|b| *b = 0);
+                count
+            }
+            Some(index) => {
+                let start = self.chunks[index].sector * 512;
+                let offset = (self.position - start) as usize;
+                let chunk = self.load(index)?;
+                let count = std::cmp::min(buf.len(), chunk.len() - offset);
+                buf[..count].copy_from_slice(&chunk[offset..offset + count]);
+                count
+            }
+            // sectors not covered by any chunk read as zeros
+            None => {
+                let count = std::cmp::min(buf.len() as u64, 512 - self.position % 512) as usize;
+                buf[..count].iter_mut().for_each(|b| *b = 0);
+                count
+            }
+        };
+
+        let count = std::cmp::min(count as u64, self.length - self.position) as usize;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<'a, R: Read + Seek> Seek for PartitionReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => self.length as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the partition"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DmgBuilder;
+    use crate::convert::{build_koly, build_mish, final_blkx};
+    use crate::format::Format;
+
+    /// A sector count that isn't a whole number of chunks
+    fn disk() -> Vec<u8> {
+        (0..300 * 512u32).map(|i| (i / 512) as u8 ^ (i % 7) as u8).collect()
+    }
+
+    fn image() -> Vec<u8> {
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new().partition("disk image", &disk[..]).write_to(&mut image).unwrap();
+        image.into_inner()
+    }
+
+    #[test]
+    fn reads_from_bytes() {
+        let image = image();
+        let mut dmg = Dmg::from_bytes(&image).unwrap();
+
+        let mut contents = Vec::new();
+        dmg.open_disk().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, disk());
+    }
+
+    #[test]
+    fn reads_through_a_small_cache() {
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new().partition("disk image", &disk[..]).chunk_size(64).write_to(&mut image).unwrap();
+        let image = image.into_inner();
+        let mut dmg = Dmg::from_bytes(&image).unwrap();
+        assert_eq!(dmg.sector_index().chunks().len(), 5);
+        // a chunk at a time, always evicting the one before
+        dmg.cache().set_budget(0);
+
+        // backwards, a sector at a time
+        let mut reader = dmg.open_disk();
+        let mut sector = [0u8; 512];
+        for i in (0..300).rev() {
+            reader.seek(SeekFrom::Start(i * 512)).unwrap();
+            reader.read_exact(&mut sector).unwrap();
+            assert_eq!(&sector[..], &disk[i as usize * 512..(i as usize + 1) * 512]);
+        }
+
+        // each chunk missed once, then hit for its other sectors
+        let (hits, misses) = dmg.cache().stats();
+        assert_eq!(hits + misses, 300);
+        assert_eq!(misses, 5);
+    }
+
+    #[test]
+    fn binary_plist() {
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new()
+            .partition("disk image", &disk[..])
+            .binary_plist(true)
+            .write_to(&mut image)
+            .unwrap();
+        let image = image.into_inner();
+
+        let koly = KolyBlock::from_bytes(&image[image.len() - 512..]).unwrap();
+        assert!(image[koly.xml_offset as usize..].starts_with(b"bplist00"));
+
+        let mut contents = Vec::new();
+        Dmg::from_bytes(&image).unwrap().open_disk().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, disk);
+    }
+
+    #[test]
+    fn fork_past_the_end() {
+        let mut image = image();
+        let koly = image.len() - 512;
+
+        // xml_length, far more than the image holds
+        image[koly + 224..koly + 232].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+        let error = Dmg::from_bytes(&image).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // xml_offset, so the end overflows
+        image[koly + 216..koly + 224].copy_from_slice(&u64::MAX.to_be_bytes());
+        let error = Dmg::from_bytes(&image).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_plist() {
+        let mut image = image();
+        let koly = KolyBlock::from_bytes(&image[image.len() - 512..]).unwrap();
+
+        // swap the first tag of the plist for garbage
+        let start = koly.xml_offset as usize;
+        let xml = String::from_utf8(image[start..start + koly.xml_length as usize].to_vec()).unwrap();
+        let broken = xml.replacen("<dict>", "<list>", 1).replacen("</dict>", "</list>", 1);
+        image[start..start + broken.len()].copy_from_slice(broken.as_bytes());
+
+        let error = Dmg::from_bytes(&image).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn bad_chunk_sizes() {
+        // one raw chunk covering a partition of sectors sectors, in a data fork of 8 sectors
+        let image = |sectors: u64, compressed_length: u64| {
+            let entries = vec![
+                BlkxChunkEntry {
+                    entry_type: DmgBlxx::RawOrNullCompression,
+                    comment: 0,
+                    sector_number: 0,
+                    sector_count: sectors,
+                    compressed_offset: 0,
+                    compressed_length,
+                },
+                final_blkx(sectors as usize, 8 * 512),
+            ];
+            let mut image = vec![0xAAu8; 8 * 512];
+            let xml = PList::build(base64::encode(&build_mish(sectors, entries).to_be_bytes()), "disk image", &Default::default());
+            let koly = build_koly(xml.len() as u64, image.len() as u64, sectors);
+            image.extend_from_slice(&xml);
+            image.append(&mut koly.to_be_bytes());
+            image
+        };
+        assert!(Dmg::from_bytes(&image(8, 8 * 512)).is_ok());
+
+        // more compressed data than the data fork holds, up to overflowing
+        for length in [8 * 512 + 1, u64::MAX / 2, u64::MAX] {
+            let error = Dmg::from_bytes(&image(8, length)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", length);
+        }
+
+        // a chunk that would decompress to far too much, and a disk too big to measure in bytes
+        for sectors in [MAX_CHUNK_SIZE + 1, u64::MAX / 256] {
+            let error = Dmg::from_bytes(&image(sectors, 8 * 512)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", sectors);
+        }
+
+        // and read_chunk checks entries that didn't come from the index
+        let valid = image(8, 8 * 512);
+        let mut dmg = Dmg::from_bytes(&valid).unwrap();
+        let mish = dmg.plist().partitions[0].data.clone();
+        let entry = BlkxChunkEntry { sector_count: MAX_CHUNK_SIZE + 1, ..mish.block_entries[0].clone() };
+        assert_eq!(dmg.read_chunk(&mish, &entry).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let entry = BlkxChunkEntry { compressed_length: 16 * 512, ..mish.block_entries[0].clone() };
+        assert!(dmg.read_chunk(&mish, &entry).is_err());
+    }
+
+    /// Sectors 0 to 8 and 40 to 48 stored raw, with ZeroFill between,
+    /// and a plist padded out as other tools' plists can be
+    fn zero_filled() -> Vec<u8> {
+        let raw = |sector: u64, offset: u64| BlkxChunkEntry {
+            entry_type: DmgBlxx::RawOrNullCompression,
+            comment: 0,
+            sector_number: sector,
+            sector_count: 8,
+            compressed_offset: offset,
+            compressed_length: 8 * 512,
+        };
+        let zero = BlkxChunkEntry {
+            entry_type: DmgBlxx::ZeroFill,
+            comment: 0,
+            sector_number: 8,
+            sector_count: 32,
+            compressed_offset: 8 * 512,
+            compressed_length: 0,
+        };
+        let entries = vec![raw(0, 0), zero, raw(40, 8 * 512), final_blkx(48, 16 * 512)];
+
+        let mut image = vec![0xAAu8; 16 * 512];
+        let mut xml = PList::build(base64::encode(&build_mish(48, entries).to_be_bytes()), "disk image", &Default::default());
+        xml.extend_from_slice(&[b'\n'; 4096]);
+        let koly = build_koly(xml.len() as u64, image.len() as u64, 48);
+        image.extend_from_slice(&xml);
+        image.append(&mut koly.to_be_bytes());
+        image
+    }
+
+    fn read_disk(image: &[u8]) -> Vec<u8> {
+        let mut contents = Vec::new();
+        Dmg::from_bytes(image).unwrap().open_disk().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn write_at_udrw() {
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new()
+            .partition("disk image", &disk[..])
+            .format(Format::Udrw)
+            .chunk_size(64)
+            .write_to(&mut image)
+            .unwrap();
+
+        let mut dmg = Dmg::from_reader(image).unwrap();
+        // across a chunk boundary
+        let offset = 64 * 512 - 100;
+        dmg.write_at(0, offset, &[0x55; 300]).unwrap();
+        let image = dmg.inner.into_inner();
+
+        let mut expected = disk;
+        expected[offset as usize..offset as usize + 300].copy_from_slice(&[0x55; 300]);
+        assert_eq!(read_disk(&image), expected);
+
+        // the checksums no longer match, so they are gone
+        let dmg = Dmg::from_bytes(&image).unwrap();
+        assert_eq!(dmg.koly().data_fork_checksum.fork_type, 0);
+        assert_eq!(dmg.koly().master_checksum.fork_type, 0);
+        assert_eq!(dmg.plist().partitions[0].data.checksum.fork_type, 0);
+    }
+
+    #[test]
+    fn write_at_zero_fill() {
+        let original = zero_filled();
+        let mut dmg = Dmg::from_reader(io::Cursor::new(original.clone())).unwrap();
+        // sectors 9 to 11 of the ZeroFill chunk
+        dmg.write_at(0, 9 * 512 + 10, &[0x55; 1024]).unwrap();
+        // the same sectors again, now they're allocated
+        dmg.write_at(0, 10 * 512, &[0x66; 512]).unwrap();
+        let image = dmg.inner.into_inner();
+
+        let mut expected = vec![0xAAu8; 8 * 512];
+        expected.resize(40 * 512, 0);
+        expected.extend_from_slice(&[0xAA; 8 * 512]);
+        expected[9 * 512 + 10..11 * 512 + 10].copy_from_slice(&[0x55; 1024]);
+        expected[10 * 512..11 * 512].copy_from_slice(&[0x66; 512]);
+        assert_eq!(read_disk(&image), expected);
+
+        // only the written sectors took space
+        let dmg = Dmg::from_bytes(&image).unwrap();
+        assert_eq!(dmg.koly().data_fork_length, 19 * 512);
+        let counts: Vec<u64> = dmg.plist().partitions[0].data.block_entries.iter().map(|e| e.sector_count).collect();
+        assert_eq!(counts, vec![8, 1, 3, 28, 8, 0]);
+        assert!(crate::lint::lint(&mut io::Cursor::new(&image)).unwrap().is_empty());
+
+        // the old plist had no room after it, so the file grew, leaving
+        // a gap before the koly block that the next plist fits in
+        assert!(image.len() > original.len());
+        let mut dmg = Dmg::from_reader(io::Cursor::new(image.clone())).unwrap();
+        dmg.write_at(0, 30 * 512, &[0x77; 512]).unwrap();
+        let grown = dmg.inner.into_inner();
+        assert_eq!(grown.len(), image.len());
+        expected[30 * 512..31 * 512].copy_from_slice(&[0x77; 512]);
+        assert_eq!(read_disk(&grown), expected);
+        assert!(crate::lint::lint(&mut io::Cursor::new(&grown)).unwrap().is_empty());
+    }
+
+    /// Fails every write after the first writes, as if the machine
+    /// crashed there
+    struct Crashing {
+        inner: io::Cursor<Vec<u8>>,
+        writes: usize,
+    }
+
+    impl Read for Crashing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Seek for Crashing {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Write for Crashing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.writes.checked_sub(1) {
+                Some(writes) => {
+                    self.writes = writes;
+                    self.inner.write(buf)
+                }
+                None => Err(io::Error::other("Crashed")),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Write data at offset into an image that fails after each number of
+    /// writes in turn, checking what's left is a whole image where only
+    /// the written bytes have changed
+    fn crash_while_writing(original: Vec<u8>, offset: usize, data: &[u8]) {
+        let before = read_disk(&original);
+        for writes in 0.. {
+            let mut dmg = Dmg::from_reader(Crashing { inner: io::Cursor::new(original.clone()), writes }).unwrap();
+            let result = dmg.write_at(0, offset as u64, data);
+            let image = dmg.inner.inner.into_inner();
+
+            let findings = crate::lint::lint(&mut io::Cursor::new(&image)).unwrap();
+            assert!(findings.iter().all(|f| f.severity != crate::lint::Severity::Error), "{} writes: {:?}", writes, findings);
+            let after = read_disk(&image);
+            assert_eq!(after[..offset], before[..offset], "{} writes", writes);
+            assert_eq!(after[offset + data.len()..], before[offset + data.len()..], "{} writes", writes);
+            for (i, byte) in after[offset..offset + data.len()].iter().enumerate() {
+                assert!(*byte == before[offset + i] || *byte == data[i], "{} writes", writes);
+            }
+
+            if result.is_ok() {
+                assert_eq!(&after[offset..offset + data.len()], data);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn write_at_crashes() {
+        // growing the data fork over the old plist
+        crash_while_writing(zero_filled(), 9 * 512 + 10, &[0x55; 1024]);
+
+        // clearing the checksums of an image with its plist in place
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new().partition("disk image", &disk[..]).format(Format::Udrw).chunk_size(64).write_to(&mut image).unwrap();
+        crash_while_writing(image.into_inner(), 64 * 512 - 100, &[0x55; 300]);
+    }
+
+    #[test]
+    fn write_at_keeps_binary_plist() {
+        let disk = disk();
+        let mut image = io::Cursor::new(Vec::new());
+        DmgBuilder::new()
+            .partition("disk image", &disk[..])
+            .format(Format::Udrw)
+            .binary_plist(true)
+            .write_to(&mut image)
+            .unwrap();
+
+        let mut dmg = Dmg::from_reader(image).unwrap();
+        dmg.write_at(0, 0, &[0x55; 512]).unwrap();
+        let image = dmg.inner.into_inner();
+
+        let koly = KolyBlock::from_bytes(&image[image.len() - 512..]).unwrap();
+        assert!(image[koly.xml_offset as usize..].starts_with(b"bplist00"));
+        let mut expected = disk;
+        expected[..512].copy_from_slice(&[0x55; 512]);
+        assert_eq!(read_disk(&image), expected);
+    }
+
+    #[test]
+    fn write_at_compressed() {
+        let original = image();
+        let mut dmg = Dmg::from_reader(io::Cursor::new(original.clone())).unwrap();
+
+        let error = dmg.write_at(0, 0, &[0x55; 512]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(dmg.inner.into_inner(), original);
+    }
+
+    #[test]
+    fn write_at_past_the_end() {
+        let mut dmg = Dmg::from_reader(io::Cursor::new(zero_filled())).unwrap();
+
+        let error = dmg.write_at(0, 48 * 512 - 1, &[0x55; 2]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = dmg.write_at(0, u64::MAX, &[0x55]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = dmg.write_at(1, 0, &[0x55]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
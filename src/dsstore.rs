@@ -0,0 +1,565 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use super::bplist;
+use super::util;
+
+/// "Bud1" in ASCII, the buddy allocator magic
+pub const BUD1_MAGIC: &[u8; 4] = b"Bud1";
+
+/// Size of each B-tree node
+const PAGE_SIZE: usize = 4096;
+
+/// Unknown bytes following the buddy allocator header, as written by Finder
+const HEADER_UNKNOWN: [u8; 16] = [0, 0, 0x10, 0x0C, 0, 0, 0, 0x87, 0, 0, 0x20, 0x0B, 0, 0, 0, 0];
+
+/// The root (bookkeeping) block lives at 0x800, as Finder puts it
+const ROOT_OFFSET: u32 = 2048;
+const ROOT_SIZE: u32 = 2048;
+
+/// Value of a .DS_Store record
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordValue {
+    /// "long" - 4 byte integer
+    Long(u32),
+    /// "shor" - 2 byte integer, stored in 4 bytes
+    Short(u16),
+    /// "bool" - 1 byte
+    Bool(bool),
+    /// "blob" - length prefixed bytes, often a binary plist
+    Blob(Vec<u8>),
+    /// "type" - four character code
+    Type([u8; 4]),
+    /// "ustr" - length prefixed UTF-16 string
+    Ustr(String),
+    /// "comp" - 8 byte integer
+    Comp(u64),
+    /// "dutc" - 8 byte timestamp, 1/65536 seconds since 1904
+    Dutc(u64),
+}
+
+/// A single .DS_Store record, describing a property of a file
+/// in the folder (or of the folder itself, if filename is ".")
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Name of the file the record is about
+    pub filename: String,
+    /// Structure id, e.g. Iloc, bwsp, icvp
+    pub code: [u8; 4],
+    pub value: RecordValue,
+}
+
+impl RecordValue {
+    pub fn type_code(&self) -> &'static [u8; 4] {
+        match self {
+            RecordValue::Long(_) => b"long",
+            RecordValue::Short(_) => b"shor",
+            RecordValue::Bool(_) => b"bool",
+            RecordValue::Blob(_) => b"blob",
+            RecordValue::Type(_) => b"type",
+            RecordValue::Ustr(_) => b"ustr",
+            RecordValue::Comp(_) => b"comp",
+            RecordValue::Dutc(_) => b"dutc",
+        }
+    }
+
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut buffer = self.type_code().to_vec();
+
+        match self {
+            RecordValue::Long(long) => buffer.extend_from_slice(&long.to_be_bytes()),
+            RecordValue::Short(short) => buffer.extend_from_slice(&u32::from(*short).to_be_bytes()),
+            RecordValue::Bool(b) => buffer.push(*b as u8),
+            RecordValue::Blob(blob) => {
+                buffer.extend_from_slice(&(blob.len() as u32).to_be_bytes());
+                buffer.extend_from_slice(blob);
+            }
+            RecordValue::Type(code) => buffer.extend_from_slice(code),
+            RecordValue::Ustr(string) => buffer.append(&mut utf16_be(string)),
+            RecordValue::Comp(comp) => buffer.extend_from_slice(&comp.to_be_bytes()),
+            RecordValue::Dutc(dutc) => buffer.extend_from_slice(&dutc.to_be_bytes()),
+        }
+
+        buffer
+    }
+}
+
+impl Record {
+    pub fn new(filename: &str, code: &[u8; 4], value: RecordValue) -> Record {
+        Record { filename: filename.to_string(), code: *code, value }
+    }
+
+    pub fn to_be_bytes(&self) -> Vec<u8> {
+        let mut buffer = utf16_be(&self.filename);
+        buffer.extend_from_slice(&self.code);
+        buffer.append(&mut self.value.to_be_bytes());
+        buffer
+    }
+
+    /// Records are ordered by filename, case insensitively, then by code
+    pub fn compare(&self, other: &Record) -> Ordering {
+        self.filename
+            .to_lowercase()
+            .cmp(&other.filename.to_lowercase())
+            .then_with(|| self.code.cmp(&other.code))
+    }
+}
+
+/// UTF-16 string, prefixed with its length in code units
+fn utf16_be(string: &str) -> Vec<u8> {
+    let units: Vec<u16> = string.encode_utf16().collect();
+
+    let mut buffer = (units.len() as u32).to_be_bytes().to_vec();
+    for unit in units {
+        buffer.extend_from_slice(&unit.to_be_bytes());
+    }
+    buffer
+}
+
+/// Serialize records into a .DS_Store file.
+///
+/// The file is a buddy allocator: a header pointing at a root block,
+/// which lists the offsets of every other block, a table of contents
+/// (just "DSDB") and free lists. DSDB describes a B-tree whose nodes
+/// hold the records, sorted by filename.
+pub fn write(mut records: Vec<Record>) -> Result<Vec<u8>, String> {
+    records.sort_by(|a, b| a.compare(b));
+
+    // Block 0 is the root block, 1 is DSDB, then the B-tree nodes
+    let (nodes, levels) = build_nodes(&records)?;
+    let node_count = nodes.len() as u32;
+    let root_node = if levels == 0 { 2 } else { 1 + node_count };
+
+    let mut dsdb = Vec::new();
+    dsdb.extend_from_slice(&root_node.to_be_bytes());
+    dsdb.extend_from_slice(&levels.to_be_bytes());
+    dsdb.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    dsdb.extend_from_slice(&node_count.to_be_bytes());
+    dsdb.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+
+    // allocate every block at an offset aligned to its size
+    let mut blocks: Vec<(u32, u32)> = vec![(ROOT_OFFSET, ROOT_SIZE), (32, 32)];
+    let mut next = ROOT_OFFSET + ROOT_SIZE;
+    for _ in &nodes {
+        blocks.push((next, PAGE_SIZE as u32));
+        next += PAGE_SIZE as u32;
+    }
+
+    let root = root_block(&blocks);
+    if root.len() > ROOT_SIZE as usize {
+        return Err("Too many blocks for the root block".to_string());
+    }
+
+    let mut buffer = vec![0u8; 4 + next as usize];
+
+    // alignment, then the allocator header
+    buffer[0..4].copy_from_slice(&1u32.to_be_bytes());
+    buffer[4..8].copy_from_slice(BUD1_MAGIC);
+    buffer[8..12].copy_from_slice(&ROOT_OFFSET.to_be_bytes());
+    buffer[12..16].copy_from_slice(&ROOT_SIZE.to_be_bytes());
+    buffer[16..20].copy_from_slice(&ROOT_OFFSET.to_be_bytes());
+    buffer[20..36].copy_from_slice(&HEADER_UNKNOWN);
+
+    // block offsets are relative to the end of the alignment bytes
+    let contents = std::iter::once(root).chain(std::iter::once(dsdb)).chain(nodes);
+    for ((offset, size), content) in blocks.iter().zip(contents) {
+        if content.len() > *size as usize {
+            return Err(format!("{} bytes do not fit in a block of {}", content.len(), size));
+        }
+        let start = 4 + *offset as usize;
+        buffer[start..start + content.len()].copy_from_slice(&content);
+    }
+
+    Ok(buffer)
+}
+
+/// Build the B-tree nodes. Everything fits in a single leaf for a
+/// typical DMG window; otherwise the records are spread over several
+/// leaves under a single internal node.
+/// Returns the nodes (root last) and the number of internal levels.
+fn build_nodes(records: &[Record]) -> Result<(Vec<Vec<u8>>, u32), String> {
+    let encoded: Vec<Vec<u8>> = records.iter().map(Record::to_be_bytes).collect();
+
+    // every record has to fit in a node on its own
+    if let Some((record, _)) = records.iter().zip(&encoded).find(|(_, e)| 8 + e.len() > PAGE_SIZE) {
+        return Err(format!(
+            "The {} record of {:?} is too large for a .DS_Store node",
+            String::from_utf8_lossy(&record.code),
+            record.filename
+        ));
+    }
+
+    if 8 + encoded.iter().map(Vec::len).sum::<usize>() <= PAGE_SIZE {
+        return Ok((vec![node(0, &encoded.iter().collect::<Vec<_>>())], 0));
+    }
+
+    // fill leaves, with the record after each full leaf becoming a separator
+    let mut leaves: Vec<Vec<&Vec<u8>>> = vec![Vec::new()];
+    let mut separators: Vec<&Vec<u8>> = Vec::new();
+    let mut size = 8;
+    let mut iter = encoded.iter().peekable();
+    while let Some(record) = iter.next() {
+        if size + record.len() > PAGE_SIZE {
+            if iter.peek().is_some() {
+                separators.push(record);
+                leaves.push(Vec::new());
+                size = 8;
+                continue;
+            }
+            // nothing follows to separate the last leaf, so the last
+            // record of the full one separates it instead
+            let leaf = leaves.last_mut().unwrap();
+            separators.push(leaf.pop().unwrap());
+            leaves.push(Vec::new());
+            size = 8;
+        }
+        size += record.len();
+        leaves.last_mut().unwrap().push(record);
+    }
+
+    let mut nodes: Vec<Vec<u8>> = leaves.iter().map(|leaf| node(0, leaf)).collect();
+
+    // internal node - rightmost child, count, then (child, separator) pairs
+    let mut internal = Vec::new();
+    internal.extend_from_slice(&(1 + nodes.len() as u32).to_be_bytes());
+    internal.extend_from_slice(&(separators.len() as u32).to_be_bytes());
+    for (index, separator) in separators.iter().enumerate() {
+        internal.extend_from_slice(&(2 + index as u32).to_be_bytes());
+        internal.extend_from_slice(separator);
+    }
+
+    if internal.len() > PAGE_SIZE {
+        return Err("Too many records for a .DS_Store".to_string());
+    }
+
+    nodes.push(internal);
+    Ok((nodes, 1))
+}
+
+/// A leaf node - zero, count, then the records
+fn node(pointer: u32, records: &[&Vec<u8>]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&pointer.to_be_bytes());
+    buffer.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    for record in records {
+        buffer.extend_from_slice(record);
+    }
+    buffer
+}
+
+/// The root block - block offsets, table of contents and free lists
+fn root_block(blocks: &[(u32, u32)]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    buffer.extend_from_slice(&(blocks.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&0u32.to_be_bytes());
+
+    // offsets are stored with log2(size) in the low bits,
+    // in groups of 256 entries
+    let padded = blocks.len().div_ceil(256) * 256;
+    for index in 0..padded {
+        let address = blocks
+            .get(index)
+            .map(|(offset, size)| offset | size.trailing_zeros())
+            .unwrap_or(0);
+        buffer.extend_from_slice(&address.to_be_bytes());
+    }
+
+    // table of contents - DSDB is block 1
+    buffer.extend_from_slice(&1u32.to_be_bytes());
+    buffer.push(4);
+    buffer.extend_from_slice(b"DSDB");
+    buffer.extend_from_slice(&1u32.to_be_bytes());
+
+    // free lists for each power of two, covering everything that isn't
+    // allocated. The header occupies the first 32 bytes.
+    let mut allocated = blocks.to_vec();
+    allocated.push((0, 32));
+    let mut free: Vec<Vec<u32>> = vec![Vec::new(); 32];
+    free_blocks(0, 31, &allocated, &mut free);
+
+    for list in free {
+        buffer.extend_from_slice(&(list.len() as u32).to_be_bytes());
+        for offset in list {
+            buffer.extend_from_slice(&offset.to_be_bytes());
+        }
+    }
+
+    buffer
+}
+
+/// Find the free buddies within the block at offset of size 2^width
+fn free_blocks(offset: u32, width: u32, allocated: &[(u32, u32)], free: &mut Vec<Vec<u32>>) {
+    let size = 1u64 << width;
+    let end = u64::from(offset) + size;
+
+    let overlapping: Vec<&(u32, u32)> = allocated
+        .iter()
+        .filter(|(o, s)| u64::from(*o) < end && u64::from(*o) + u64::from(*s) > u64::from(offset))
+        .collect();
+
+    if overlapping.is_empty() {
+        free[width as usize].push(offset);
+    } else if overlapping.len() == 1 && overlapping[0].0 == offset && u64::from(overlapping[0].1) == size {
+        // exactly this block is allocated
+    } else {
+        let half = (size / 2) as u32;
+        free_blocks(offset, width - 1, allocated, free);
+        free_blocks(offset + half, width - 1, allocated, free);
+    }
+}
+
+/// Parse a .DS_Store file into its records, in B-tree order
+pub fn read(buffer: &[u8]) -> Result<Vec<Record>, String> {
+    if buffer.len() < 36 || &buffer[4..8] != BUD1_MAGIC {
+        return Err("Invalid Bud1 magic bytes".to_string());
+    }
+
+    let root_offset = util::read_be_u32(&mut &buffer[8..12]) as usize;
+    let root_size = util::read_be_u32(&mut &buffer[12..16]) as usize;
+    let allocator = Allocator::new(buffer, slice(buffer, 4 + root_offset, root_size)?)?;
+
+    let dsdb = allocator.block(allocator.toc("DSDB")?)?;
+    if dsdb.len() < 20 {
+        return Err("Truncated DSDB block".to_string());
+    }
+    let root_node = util::read_be_u32(&mut &dsdb[0..4]);
+
+    let mut records = Vec::new();
+    allocator.traverse(root_node, &mut HashSet::new(), &mut records)?;
+
+    Ok(records)
+}
+
+/// The bookkeeping information from the root block
+struct Allocator<'a> {
+    buffer: &'a [u8],
+    /// Block addresses, offset | log2(size)
+    addresses: Vec<u32>,
+    /// Table of contents, name to block number
+    toc: Vec<(String, u32)>,
+}
+
+impl<'a> Allocator<'a> {
+    fn new(buffer: &'a [u8], root: &'a [u8]) -> Result<Allocator<'a>, String> {
+        let mut cursor = root;
+
+        let count = read_u32(&mut cursor)? as usize;
+        read_u32(&mut cursor)?;
+
+        let padded = count.div_ceil(256) * 256;
+        let mut addresses = Vec::new();
+        for index in 0..padded {
+            let address = read_u32(&mut cursor)?;
+            if index < count {
+                addresses.push(address);
+            }
+        }
+
+        let mut toc = Vec::new();
+        for _ in 0..read_u32(&mut cursor)? {
+            let length = *cursor.first().ok_or("Truncated table of contents")? as usize;
+            let name = cursor.get(1..1 + length).ok_or("Truncated table of contents")?;
+            let name = String::from_utf8_lossy(name).to_string();
+            cursor = &cursor[1 + length..];
+            toc.push((name, read_u32(&mut cursor)?));
+        }
+
+        Ok(Allocator { buffer, addresses, toc })
+    }
+
+    fn toc(&self, name: &str) -> Result<u32, String> {
+        self.toc
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, block)| *block)
+            .ok_or_else(|| format!("No {} in the table of contents", name))
+    }
+
+    fn block(&self, number: u32) -> Result<&'a [u8], String> {
+        let address = *self.addresses
+            .get(number as usize)
+            .ok_or_else(|| format!("Block {} does not exist", number))?;
+
+        let offset = (address & !0x1F) as usize;
+        let size = 1usize << (address & 0x1F);
+
+        slice(self.buffer, 4 + offset, size)
+    }
+
+    /// Walk a node, adding records in order
+    fn traverse(&self, number: u32, visited: &mut HashSet<u32>, records: &mut Vec<Record>) -> Result<(), String> {
+        // a corrupt tree could point back at a node we've already seen
+        if !visited.insert(number) {
+            return Err(format!("Block {} is referenced more than once", number));
+        }
+
+        let mut cursor = self.block(number)?;
+        let pointer = read_u32(&mut cursor)?;
+        let count = read_u32(&mut cursor)?;
+
+        // internal nodes have a child before each record, and the
+        // rightmost child in the pointer
+        for _ in 0..count {
+            if pointer != 0 {
+                let child = read_u32(&mut cursor)?;
+                self.traverse(child, visited, records)?;
+            }
+            records.push(Record::from_be_bytes(&mut cursor)?);
+        }
+
+        if pointer != 0 {
+            self.traverse(pointer, visited, records)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Record {
+    /// Parse a record, advancing the cursor past it
+    pub fn from_be_bytes(cursor: &mut &[u8]) -> Result<Record, String> {
+        let filename = read_utf16(cursor)?;
+        let code = read_code(cursor)?;
+        let kind = read_code(cursor)?;
+
+        let value = match &kind {
+            b"long" => RecordValue::Long(read_u32(cursor)?),
+            b"shor" => RecordValue::Short(read_u32(cursor)? as u16),
+            b"bool" => {
+                let b = *cursor.first().ok_or("Truncated record")?;
+                *cursor = &cursor[1..];
+                RecordValue::Bool(b != 0)
+            }
+            b"blob" => {
+                let length = read_u32(cursor)? as usize;
+                let blob = cursor.get(..length).ok_or("Truncated blob")?.to_vec();
+                *cursor = &cursor[length..];
+                RecordValue::Blob(blob)
+            }
+            b"type" => RecordValue::Type(read_code(cursor)?),
+            b"ustr" => RecordValue::Ustr(read_utf16(cursor)?),
+            b"comp" => RecordValue::Comp(read_u64(cursor)?),
+            b"dutc" => RecordValue::Dutc(read_u64(cursor)?),
+            _ => return Err(format!("Unknown record type {:?}", String::from_utf8_lossy(&kind))),
+        };
+
+        Ok(Record { filename, code, value })
+    }
+
+    /// Convert into JSON. Binary plist blobs are decoded, as are icon locations.
+    pub fn to_json(&self) -> serde_json::Value {
+        let value = match &self.value {
+            RecordValue::Long(long) => serde_json::Value::from(*long),
+            RecordValue::Short(short) => serde_json::Value::from(*short),
+            RecordValue::Bool(b) => serde_json::Value::from(*b),
+            RecordValue::Blob(blob) if &self.code == b"Iloc" && blob.len() >= 8 => serde_json::json!({
+                "x": util::read_be_u32(&mut &blob[0..4]),
+                "y": util::read_be_u32(&mut &blob[4..8]),
+            }),
+            RecordValue::Blob(blob) => match bplist::read(blob) {
+                Ok(plist) => serde_json::json!({ "bplist": plist.to_json() }),
+                Err(_) => serde_json::json!({ "hex": util::encode_hex(blob) }),
+            },
+            RecordValue::Type(code) => serde_json::Value::from(String::from_utf8_lossy(code).to_string()),
+            RecordValue::Ustr(string) => serde_json::Value::from(string.clone()),
+            RecordValue::Comp(comp) => serde_json::Value::from(*comp),
+            RecordValue::Dutc(dutc) => serde_json::Value::from(*dutc),
+        };
+
+        serde_json::json!({
+            "filename": self.filename,
+            "code": String::from_utf8_lossy(&self.code),
+            "type": String::from_utf8_lossy(self.value.type_code()),
+            "value": value,
+        })
+    }
+}
+
+fn slice(buffer: &[u8], start: usize, length: usize) -> Result<&[u8], String> {
+    start
+        .checked_add(length)
+        .and_then(|end| buffer.get(start..end))
+        .ok_or_else(|| "Block is out of bounds".to_string())
+}
+
+fn read_u32(cursor: &mut &[u8]) -> Result<u32, String> {
+    if cursor.len() < 4 {
+        return Err("Truncated .DS_Store".to_string());
+    }
+    Ok(util::read_be_u32(cursor))
+}
+
+fn read_u64(cursor: &mut &[u8]) -> Result<u64, String> {
+    if cursor.len() < 8 {
+        return Err("Truncated .DS_Store".to_string());
+    }
+    Ok(util::read_be_u64(cursor))
+}
+
+fn read_code(cursor: &mut &[u8]) -> Result<[u8; 4], String> {
+    Ok(read_u32(cursor)?.to_be_bytes())
+}
+
+fn read_utf16(cursor: &mut &[u8]) -> Result<String, String> {
+    let length = read_u32(cursor)? as usize;
+    let bytes = length
+        .checked_mul(2)
+        .and_then(|size| cursor.get(..size))
+        .ok_or("Truncated string")?;
+
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|u| u16::from_be_bytes([u[0], u[1]])).collect();
+    *cursor = &cursor[length * 2..];
+
+    Ok(String::from_utf16_lossy(&units))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(records: Vec<Record>) {
+        let mut sorted = records.clone();
+        sorted.sort_by(|a, b| a.compare(b));
+
+        let written = write(records).unwrap();
+        assert_eq!(read(&written).unwrap(), sorted);
+    }
+
+    #[test]
+    fn single_leaf_round_trip() {
+        round_trip(vec![
+            Record::new("Bitcoin-Qt.app", b"Iloc", RecordValue::Blob(vec![0, 0, 0, 128, 0, 0, 0, 64, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0])),
+            Record::new(".", b"icvo", RecordValue::Type(*b"icv4")),
+            Record::new(".", b"vSrn", RecordValue::Long(1)),
+            Record::new(".", b"ICVO", RecordValue::Bool(true)),
+            Record::new("Applications", b"cmmt", RecordValue::Ustr("Drag here".to_string())),
+            Record::new(".", b"moDD", RecordValue::Dutc(0x0000_CA7F_0000_0000)),
+            Record::new(".", b"logS", RecordValue::Comp(4096)),
+        ]);
+    }
+
+    #[test]
+    fn many_leaves_round_trip() {
+        let records = (0..400)
+            .map(|i| Record::new(&format!("file {:03}", i), b"cmmt", RecordValue::Ustr(format!("comment {}", i))))
+            .collect();
+        round_trip(records);
+    }
+
+    #[test]
+    fn large_last_record() {
+        // the last record doesn't fit in the leaf before it
+        let mut records: Vec<Record> = (0..6)
+            .map(|i| Record::new(&format!("{}", i), b"bwsp", RecordValue::Blob(vec![i as u8; 1000])))
+            .collect();
+        records.push(Record::new("z", b"pict", RecordValue::Blob(vec![7u8; 3500])));
+        round_trip(records);
+    }
+
+    #[test]
+    fn oversized_record() {
+        let records = vec![Record::new(".", b"pict", RecordValue::Blob(vec![0u8; PAGE_SIZE]))];
+        assert!(write(records).is_err());
+    }
+}
@@ -0,0 +1,720 @@
+use std::io::{self, prelude::{Read, Seek}, SeekFrom};
+
+use aes::cipher::{block_padding::{NoPadding, Pkcs7}, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use super::util;
+
+/// "encrcdsa" in ASCII
+pub const ENCRCDSA_MAGIC: &[u8; 8] = b"encrcdsa";
+
+/// Key pointer type for a passphrase wrapped key
+pub const PASSPHRASE_KEY_TYPE: u32 = 1;
+
+/// Size of the fixed part of the header, before the key pointers
+const HEADER_SIZE: usize = 76;
+/// Size of each key pointer
+const KEY_POINTER_SIZE: usize = 20;
+/// Size of the password key header, before the wrapped key blob
+const PASSWORD_KEY_SIZE: usize = 104;
+/// Images have one key pointer per way to unlock them, refuse more than this
+const MAX_KEY_POINTERS: usize = 16;
+/// Largest wrapped key read, far more than a passphrase or public key needs
+const MAX_KEY_SIZE: u64 = 64 * 1024;
+
+/// Most PBKDF2 iterations to run when unwrapping, far more than hdiutil
+/// picks, so a corrupt count can't keep us busy for hours
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+
+/// Length of the HMAC-SHA1 key used to derive the per-chunk IVs
+const HMAC_KEY_LENGTH: usize = 20;
+
+/// Size of each encrypted chunk when creating images
+const BLOCK_SIZE: u32 = 4096;
+/// PBKDF2 iterations used when wrapping the key
+#[cfg(not(test))]
+const KDF_ITERATIONS: u32 = 100_000;
+/// Unoptimised, the full count takes seconds per key
+#[cfg(test)]
+const KDF_ITERATIONS: u32 = 1000;
+
+/// Represents a version 2 "encrcdsa" header.
+/// Found at the very start of an encrypted DMG, it wraps the
+/// entire UDIF image (data fork, plist and koly block).
+/// All fields are in big endian ordering.
+#[derive(Debug)]
+pub struct EncryptedHeader {
+    /// Magic - "encrcdsa" in ASCII
+    pub signature: u64,
+    /// Version 2
+    pub version: u32,
+    /// Size of the per-chunk IV - 16 bytes
+    pub enc_iv_size: u32,
+    /// Encryption mode, usually 5
+    pub enc_mode: u32,
+    /// Encryption algorithm, 0x80000001 for AES
+    pub enc_algorithm: u32,
+    /// AES key size - 128 or 256 bits
+    pub key_bits: u32,
+    /// PRNG algorithm, 0x80000002
+    pub prng_algorithm: u32,
+    /// PRNG key size in bits
+    pub prng_key_bits: u32,
+    /// 128-bit UUID of the image
+    pub uuid: u128,
+    /// Size of each encrypted chunk - usually 4096 bytes
+    pub block_size: u32,
+    /// Size of the decrypted data in bytes
+    pub data_size: u64,
+    /// Start of the encrypted data
+    pub data_offset: u64,
+    /// Where the wrapped keys can be found
+    pub key_pointers: Vec<KeyPointer>,
+}
+
+/// Points at a wrapped key somewhere in the encrypted image
+#[derive(Debug)]
+pub struct KeyPointer {
+    /// 1 - passphrase, 2 - public key
+    pub key_type: u32,
+    /// Start of the key header
+    pub offset: u64,
+    /// Length of the key header
+    pub size: u64,
+}
+
+/// Passphrase wrapped key.
+/// The AES key and the HMAC-SHA1 key are encrypted with 3DES-CBC,
+/// using a key derived from the passphrase with PBKDF2-HMAC-SHA1.
+#[derive(Debug)]
+pub struct PasswordKey {
+    /// 103 - PBKDF2
+    pub kdf_algorithm: u32,
+    /// PRNG used by the KDF, 0
+    pub kdf_prng_algorithm: u32,
+    /// Number of PBKDF2 iterations
+    pub kdf_iteration_count: u32,
+    /// Length of the salt
+    pub kdf_salt_len: u32,
+    /// Up to 32 bytes of salt
+    pub kdf_salt: Vec<u8>,
+    /// Length of the key blob IV - 8 bytes
+    pub blob_enc_iv_size: u32,
+    /// Up to 32 bytes of IV
+    pub blob_enc_iv: Vec<u8>,
+    /// 192 - 3DES
+    pub blob_enc_key_bits: u32,
+    /// 17 - 3DES
+    pub blob_enc_algorithm: u32,
+    /// 7 - PKCS7
+    pub blob_enc_padding: u32,
+    /// 6 - CBC
+    pub blob_enc_mode: u32,
+    /// Length of the wrapped key
+    pub encrypted_keyblob_size: u32,
+    /// AES key followed by HMAC-SHA1 key, 3DES encrypted
+    pub encrypted_keyblob: Vec<u8>,
+}
+
+/// Unwrapped key material
+pub struct ImageKey {
+    /// 16 or 32 bytes of AES key
+    pub aes_key: Vec<u8>,
+    /// 20 bytes of HMAC-SHA1 key
+    pub hmac_key: Vec<u8>,
+}
+
+impl EncryptedHeader {
+    pub fn from_be_bytes(buffer: &[u8]) -> Result<EncryptedHeader, io::Error> {
+        if buffer.len() < HEADER_SIZE || &buffer[0..8] != ENCRCDSA_MAGIC {
+            return Err(invalid("Invalid encrcdsa magic bytes"));
+        }
+
+        let version = util::read_be_u32(&mut &buffer[8..12]);
+        if version != 2 {
+            return Err(invalid(&format!("Unsupported encrcdsa version {}", version)));
+        }
+
+        let key_count = util::read_be_u32(&mut &buffer[72..76]) as usize;
+        if key_count > MAX_KEY_POINTERS {
+            return Err(invalid(&format!("Too many encrcdsa key pointers: {}", key_count)));
+        }
+        if buffer.len() < HEADER_SIZE + key_count * KEY_POINTER_SIZE {
+            return Err(invalid("Truncated encrcdsa key pointers"));
+        }
+
+        let key_pointers = buffer[HEADER_SIZE..HEADER_SIZE + key_count * KEY_POINTER_SIZE]
+            .chunks_exact(KEY_POINTER_SIZE)
+            .map(|p| KeyPointer {
+                key_type: util::read_be_u32(&mut &p[0..4]),
+                offset: util::read_be_u64(&mut &p[4..12]),
+                size: util::read_be_u64(&mut &p[12..20]),
+            })
+            .collect();
+
+        Ok(EncryptedHeader {
+            signature: util::read_be_u64(&mut &buffer[0..8]),
+            version,
+            enc_iv_size: util::read_be_u32(&mut &buffer[12..16]),
+            enc_mode: util::read_be_u32(&mut &buffer[16..20]),
+            enc_algorithm: util::read_be_u32(&mut &buffer[20..24]),
+            key_bits: util::read_be_u32(&mut &buffer[24..28]),
+            prng_algorithm: util::read_be_u32(&mut &buffer[28..32]),
+            prng_key_bits: util::read_be_u32(&mut &buffer[32..36]),
+            uuid: util::read_be_u128(&mut &buffer[36..52]),
+            block_size: util::read_be_u32(&mut &buffer[52..56]),
+            data_size: util::read_be_u64(&mut &buffer[56..64]),
+            data_offset: util::read_be_u64(&mut &buffer[64..72]),
+            key_pointers,
+        })
+    }
+}
+
+impl EncryptedHeader {
+    pub fn to_be_bytes(self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        buffer.extend_from_slice(&self.signature.to_be_bytes());
+        buffer.extend_from_slice(&self.version.to_be_bytes());
+        buffer.extend_from_slice(&self.enc_iv_size.to_be_bytes());
+        buffer.extend_from_slice(&self.enc_mode.to_be_bytes());
+        buffer.extend_from_slice(&self.enc_algorithm.to_be_bytes());
+        buffer.extend_from_slice(&self.key_bits.to_be_bytes());
+        buffer.extend_from_slice(&self.prng_algorithm.to_be_bytes());
+        buffer.extend_from_slice(&self.prng_key_bits.to_be_bytes());
+        buffer.extend_from_slice(&self.uuid.to_be_bytes());
+        buffer.extend_from_slice(&self.block_size.to_be_bytes());
+        buffer.extend_from_slice(&self.data_size.to_be_bytes());
+        buffer.extend_from_slice(&self.data_offset.to_be_bytes());
+
+        buffer.extend_from_slice(&(self.key_pointers.len() as u32).to_be_bytes());
+        for pointer in self.key_pointers {
+            buffer.extend_from_slice(&pointer.key_type.to_be_bytes());
+            buffer.extend_from_slice(&pointer.offset.to_be_bytes());
+            buffer.extend_from_slice(&pointer.size.to_be_bytes());
+        }
+
+        buffer
+    }
+}
+
+impl PasswordKey {
+    pub fn from_be_bytes(buffer: &[u8]) -> Result<PasswordKey, io::Error> {
+        if buffer.len() < PASSWORD_KEY_SIZE {
+            return Err(invalid("Truncated password key header"));
+        }
+
+        let kdf_salt_len = util::read_be_u32(&mut &buffer[12..16]);
+        let blob_enc_iv_size = util::read_be_u32(&mut &buffer[48..52]);
+        let encrypted_keyblob_size = util::read_be_u32(&mut &buffer[100..104]);
+
+        if kdf_salt_len > 32 || blob_enc_iv_size > 32 || buffer.len() < PASSWORD_KEY_SIZE + encrypted_keyblob_size as usize {
+            return Err(invalid("Malformed password key header"));
+        }
+
+        Ok(PasswordKey {
+            kdf_algorithm: util::read_be_u32(&mut &buffer[0..4]),
+            kdf_prng_algorithm: util::read_be_u32(&mut &buffer[4..8]),
+            kdf_iteration_count: util::read_be_u32(&mut &buffer[8..12]),
+            kdf_salt_len,
+            kdf_salt: buffer[16..48].to_vec(),
+            blob_enc_iv_size,
+            blob_enc_iv: buffer[52..84].to_vec(),
+            blob_enc_key_bits: util::read_be_u32(&mut &buffer[84..88]),
+            blob_enc_algorithm: util::read_be_u32(&mut &buffer[88..92]),
+            blob_enc_padding: util::read_be_u32(&mut &buffer[92..96]),
+            blob_enc_mode: util::read_be_u32(&mut &buffer[96..100]),
+            encrypted_keyblob_size,
+            encrypted_keyblob: buffer[PASSWORD_KEY_SIZE..PASSWORD_KEY_SIZE + encrypted_keyblob_size as usize].to_vec(),
+        })
+    }
+
+    /// Derive the 3DES key from the passphrase, and use it to unwrap
+    /// the AES and HMAC keys.
+    pub fn unwrap(&self, passphrase: &[u8], key_bits: u32) -> Result<ImageKey, io::Error> {
+        if self.kdf_iteration_count > MAX_KDF_ITERATIONS {
+            return Err(invalid(&format!("Too many PBKDF2 iterations: {}", self.kdf_iteration_count)));
+        }
+        let salt = &self.kdf_salt[..self.kdf_salt_len as usize];
+        let mut derived = [0u8; 24];
+        pbkdf2::pbkdf2_hmac::<Sha1>(passphrase, salt, self.kdf_iteration_count, &mut derived);
+
+        let iv = &self.blob_enc_iv[..self.blob_enc_iv_size as usize];
+        let mut blob = self.encrypted_keyblob.clone();
+
+        // A wrong passphrase shows up as bad padding
+        let decrypted = cbc::Decryptor::<des::TdesEde3>::new_from_slices(&derived, iv)
+            .map_err(|_| invalid("Invalid key blob IV"))?
+            .decrypt_padded_mut::<Pkcs7>(&mut blob)
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "Incorrect passphrase"))?;
+
+        ImageKey::from_bytes(decrypted, key_bits)
+    }
+
+    /// Wrap an image key with a key derived from the passphrase
+    pub fn wrap(key: &ImageKey, passphrase: &[u8], salt: Vec<u8>, iv: Vec<u8>) -> PasswordKey {
+        let mut derived = [0u8; 24];
+        pbkdf2::pbkdf2_hmac::<Sha1>(passphrase, &salt, KDF_ITERATIONS, &mut derived);
+
+        let mut blob = key.aes_key.clone();
+        blob.extend_from_slice(&key.hmac_key);
+        let length = blob.len();
+        blob.resize(length + 8, 0);
+
+        let encrypted_keyblob = cbc::Encryptor::<des::TdesEde3>::new_from_slices(&derived, &iv)
+            .expect("3DES key and IV are the right length")
+            .encrypt_padded_mut::<Pkcs7>(&mut blob, length)
+            .expect("Buffer has room for padding")
+            .to_vec();
+
+        let mut kdf_salt = salt.clone();
+        kdf_salt.resize(32, 0);
+        let mut blob_enc_iv = iv.clone();
+        blob_enc_iv.resize(32, 0);
+
+        PasswordKey {
+            kdf_algorithm: 103,
+            kdf_prng_algorithm: 0,
+            kdf_iteration_count: KDF_ITERATIONS,
+            kdf_salt_len: salt.len() as u32,
+            kdf_salt,
+            blob_enc_iv_size: iv.len() as u32,
+            blob_enc_iv,
+            blob_enc_key_bits: 192,
+            blob_enc_algorithm: 17,
+            blob_enc_padding: 7,
+            blob_enc_mode: 6,
+            encrypted_keyblob_size: encrypted_keyblob.len() as u32,
+            encrypted_keyblob,
+        }
+    }
+
+    pub fn to_be_bytes(self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        buffer.extend_from_slice(&self.kdf_algorithm.to_be_bytes());
+        buffer.extend_from_slice(&self.kdf_prng_algorithm.to_be_bytes());
+        buffer.extend_from_slice(&self.kdf_iteration_count.to_be_bytes());
+        buffer.extend_from_slice(&self.kdf_salt_len.to_be_bytes());
+        buffer.extend_from_slice(&self.kdf_salt);
+        buffer.extend_from_slice(&self.blob_enc_iv_size.to_be_bytes());
+        buffer.extend_from_slice(&self.blob_enc_iv);
+        buffer.extend_from_slice(&self.blob_enc_key_bits.to_be_bytes());
+        buffer.extend_from_slice(&self.blob_enc_algorithm.to_be_bytes());
+        buffer.extend_from_slice(&self.blob_enc_padding.to_be_bytes());
+        buffer.extend_from_slice(&self.blob_enc_mode.to_be_bytes());
+        buffer.extend_from_slice(&self.encrypted_keyblob_size.to_be_bytes());
+        buffer.extend_from_slice(&self.encrypted_keyblob);
+
+        buffer
+    }
+}
+
+impl ImageKey {
+    /// Split an unwrapped key blob into the AES and HMAC keys
+    pub fn from_bytes(blob: &[u8], key_bits: u32) -> Result<ImageKey, io::Error> {
+        let aes_length = (key_bits / 8) as usize;
+
+        if (key_bits != 128 && key_bits != 256) || blob.len() < aes_length + HMAC_KEY_LENGTH {
+            return Err(invalid("Key blob does not match the image key size"));
+        }
+
+        Ok(ImageKey {
+            aes_key: blob[..aes_length].to_vec(),
+            hmac_key: blob[aes_length..aes_length + HMAC_KEY_LENGTH].to_vec(),
+        })
+    }
+
+    /// Read a keyfile containing the unwrapped key (AES key followed by
+    /// HMAC key), either as raw bytes or as a hex string like vfdecrypt -k.
+    pub fn from_keyfile(contents: &[u8], key_bits: u32) -> Result<ImageKey, io::Error> {
+        let text = String::from_utf8_lossy(contents);
+        match util::decode_hex(text.trim()) {
+            Some(bytes) => ImageKey::from_bytes(&bytes, key_bits),
+            None => ImageKey::from_bytes(contents, key_bits),
+        }
+    }
+
+    /// The IV of each chunk is the truncated HMAC-SHA1 of its number
+    pub fn chunk_iv(&self, chunk: u32) -> Vec<u8> {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.hmac_key).expect("HMAC accepts any key length");
+        mac.update(&chunk.to_be_bytes());
+        mac.finalize().into_bytes()[..16].to_vec()
+    }
+
+    /// Decrypt a single chunk in place
+    pub fn decrypt_chunk(&self, chunk: u32, data: &mut [u8]) -> Result<(), io::Error> {
+        let iv = self.chunk_iv(chunk);
+
+        let result = match self.aes_key.len() {
+            16 => cbc::Decryptor::<aes::Aes128>::new_from_slices(&self.aes_key, &iv)
+                .map_err(|_| invalid("Invalid AES key"))?
+                .decrypt_padded_mut::<NoPadding>(data)
+                .map(|_| ()),
+            _ => cbc::Decryptor::<aes::Aes256>::new_from_slices(&self.aes_key, &iv)
+                .map_err(|_| invalid("Invalid AES key"))?
+                .decrypt_padded_mut::<NoPadding>(data)
+                .map(|_| ()),
+        };
+
+        result.map_err(|_| invalid("Encrypted chunk is not a multiple of the AES block size"))
+    }
+
+    /// Encrypt a single chunk in place
+    pub fn encrypt_chunk(&self, chunk: u32, data: &mut [u8]) -> Result<(), io::Error> {
+        let iv = self.chunk_iv(chunk);
+        let length = data.len();
+
+        let result = match self.aes_key.len() {
+            16 => cbc::Encryptor::<aes::Aes128>::new_from_slices(&self.aes_key, &iv)
+                .map_err(|_| invalid("Invalid AES key"))?
+                .encrypt_padded_mut::<NoPadding>(data, length)
+                .map(|_| ()),
+            _ => cbc::Encryptor::<aes::Aes256>::new_from_slices(&self.aes_key, &iv)
+                .map_err(|_| invalid("Invalid AES key"))?
+                .encrypt_padded_mut::<NoPadding>(data, length)
+                .map(|_| ()),
+        };
+
+        result.map_err(|_| invalid("Chunk is not a multiple of the AES block size"))
+    }
+}
+
+/// How to encrypt a DMG during conversion
+#[derive(Debug)]
+pub struct Encryption {
+    /// AES key size - 128 or 256 bits
+    pub key_bits: u32,
+    /// Passphrase used to wrap the key
+    pub passphrase: Vec<u8>,
+    /// Derive the keys, salt, IVs and UUID from the passphrase instead
+    /// of /dev/urandom. Only for reproducible test fixtures, as anyone
+    /// with the passphrase can recreate the image key.
+    pub deterministic: bool,
+}
+
+impl Encryption {
+    /// Source of the key material, salts and IVs
+    fn material(&self, label: &str, len: usize) -> Result<Vec<u8>, io::Error> {
+        if !self.deterministic {
+            return util::random_bytes(len);
+        }
+
+        // HMAC-SHA1(passphrase, label || counter), truncated to len
+        let mut bytes = Vec::new();
+        let mut counter = 0u32;
+        while bytes.len() < len {
+            let mut mac = Hmac::<Sha1>::new_from_slice(&self.passphrase).expect("HMAC accepts any key length");
+            mac.update(label.as_bytes());
+            mac.update(&counter.to_be_bytes());
+            bytes.extend_from_slice(&mac.finalize().into_bytes());
+            counter += 1;
+        }
+        bytes.truncate(len);
+
+        Ok(bytes)
+    }
+}
+
+/// Parse an encryption algorithm name, as accepted by hdiutil -encryption
+pub fn parse_algorithm(algorithm: &str) -> Result<u32, String> {
+    match algorithm.to_lowercase().as_str() {
+        "aes-128" => Ok(128),
+        "aes-256" => Ok(256),
+        _ => Err(format!("Unsupported encryption {:?}, expected aes-128 or aes-256", algorithm)),
+    }
+}
+
+/// Wrap a complete UDIF image (data fork, plist and koly block)
+/// in an encrcdsa v2 container.
+pub fn encrypt(udif: &[u8], encryption: &Encryption) -> Result<Vec<u8>, io::Error> {
+    let key_bytes = encryption.material("key", (encryption.key_bits / 8) as usize + HMAC_KEY_LENGTH)?;
+    let key = ImageKey::from_bytes(&key_bytes, encryption.key_bits)?;
+
+    let password_key = PasswordKey::wrap(
+        &key,
+        &encryption.passphrase,
+        encryption.material("salt", 20)?,
+        encryption.material("iv", 8)?,
+    );
+    let password_key = password_key.to_be_bytes();
+
+    let key_offset = (HEADER_SIZE + KEY_POINTER_SIZE) as u64;
+    let header = EncryptedHeader {
+        signature: util::read_be_u64(&mut &ENCRCDSA_MAGIC[..]),
+        version: 2,
+        enc_iv_size: 16,
+        enc_mode: 5,
+        enc_algorithm: 0x8000_0001,
+        key_bits: encryption.key_bits,
+        prng_algorithm: 0x8000_0002,
+        prng_key_bits: 128,
+        uuid: util::read_be_u128(&mut &encryption.material("uuid", 16)?[..]),
+        block_size: BLOCK_SIZE,
+        data_size: udif.len() as u64,
+        data_offset: u64::from(BLOCK_SIZE),
+        key_pointers: vec![KeyPointer {
+            key_type: PASSPHRASE_KEY_TYPE,
+            offset: key_offset,
+            size: password_key.len() as u64,
+        }],
+    };
+
+    // header and wrapped key, zero padded up to the first chunk
+    let mut buffer = header.to_be_bytes();
+    buffer.extend_from_slice(&password_key);
+    buffer.resize(BLOCK_SIZE as usize, 0);
+
+    // the last chunk is zero padded to a full block
+    for (number, chunk) in udif.chunks(BLOCK_SIZE as usize).enumerate() {
+        let mut data = chunk.to_vec();
+        data.resize(BLOCK_SIZE as usize, 0);
+        key.encrypt_chunk(number as u32, &mut data)?;
+        buffer.append(&mut data);
+    }
+
+    Ok(buffer)
+}
+
+/// Presents the decrypted contents of an encrcdsa image, so that the
+/// koly block and plist can be parsed as if it was a plain UDIF image.
+pub struct EncryptedReader<R: Read + Seek> {
+    inner: R,
+    pub header: EncryptedHeader,
+    key: ImageKey,
+    /// Current position in the decrypted data
+    position: u64,
+    /// The last chunk decrypted, and its number
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+/// Unlock an encrypted image with either a passphrase or a keyfile
+pub enum Unlock {
+    Passphrase(Vec<u8>),
+    Keyfile(Vec<u8>),
+}
+
+impl<R: Read + Seek> EncryptedReader<R> {
+    pub fn new(mut inner: R, unlock: &Unlock) -> Result<EncryptedReader<R>, io::Error> {
+        // the header comes from the image, so nothing it points at is
+        // read without checking it is within the file
+        let length = inner.seek(SeekFrom::End(0))?;
+
+        // read enough to cover the fixed header, then the key pointers
+        let mut buffer = vec![0u8; HEADER_SIZE];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut buffer).map_err(|_| invalid("Truncated encrcdsa header"))?;
+        let key_count = util::read_be_u32(&mut &buffer[72..76]) as usize;
+        if key_count > MAX_KEY_POINTERS {
+            return Err(invalid(&format!("Too many encrcdsa key pointers: {}", key_count)));
+        }
+        buffer.resize(HEADER_SIZE + key_count * KEY_POINTER_SIZE, 0);
+        inner.read_exact(&mut buffer[HEADER_SIZE..]).map_err(|_| invalid("Truncated encrcdsa key pointers"))?;
+
+        let header = EncryptedHeader::from_be_bytes(&buffer)?;
+
+        if header.block_size == 0 || !header.block_size.is_multiple_of(16) {
+            return Err(invalid("Invalid encrypted chunk size"));
+        }
+
+        let key = match unlock {
+            Unlock::Keyfile(contents) => ImageKey::from_keyfile(contents, header.key_bits)?,
+            Unlock::Passphrase(passphrase) => {
+                let pointer = header.key_pointers
+                    .iter()
+                    .find(|p| p.key_type == PASSPHRASE_KEY_TYPE)
+                    .ok_or_else(|| invalid("Image has no passphrase wrapped key"))?;
+
+                let within = pointer.offset.checked_add(pointer.size).map(|end| end <= length).unwrap_or(false);
+                if !within || pointer.size > MAX_KEY_SIZE {
+                    return Err(invalid("Passphrase key pointer is outside the image"));
+                }
+
+                let mut key_header = vec![0u8; pointer.size as usize];
+                inner.seek(SeekFrom::Start(pointer.offset))?;
+                inner.read_exact(&mut key_header)?;
+
+                PasswordKey::from_be_bytes(&key_header)?.unwrap(passphrase, header.key_bits)?
+            }
+        };
+
+        Ok(EncryptedReader { inner, header, key, position: 0, chunk: None })
+    }
+
+    /// Size of the decrypted data
+    pub fn len(&self) -> u64 {
+        self.header.data_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.data_size == 0
+    }
+
+    fn load_chunk(&mut self, number: u64) -> Result<(), io::Error> {
+        if let Some((current, _)) = self.chunk {
+            if current == number {
+                return Ok(());
+            }
+        }
+
+        let block_size = u64::from(self.header.block_size);
+        let mut data = vec![0u8; block_size as usize];
+        let offset = number
+            .checked_mul(block_size)
+            .and_then(|start| start.checked_add(self.header.data_offset))
+            .ok_or_else(|| invalid("Encrypted chunk offset overflows"))?;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(&mut data)?;
+        self.key.decrypt_chunk(number as u32, &mut data)?;
+
+        self.chunk = Some((number, data));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for EncryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.header.data_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = u64::from(self.header.block_size);
+        let number = self.position / block_size;
+        self.load_chunk(number)?;
+
+        let relative = (self.position % block_size) as usize;
+        let available = std::cmp::min(block_size - relative as u64, self.header.data_size - self.position) as usize;
+        let count = std::cmp::min(available, buf.len());
+
+        let (_, data) = self.chunk.as_ref().expect("Chunk was just loaded");
+        buf[..count].copy_from_slice(&data[relative..relative + count]);
+        self.position += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl<R: Read + Seek> Seek for EncryptedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.header.data_size as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of decrypted data"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+/// Check whether a file starts with the encrcdsa magic
+pub fn is_encrypted<R: Read + Seek>(reader: &mut R) -> Result<bool, io::Error> {
+    let mut magic = [0u8; 8];
+    reader.seek(SeekFrom::Start(0))?;
+    let read = reader.read(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(read == 8 && &magic == ENCRCDSA_MAGIC)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn image_key() -> ImageKey {
+        let blob: Vec<u8> = (0..36).collect();
+        ImageKey::from_bytes(&blob, 128).unwrap()
+    }
+
+    fn encrypted_image(udif: &[u8]) -> Vec<u8> {
+        let encryption = Encryption { key_bits: 128, passphrase: b"secret".to_vec(), deterministic: true };
+        encrypt(udif, &encryption).unwrap()
+    }
+
+    #[test]
+    fn wrap_unwrap_round_trip() {
+        let key = image_key();
+        let wrapped = PasswordKey::wrap(&key, b"secret", vec![2u8; 20], vec![3u8; 8]).to_be_bytes();
+
+        let unwrapped = PasswordKey::from_be_bytes(&wrapped).unwrap().unwrap(b"secret", 128).unwrap();
+        assert_eq!(unwrapped.aes_key, key.aes_key);
+        assert_eq!(unwrapped.hmac_key, key.hmac_key);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let wrapped = PasswordKey::wrap(&image_key(), b"secret", vec![2u8; 20], vec![3u8; 8]).to_be_bytes();
+
+        let error = PasswordKey::from_be_bytes(&wrapped).unwrap().unwrap(b"guess", 128).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn encrypted_reader_round_trip() {
+        // not a multiple of the chunk size, so the last chunk is padded
+        let udif: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let image = encrypted_image(&udif);
+
+        let mut reader = EncryptedReader::new(Cursor::new(image), &Unlock::Passphrase(b"secret".to_vec())).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, udif);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let image = encrypted_image(&[0u8; 512]);
+
+        let error = EncryptedReader::new(Cursor::new(&image[..40]), &Unlock::Passphrase(b"secret".to_vec())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn too_many_key_pointers() {
+        let mut image = encrypted_image(&[0u8; 512]);
+        image[72..76].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let error = EncryptedReader::new(Cursor::new(image), &Unlock::Passphrase(b"secret".to_vec())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn key_pointer_past_end() {
+        let mut image = encrypted_image(&[0u8; 512]);
+        // size of the first key pointer
+        image[HEADER_SIZE + 12..HEADER_SIZE + 20].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+
+        let error = EncryptedReader::new(Cursor::new(image), &Unlock::Passphrase(b"secret".to_vec())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn too_many_iterations() {
+        let mut wrapped = PasswordKey::wrap(&image_key(), b"secret", vec![2u8; 20], vec![3u8; 8]);
+        wrapped.kdf_iteration_count = u32::MAX;
+
+        let error = wrapped.unwrap(b"secret", 128).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn data_offset_overflows() {
+        let mut image = encrypted_image(&[0u8; 10_000]);
+        image[64..72].copy_from_slice(&(u64::MAX - 100).to_be_bytes());
+
+        let mut reader = EncryptedReader::new(Cursor::new(image), &Unlock::Passphrase(b"secret".to_vec())).unwrap();
+        reader.seek(SeekFrom::Start(5000)).unwrap();
+        let error = reader.read(&mut [0u8; 16]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
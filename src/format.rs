@@ -0,0 +1,318 @@
+use std::io::{self, prelude::Write};
+use std::path::Path;
+
+use super::blkx::{BlkxChunkEntry, DmgBlxx};
+use super::builder::{Compression, DmgSummary, DEFAULT_CHUNK_SIZE};
+use super::dmg::{self, Dmg};
+use super::partition::PartitionEntry;
+use super::segment::SegmentedImage;
+use super::util::{self, UDIFChecksum};
+use super::xml::PList;
+
+/// UDIF image formats, named as hdiutil convert -format names them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// zlib compressed
+    Udzo,
+    /// Uncompressed, read-only
+    Udro,
+    /// bzip2 compressed
+    Udbz,
+    /// LZFSE compressed
+    Ulfo,
+    /// Uncompressed, with every sector stored so it can be written in place
+    Udrw,
+}
+
+impl Format {
+    /// How this format stores chunks
+    pub fn compression(self) -> Compression {
+        match self {
+            Format::Udzo => Compression::Zlib,
+            Format::Udro | Format::Udrw => Compression::Raw,
+            Format::Udbz => Compression::Bzip2,
+            Format::Ulfo => Compression::Lzfse,
+        }
+    }
+
+    /// The koly image variant. hdiutil writes 1 for read-write images,
+    /// the read-only formats keep the 2 that libdmg-hfsplus writes.
+    pub fn image_variant(self) -> u32 {
+        match self {
+            Format::Udrw => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// Parse a format name such as "UDZO", case insensitively
+pub fn parse_format(format: &str) -> Result<Format, String> {
+    match format.to_uppercase().as_str() {
+        "UDZO" => Ok(Format::Udzo),
+        "UDRO" => Ok(Format::Udro),
+        "UDBZ" => Ok(Format::Udbz),
+        "ULFO" => Ok(Format::Ulfo),
+        "UDRW" => Ok(Format::Udrw),
+        _ => Err(format!("Unsupported format {:?}, expected UDZO, UDRO, UDBZ, ULFO or UDRW", format)),
+    }
+}
+
+/// Re-encode the chunks of an existing image in another format, like
+/// hdiutil convert -format. Partitions, chunk boundaries and the other
+/// resources (e.g. a license agreement) are kept as they are. The output
+/// only replaces what was at its path once it is completely written, and
+/// can't be the input or one of its segments.
+pub fn convert_format(input: &Path, output: &Path, format: Format) -> Result<DmgSummary, io::Error> {
+    let mut dmg = Dmg::open(input)?;
+
+    if let Ok(output) = output.canonicalize() {
+        for segment in &dmg.get_ref().segments {
+            if segment.path.canonicalize()? == output {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} would overwrite the input", output.display())));
+            }
+        }
+    }
+
+    util::write_atomically(output, |out| write_format(&mut dmg, format, out))
+}
+
+fn write_format<W: Write>(dmg: &mut Dmg<SegmentedImage>, format: Format, out: &mut W) -> Result<DmgSummary, io::Error> {
+    let compression = format.compression();
+
+    let mut partitions = Vec::new();
+    let mut offset = 0;
+    let mut chunk_count = 0;
+
+    for partition in dmg.plist().partitions.clone() {
+        let mut mish = partition.data.clone();
+        let mut entries = Vec::new();
+
+        for entry in &partition.data.block_entries {
+            match entry.entry_type {
+                DmgBlxx::LastEntry => entries.push(BlkxChunkEntry { compressed_offset: offset, ..entry.clone() }),
+                _ if dmg::has_data(&entry.entry_type) => {
+                    let chunk = dmg.read_chunk(&partition.data, entry)?;
+                    let (entry_type, stored) = compression.compress(&chunk)?;
+                    out.write_all(&stored)?;
+
+                    entries.push(BlkxChunkEntry {
+                        entry_type,
+                        compressed_offset: offset,
+                        compressed_length: stored.len() as u64,
+                        ..entry.clone()
+                    });
+                    offset += stored.len() as u64;
+                    chunk_count += 1;
+                }
+                // UDRW images store zeros too, split into chunks of the usual size
+                DmgBlxx::ZeroFill | DmgBlxx::IgnoredOrUnknown if format == Format::Udrw => {
+                    let mut sector = entry.sector_number;
+                    while sector < entry.sector_number + entry.sector_count {
+                        let sector_count = std::cmp::min(DEFAULT_CHUNK_SIZE, entry.sector_number + entry.sector_count - sector);
+                        out.write_all(&vec![0u8; (sector_count * 512) as usize])?;
+
+                        entries.push(BlkxChunkEntry {
+                            entry_type: DmgBlxx::RawOrNullCompression,
+                            comment: 0,
+                            sector_number: sector,
+                            sector_count,
+                            compressed_offset: offset,
+                            compressed_length: sector_count * 512,
+                        });
+                        sector += sector_count;
+                        offset += sector_count * 512;
+                        chunk_count += 1;
+                    }
+                }
+                _ => entries.push(BlkxChunkEntry { compressed_offset: offset, compressed_length: 0, ..entry.clone() }),
+            }
+        }
+
+        // chunk offsets are now from the start of the data fork
+        mish.data_offset = 0;
+        mish.number_block_chunks = entries.len() as u32;
+        mish.block_entries = entries;
+        partitions.push(PartitionEntry { data: mish, ..partition });
+    }
+
+    let plist = PList { partitions, resources: dmg.plist().resources.clone() };
+    let xml = plist.to_xml();
+    out.write_all(&xml)?;
+
+    // keep the classic resource fork if the original had one
+    let resource_fork = match dmg.koly().source_fork_length {
+        0 => Vec::new(),
+        _ => plist.to_resource_fork().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+    };
+    out.write_all(&resource_fork)?;
+
+    let mut koly = dmg.koly().clone();
+    koly.running_data_fork_offset = 0;
+    koly.data_fork_offset = 0;
+    koly.data_fork_length = offset;
+    koly.xml_offset = offset;
+    koly.xml_length = xml.len() as u64;
+    koly.source_fork_offset = if resource_fork.is_empty() { 0 } else { offset + xml.len() as u64 };
+    koly.source_fork_length = resource_fork.len() as u64;
+    koly.image_variant = format.image_variant();
+    // the output is a single file, even if the input was segmented
+    if koly.segment_count > 1 {
+        koly.segment_number = 1;
+        koly.segment_count = 1;
+    }
+    // the data fork changed, the chunk checksums in the master checksum did not
+    koly.data_fork_checksum = UDIFChecksum::none();
+    let sector_count = koly.sector_count;
+    out.write_all(&koly.to_be_bytes())?;
+    out.flush()?;
+
+    let length = offset + xml.len() as u64 + resource_fork.len() as u64 + 512;
+    Ok(DmgSummary {
+        sector_count,
+        chunk_count,
+        data_fork_length: offset,
+        xml_length: xml.len() as u64,
+        resource_fork_length: resource_fork.len() as u64,
+        length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DmgBuilder;
+    use crate::convert::build_koly;
+    use crate::resource::ResourceEntry;
+    use crate::sla::{License, LicenseText, LANGUAGES};
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("libdmg-format-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Some runs of zeros, some text, and some noise
+    fn disk() -> Vec<u8> {
+        let mut state: u32 = 7;
+        let mut disk = vec![0u8; 64 * 512];
+        disk.extend(b"libdmg ".iter().cycle().take(100 * 512));
+        disk.extend((0..100 * 512).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }));
+        disk
+    }
+
+    /// A UDZO image with an English and an RTF German license, and a plst resource
+    fn image(path: &Path) {
+        let licenses = [
+            License { language: &LANGUAGES[0], text: LicenseText::Text("Terms".to_string()) },
+            License { language: &LANGUAGES[2], text: LicenseText::Rtf(b"{\\rtf1 Bedingungen}".to_vec()) },
+        ];
+        let disk = disk();
+        let parts = DmgBuilder::new().partition("disk image", &disk[..]).chunk_size(64).licenses(&licenses).build().unwrap();
+
+        let mut plist = PList::from_bytes(&parts.xml).unwrap();
+        plist.resources.insert("plst".to_string(), vec![ResourceEntry {
+            attributes: "0x0050".to_string(),
+            data: vec![0xAB; 20],
+            id: 0,
+            name: String::new(),
+        }]);
+        let xml = plist.to_xml();
+
+        let koly = build_koly(xml.len() as u64, parts.data_fork.len() as u64, parts.summary.sector_count);
+        let mut image = parts.data_fork;
+        image.extend_from_slice(&xml);
+        image.extend_from_slice(&koly.to_be_bytes());
+        std::fs::write(path, image).unwrap();
+    }
+
+    /// Every resource but blkx, in a form that can be compared
+    fn resources(dmg: &Dmg<SegmentedImage>) -> Vec<(String, i32, String, String, Vec<u8>)> {
+        dmg.plist()
+            .resources
+            .iter()
+            .flat_map(|(kind, entries)| entries.iter().map(move |e| (kind.clone(), e.id, e.name.clone(), e.attributes.clone(), e.data.clone())))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = scratch_dir("round-trip");
+        let first = dir.join("0.dmg");
+        image(&first);
+        let original = Dmg::open(&first).unwrap();
+        let expected = resources(&original);
+        assert!(expected.iter().any(|(kind, ..)| kind == "plst"));
+        assert!(expected.iter().any(|(kind, ..)| kind == "RTF "));
+
+        let formats = [
+            (Format::Udro, DmgBlxx::RawOrNullCompression),
+            (Format::Udbz, DmgBlxx::Bz2Compression),
+            (Format::Ulfo, DmgBlxx::LzfseCompression),
+            (Format::Udrw, DmgBlxx::RawOrNullCompression),
+            (Format::Udzo, DmgBlxx::ZLibCompression),
+        ];
+        let mut input = first;
+        for (i, (format, entry_type)) in formats.iter().enumerate() {
+            let output = dir.join(format!("{}.dmg", i + 1));
+            convert_format(&input, &output, *format).unwrap();
+
+            let mut dmg = Dmg::open(&output).unwrap();
+            let mut contents = Vec::new();
+            dmg.open_partition(0).unwrap().read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, disk(), "{:?}", format);
+            assert_eq!(resources(&dmg), expected, "{:?}", format);
+            assert_eq!(dmg.koly().image_variant, format.image_variant());
+
+            let entries = &dmg.plist().partitions[0].data.block_entries;
+            assert!(entries.iter().all(|e| !dmg::has_data(&e.entry_type) || std::mem::discriminant(&e.entry_type) == std::mem::discriminant(entry_type)));
+            // UDRW stores every sector, the others leave zeros out
+            let zeros = entries.iter().any(|e| matches!(e.entry_type, DmgBlxx::ZeroFill | DmgBlxx::IgnoredOrUnknown));
+            assert!(!zeros || *format != Format::Udrw);
+            input = output;
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_overwrite_the_input() {
+        let dir = scratch_dir("overwrite");
+        let input = dir.join("image.dmg");
+        image(&input);
+        let original = std::fs::read(&input).unwrap();
+
+        // the same file, by another path
+        let error = convert_format(&input, &dir.join(".").join("image.dmg"), Format::Udro).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(std::fs::read(&input).unwrap(), original);
+        assert!(!dir.join("image.dmg.partial").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_conversion_keeps_output() {
+        let dir = scratch_dir("failed");
+        let input = dir.join("image.dmg");
+        image(&input);
+        let output = dir.join("output.dmg");
+        std::fs::write(&output, b"existing").unwrap();
+
+        // a chunk that can't be decompressed
+        let mut broken = std::fs::read(&input).unwrap();
+        broken[100..200].iter_mut().for_each(|b| *b = 0xFF);
+        std::fs::write(&input, broken).unwrap();
+
+        assert!(convert_format(&input, &output, Format::Udro).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"existing");
+        assert!(!dir.join("output.dmg.partial").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,767 @@
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::io::{prelude::{Read, Seek, Write}, SeekFrom};
+
+use super::util;
+
+/// "H+" - HFS+ volume signature
+pub const HFS_PLUS_SIGNATURE: u16 = 0x482B;
+/// "HX" - case sensitive HFS+ (HFSX) volume signature
+pub const HFSX_SIGNATURE: u16 = 0x4858;
+
+/// The volume header is always 1024 bytes into the volume
+pub const VOLUME_HEADER_OFFSET: u64 = 1024;
+
+/// CNID of the root folder
+pub const ROOT_FOLDER_ID: u32 = 2;
+
+/// Finder flags, in FileInfo / FolderInfo
+pub const K_HAS_CUSTOM_ICON: u16 = 0x0400;
+pub const K_IS_INVISIBLE: u16 = 0x4000;
+
+/// Name of the volume icon in the root folder
+pub const VOLUME_ICON_NAME: &str = ".VolumeIcon.icns";
+
+/// Seconds between 1904-01-01 (HFS+) and 1970-01-01 (Unix)
+pub const HFS_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+/// Convert a Unix timestamp into an HFS+ date
+pub fn hfs_date(unix: u64) -> Result<u32, String> {
+    u32::try_from(unix + HFS_EPOCH_OFFSET).map_err(|_| format!("Timestamp {} is too late for HFS+", unix))
+}
+
+/// Start of a file's data (or resource) fork, 8 extents are stored inline.
+/// All fields are in big endian ordering.
+#[derive(Debug, Clone)]
+pub struct ForkData {
+    /// Size of the fork in bytes
+    pub logical_size: u64,
+    pub clump_size: u32,
+    /// Allocation blocks used by the fork
+    pub total_blocks: u32,
+    /// (start block, block count) pairs
+    pub extents: Vec<(u32, u32)>,
+}
+
+/// Represents the HFS+ volume header, found 1024 bytes into the volume
+#[derive(Debug)]
+pub struct VolumeHeader {
+    /// "H+" or "HX"
+    pub signature: u16,
+    /// 4 for HFS+, 5 for HFSX
+    pub version: u16,
+    pub attributes: u32,
+    pub last_mounted_version: u32,
+    pub journal_info_block: u32,
+
+    /// Dates are seconds since 1904. create_date is in local time,
+    /// the others are UTC
+    pub create_date: u32,
+    pub modify_date: u32,
+    pub backup_date: u32,
+    pub checked_date: u32,
+
+    pub file_count: u32,
+    pub folder_count: u32,
+
+    /// Allocation block size in bytes
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub free_blocks: u32,
+
+    pub next_allocation: u32,
+    pub rsrc_clump_size: u32,
+    pub data_clump_size: u32,
+    pub next_catalog_id: u32,
+
+    pub write_count: u32,
+    pub encodings_bitmap: u64,
+
+    /// Used by Finder, e.g. the blessed folder
+    pub finder_info: Vec<u8>,
+
+    pub allocation_file: ForkData,
+    pub extents_file: ForkData,
+    pub catalog_file: ForkData,
+    pub attributes_file: ForkData,
+    pub startup_file: ForkData,
+}
+
+/// Whether a catalog record describes a folder or a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatalogKind {
+    /// kHFSPlusFolderRecord - 0x0001
+    Folder,
+    /// kHFSPlusFileRecord - 0x0002
+    File,
+}
+
+/// A folder or file record from the catalog B-tree
+#[derive(Debug, Clone)]
+pub struct CatalogRecord {
+    /// CNID of the containing folder
+    pub parent_id: u32,
+    /// Name of the file or folder
+    pub name: String,
+    pub kind: CatalogKind,
+    /// CNID of this file or folder
+    pub id: u32,
+    /// Seconds since 1904
+    pub create_date: u32,
+    pub content_mod_date: u32,
+    /// Type and creator codes, zero for folders
+    pub file_type: [u8; 4],
+    pub creator: [u8; 4],
+    /// Finder flags, from FileInfo / FolderInfo
+    pub finder_flags: u16,
+    /// BSD file mode, type and permission bits. 0 if never set.
+    pub mode: u16,
+    /// Only present for files
+    pub data_fork: Option<ForkData>,
+    pub resource_fork: Option<ForkData>,
+    /// Where the record data (after the key) starts, from the start of the volume
+    pub offset: u64,
+}
+
+/// An HFS+ volume, with its catalog read into memory
+#[derive(Debug)]
+pub struct HfsVolume {
+    pub header: VolumeHeader,
+    /// Every folder and file record, in catalog order
+    pub records: Vec<CatalogRecord>,
+}
+
+impl ForkData {
+    pub fn from_be_bytes(buffer: &[u8]) -> ForkData {
+        ForkData {
+            logical_size: util::read_be_u64(&mut &buffer[0..8]),
+            clump_size: util::read_be_u32(&mut &buffer[8..12]),
+            total_blocks: util::read_be_u32(&mut &buffer[12..16]),
+            extents: buffer[16..80]
+                .chunks_exact(8)
+                .map(|e| (util::read_be_u32(&mut &e[0..4]), util::read_be_u32(&mut &e[4..8])))
+                .collect(),
+        }
+    }
+
+    /// Byte ranges (offset, length) on the volume allocated to this fork
+    pub fn allocated(&self, block_size: u32) -> Result<Vec<(u64, u64)>, String> {
+        let block_size = u64::from(block_size);
+        let ranges: Vec<(u64, u64)> = self.extents
+            .iter()
+            .take_while(|(_, count)| *count != 0)
+            .map(|(start, count)| (u64::from(*start) * block_size, u64::from(*count) * block_size))
+            .collect();
+
+        // the rest would be in the extents overflow file
+        let blocks: u64 = ranges.iter().map(|(_, length)| length / block_size).sum();
+        if blocks < u64::from(self.total_blocks) {
+            return Err("Fork has more than 8 extents, which is not supported".to_string());
+        }
+
+        Ok(ranges)
+    }
+
+    /// Byte ranges (offset, length) on the volume holding this fork's data
+    pub fn ranges(&self, block_size: u32) -> Result<Vec<(u64, u64)>, String> {
+        let mut remaining = self.logical_size;
+        let mut ranges = Vec::new();
+
+        for (start, length) in self.allocated(block_size)? {
+            if remaining == 0 {
+                break;
+            }
+            let length = std::cmp::min(remaining, length);
+            ranges.push((start, length));
+            remaining -= length;
+        }
+
+        if remaining != 0 {
+            return Err("Fork is larger than its allocated blocks".to_string());
+        }
+
+        Ok(ranges)
+    }
+}
+
+impl VolumeHeader {
+    pub fn from_be_bytes(buffer: &[u8]) -> Result<VolumeHeader, String> {
+        if buffer.len() < 512 {
+            return Err("Truncated HFS+ volume header".to_string());
+        }
+
+        let signature = util::read_be_u16(&mut &buffer[0..2]);
+        if signature != HFS_PLUS_SIGNATURE && signature != HFSX_SIGNATURE {
+            return Err("Not an HFS+ volume".to_string());
+        }
+
+        Ok(VolumeHeader {
+            signature,
+            version: util::read_be_u16(&mut &buffer[2..4]),
+            attributes: util::read_be_u32(&mut &buffer[4..8]),
+            last_mounted_version: util::read_be_u32(&mut &buffer[8..12]),
+            journal_info_block: util::read_be_u32(&mut &buffer[12..16]),
+
+            create_date: util::read_be_u32(&mut &buffer[16..20]),
+            modify_date: util::read_be_u32(&mut &buffer[20..24]),
+            backup_date: util::read_be_u32(&mut &buffer[24..28]),
+            checked_date: util::read_be_u32(&mut &buffer[28..32]),
+
+            file_count: util::read_be_u32(&mut &buffer[32..36]),
+            folder_count: util::read_be_u32(&mut &buffer[36..40]),
+
+            block_size: util::read_be_u32(&mut &buffer[40..44]),
+            total_blocks: util::read_be_u32(&mut &buffer[44..48]),
+            free_blocks: util::read_be_u32(&mut &buffer[48..52]),
+
+            next_allocation: util::read_be_u32(&mut &buffer[52..56]),
+            rsrc_clump_size: util::read_be_u32(&mut &buffer[56..60]),
+            data_clump_size: util::read_be_u32(&mut &buffer[60..64]),
+            next_catalog_id: util::read_be_u32(&mut &buffer[64..68]),
+
+            write_count: util::read_be_u32(&mut &buffer[68..72]),
+            encodings_bitmap: util::read_be_u64(&mut &buffer[72..80]),
+
+            finder_info: buffer[80..112].to_vec(),
+
+            allocation_file: ForkData::from_be_bytes(&buffer[112..192]),
+            extents_file: ForkData::from_be_bytes(&buffer[192..272]),
+            catalog_file: ForkData::from_be_bytes(&buffer[272..352]),
+            attributes_file: ForkData::from_be_bytes(&buffer[352..432]),
+            startup_file: ForkData::from_be_bytes(&buffer[432..512]),
+        })
+    }
+}
+
+impl HfsVolume {
+    /// Read the volume header and every record in the catalog
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<HfsVolume, String> {
+        let mut buffer = vec![0u8; 512];
+        reader.seek(SeekFrom::Start(VOLUME_HEADER_OFFSET)).map_err(|e| e.to_string())?;
+        reader.read_exact(&mut buffer).map_err(|e| e.to_string())?;
+        let header = VolumeHeader::from_be_bytes(&buffer)?;
+
+        // read the whole catalog file, remembering where each piece came from
+        let ranges = header.catalog_file.ranges(header.block_size)?;
+        let catalog = read_ranges(reader, &ranges)?;
+
+        let records = read_catalog(&catalog, &ranges)?;
+
+        Ok(HfsVolume { header, records })
+    }
+
+    /// Name of the volume, which is the name of the root folder
+    pub fn name(&self) -> String {
+        self.records
+            .iter()
+            .find(|r| r.id == ROOT_FOLDER_ID && r.kind == CatalogKind::Folder)
+            .map(|r| r.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Find a file or folder by its path from the root of the volume,
+    /// e.g. ".background/background.tiff". HFS+ names are compared
+    /// case insensitively, HFSX ones exactly.
+    pub fn lookup(&self, path: &str) -> Option<&CatalogRecord> {
+        let mut current = self.records.iter().find(|r| r.id == ROOT_FOLDER_ID)?;
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = self.children(current.id).find(|r| self.names_match(&r.name, component))?;
+        }
+
+        Some(current)
+    }
+
+    /// Files and folders directly inside a folder
+    pub fn children(&self, parent_id: u32) -> impl Iterator<Item = &CatalogRecord> {
+        self.records.iter().filter(move |r| r.parent_id == parent_id)
+    }
+
+    /// CNIDs of the folders between the root and a record, nearest first.
+    /// The root folder itself is not included.
+    pub fn ancestors(&self, record: &CatalogRecord) -> Vec<u32> {
+        let mut ancestors = Vec::new();
+        let mut parent_id = record.parent_id;
+
+        while parent_id != ROOT_FOLDER_ID && ancestors.len() < self.records.len() {
+            match self.records.iter().find(|r| r.id == parent_id && r.kind == CatalogKind::Folder) {
+                Some(parent) => {
+                    ancestors.push(parent.id);
+                    parent_id = parent.parent_id;
+                }
+                None => break,
+            }
+        }
+
+        ancestors
+    }
+
+    /// Read the data fork of a file
+    pub fn read_file<R: Read + Seek>(&self, reader: &mut R, record: &CatalogRecord) -> Result<Vec<u8>, String> {
+        let fork = record.data_fork.as_ref().ok_or_else(|| format!("{} is not a file", record.name))?;
+
+        let mut data = Vec::new();
+        for (offset, length) in fork.ranges(self.header.block_size)? {
+            let start = data.len();
+            data.resize(start + length as usize, 0);
+            reader.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            reader.read_exact(&mut data[start..]).map_err(|e| e.to_string())?;
+        }
+
+        Ok(data)
+    }
+
+    /// Replace the data fork of a file in place. The new contents have
+    /// to fit in the blocks already allocated to the file, as we can't
+    /// allocate more. The rest of the last block is zeroed and the
+    /// logical size in the catalog record is updated.
+    pub fn overwrite_file<W: Write + Seek>(&self, writer: &mut W, record: &CatalogRecord, data: &[u8]) -> Result<(), String> {
+        let fork = record.data_fork.as_ref().ok_or_else(|| format!("{} is not a file", record.name))?;
+        let allocated = fork.allocated(self.header.block_size)?;
+
+        let capacity: u64 = allocated.iter().map(|(_, length)| length).sum();
+        if data.len() as u64 > capacity {
+            return Err(format!(
+                "{} bytes do not fit in the {} bytes allocated to {}",
+                data.len(), capacity, record.name
+            ));
+        }
+
+        let mut remaining = data;
+        for (offset, length) in allocated {
+            let (chunk, rest) = remaining.split_at(std::cmp::min(remaining.len(), length as usize));
+            let mut block = chunk.to_vec();
+            block.resize(length as usize, 0);
+
+            writer.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            writer.write_all(&block).map_err(|e| e.to_string())?;
+            remaining = rest;
+        }
+
+        // logicalSize is the first field of the data fork
+        writer.seek(SeekFrom::Start(record.offset + 88)).map_err(|e| e.to_string())?;
+        writer.write_all(&(data.len() as u64).to_be_bytes()).map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    /// Set Finder flags on a file or folder in place, e.g. K_IS_INVISIBLE
+    pub fn set_finder_flags<W: Write + Seek>(&self, writer: &mut W, record: &CatalogRecord, flags: u16) -> Result<(), String> {
+        // finderFlags follows fileType and fileCreator (or windowBounds)
+        writer.seek(SeekFrom::Start(record.offset + 56)).map_err(|e| e.to_string())?;
+        writer.write_all(&(record.finder_flags | flags).to_be_bytes()).map_err(|e| e.to_string())
+    }
+
+    /// Set every date on the volume to date (seconds since 1904): the
+    /// volume header, its alternate copy, and each file and folder record
+    pub fn set_dates<W: Write + Seek>(&self, writer: &mut W, date: u32) -> Result<(), String> {
+        let end = writer.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        let volume_size = u64::from(self.header.total_blocks) * u64::from(self.header.block_size);
+
+        // create, modify, backup and checked dates
+        let header_dates = date.to_be_bytes().repeat(4);
+        let mut headers = vec![VOLUME_HEADER_OFFSET];
+        if volume_size <= end && volume_size >= 2 * VOLUME_HEADER_OFFSET {
+            headers.push(volume_size - VOLUME_HEADER_OFFSET);
+        }
+        for offset in headers {
+            writer.seek(SeekFrom::Start(offset + 16)).map_err(|e| e.to_string())?;
+            writer.write_all(&header_dates).map_err(|e| e.to_string())?;
+        }
+
+        // create, content mod, attribute mod, access and backup dates
+        let record_dates = date.to_be_bytes().repeat(5);
+        for record in &self.records {
+            writer.seek(SeekFrom::Start(record.offset + 12)).map_err(|e| e.to_string())?;
+            writer.write_all(&record_dates).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// Zero the allocation blocks that aren't in use, and the space after
+    /// the end of each fork in its last block, which may hold leftovers
+    /// from whatever created the volume
+    pub fn zero_unused<F: Read + Write + Seek>(&self, file: &mut F) -> Result<(), String> {
+        let end = file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        let block_size = u64::from(self.header.block_size);
+
+        // one bit per allocation block, most significant bit first
+        let mut bitmap = Vec::new();
+        for (offset, length) in self.header.allocation_file.ranges(self.header.block_size)? {
+            let start = bitmap.len();
+            bitmap.resize(start + length as usize, 0);
+            file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            file.read_exact(&mut bitmap[start..]).map_err(|e| e.to_string())?;
+        }
+
+        let used = |block: u64| bitmap.get((block / 8) as usize).is_none_or(|b| b & (0x80 >> (block % 8)) != 0);
+        let zeros = vec![0u8; block_size as usize];
+
+        for block in 0..u64::from(self.header.total_blocks) {
+            let offset = block * block_size;
+            // never touch the volume headers, even if the bitmap is wrong
+            let header = offset < VOLUME_HEADER_OFFSET + 512 || offset + block_size > end.saturating_sub(VOLUME_HEADER_OFFSET);
+            if used(block) || header {
+                continue;
+            }
+            file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            file.write_all(&zeros).map_err(|e| e.to_string())?;
+        }
+
+        // the rest of the blocks holding the volume headers is unused too,
+        // apart from the boot blocks
+        let volume_size = u64::from(self.header.total_blocks) * block_size;
+        let header_end = VOLUME_HEADER_OFFSET + 512;
+        let mut gaps = vec![(header_end, header_end.div_ceil(block_size) * block_size)];
+        if volume_size <= end && volume_size >= 2 * header_end {
+            let alternate = volume_size - VOLUME_HEADER_OFFSET;
+            gaps.push((alternate / block_size * block_size, alternate));
+            gaps.push((alternate + 512, volume_size));
+        }
+        for (start, stop) in gaps.into_iter().filter(|(start, stop)| start < stop) {
+            file.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+            file.write_all(&vec![0u8; (stop - start) as usize]).map_err(|e| e.to_string())?;
+        }
+
+        let forks = self.records.iter().flat_map(|r| r.data_fork.iter().chain(r.resource_fork.iter()));
+        for fork in forks {
+            let mut remaining = fork.logical_size;
+            for (offset, length) in fork.allocated(self.header.block_size)? {
+                if remaining < length {
+                    file.seek(SeekFrom::Start(offset + remaining)).map_err(|e| e.to_string())?;
+                    file.write_all(&vec![0u8; (length - remaining) as usize]).map_err(|e| e.to_string())?;
+                }
+                remaining = remaining.saturating_sub(length);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put the records of each catalog leaf node into key order, so that
+    /// directory entries are listed canonically whatever wrote the volume.
+    /// Returns whether anything moved, as the record offsets of this
+    /// HfsVolume are then out of date. Nodes can't be split or merged
+    /// here, so keys out of order between nodes are an error.
+    pub fn order_catalog<F: Read + Write + Seek>(&self, file: &mut F) -> Result<bool, String> {
+        let ranges = self.header.catalog_file.ranges(self.header.block_size)?;
+        let mut catalog = read_ranges(file, &ranges)?;
+        let node_size = catalog_node_size(&catalog)?;
+        let case_sensitive = self.header.signature == HFSX_SIGNATURE;
+
+        let mut changed = false;
+        let mut previous: Option<(u32, Vec<u16>)> = None;
+
+        for start in leaf_nodes(&catalog)? {
+            let number = start / node_size;
+            let node = &catalog[start..start + node_size];
+            let offsets = record_offsets(node)?;
+
+            let mut records = Vec::new();
+            for offset in &offsets {
+                let end = offset + record_length(node, *offset)?;
+                if end > node_size - 2 * (offsets.len() + 1) {
+                    return Err(format!("Catalog node {} has a record outside of it", number));
+                }
+                records.push((record_key(node, *offset)?, &node[*offset..end]));
+            }
+
+            let mut order: Vec<usize> = (0..records.len()).collect();
+            order.sort_by(|a, b| compare_keys(case_sensitive, &records[*a].0, &records[*b].0));
+
+            if let (Some(previous), Some(first)) = (&previous, order.first()) {
+                if compare_keys(case_sensitive, previous, &records[*first].0) == Ordering::Greater {
+                    return Err(format!("Catalog node {} is out of order with the node before it", number));
+                }
+            }
+            previous = order.last().map(|last| records[*last].0.clone());
+
+            if order.iter().enumerate().all(|(index, sorted)| index == *sorted) {
+                continue;
+            }
+            // the index nodes hold the first key of each leaf
+            if order[0] != 0 {
+                return Err(format!("The first record of catalog node {} is out of order", number));
+            }
+
+            let mut rebuilt = node[..14].to_vec();
+            let mut new_offsets = Vec::new();
+            for index in &order {
+                new_offsets.push(rebuilt.len() as u16);
+                rebuilt.extend_from_slice(records[*index].1);
+            }
+            new_offsets.push(rebuilt.len() as u16);
+            rebuilt.resize(node_size, 0);
+            for (index, offset) in new_offsets.iter().enumerate() {
+                let position = node_size - 2 * (index + 1);
+                rebuilt[position..position + 2].copy_from_slice(&offset.to_be_bytes());
+            }
+
+            catalog[start..start + node_size].copy_from_slice(&rebuilt);
+            changed = true;
+        }
+
+        if changed {
+            let mut remaining = &catalog[..];
+            for (offset, length) in &ranges {
+                let (piece, rest) = remaining.split_at(*length as usize);
+                file.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+                file.write_all(piece).map_err(|e| e.to_string())?;
+                remaining = rest;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    fn names_match(&self, a: &str, b: &str) -> bool {
+        if self.header.signature == HFSX_SIGNATURE {
+            a == b
+        } else {
+            a.to_lowercase() == b.to_lowercase()
+        }
+    }
+}
+
+/// Walk the leaf nodes of the catalog B-tree, collecting folder and
+/// file records. Thread records are skipped.
+fn read_catalog(catalog: &[u8], ranges: &[(u64, u64)]) -> Result<Vec<CatalogRecord>, String> {
+    let node_size = catalog_node_size(catalog)?;
+    let mut records = Vec::new();
+
+    for start in leaf_nodes(catalog)? {
+        let node = &catalog[start..start + node_size];
+        for offset in record_offsets(node)? {
+            if let Some(record) = read_record(node, offset, start, ranges)? {
+                records.push(record);
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+fn catalog_node_size(catalog: &[u8]) -> Result<usize, String> {
+    // node 0 is the header node
+    if catalog.len() < 14 + 106 {
+        return Err("Truncated catalog header node".to_string());
+    }
+    let node_size = util::read_be_u16(&mut &catalog[14 + 18..14 + 20]) as usize;
+
+    if node_size < 512 {
+        return Err("Invalid catalog node size".to_string());
+    }
+    Ok(node_size)
+}
+
+/// Where each leaf node starts in the catalog file, following the
+/// forward links from the first leaf
+fn leaf_nodes(catalog: &[u8]) -> Result<Vec<usize>, String> {
+    let node_size = catalog_node_size(catalog)?;
+    let header = &catalog[14..];
+    let first_leaf = util::read_be_u32(&mut &header[10..14]);
+    let total_nodes = util::read_be_u32(&mut &header[22..26]);
+
+    let mut leaves = Vec::new();
+    let mut number = first_leaf;
+
+    while number != 0 {
+        if leaves.len() as u32 >= total_nodes {
+            return Err("Catalog leaf nodes form a loop".to_string());
+        }
+
+        let start = number as usize * node_size;
+        let node = catalog
+            .get(start..start + node_size)
+            .ok_or_else(|| format!("Catalog node {} is out of bounds", number))?;
+
+        // descriptor - forward link, backward link, kind, height, record count
+        if node[8] as i8 != -1 {
+            return Err(format!("Catalog node {} is not a leaf node", number));
+        }
+
+        leaves.push(start);
+        number = util::read_be_u32(&mut &node[0..4]);
+    }
+
+    Ok(leaves)
+}
+
+/// Offsets of the records in a node, in index order. They are stored
+/// backwards from the end of the node, followed by the start of the free space.
+fn record_offsets(node: &[u8]) -> Result<Vec<usize>, String> {
+    let count = util::read_be_u16(&mut &node[10..12]) as usize;
+    if 14 + 2 * (count + 1) > node.len() {
+        return Err("Catalog node has too many records".to_string());
+    }
+
+    Ok((0..count)
+        .map(|index| {
+            let position = node.len() - 2 * (index + 1);
+            util::read_be_u16(&mut &node[position..position + 2]) as usize
+        })
+        .collect())
+}
+
+/// The parent CNID and name of the catalog key at offset
+fn record_key(node: &[u8], offset: usize) -> Result<(u32, Vec<u16>), String> {
+    let truncated = || "Truncated catalog record".to_string();
+
+    let key = node.get(offset..offset + 8).ok_or_else(truncated)?;
+    let parent_id = util::read_be_u32(&mut &key[2..6]);
+    let name_length = util::read_be_u16(&mut &key[6..8]) as usize;
+    let name = node.get(offset + 8..offset + 8 + name_length * 2).ok_or_else(truncated)?;
+
+    Ok((parent_id, name.chunks_exact(2).map(|u| u16::from_be_bytes([u[0], u[1]])).collect()))
+}
+
+/// Length of the catalog record at offset, key included
+fn record_length(node: &[u8], offset: usize) -> Result<usize, String> {
+    let truncated = || "Truncated catalog record".to_string();
+    let read_u16 = |at: usize| node.get(at..at + 2).map(|b| util::read_be_u16(&mut &b[..])).ok_or_else(truncated);
+
+    let key_length = read_u16(offset)? as usize;
+    let data_offset = offset + 2 + key_length;
+    let data_length = match read_u16(data_offset)? {
+        1 => 88,
+        2 => 248,
+        // folder and file threads - type, reserved, parent CNID, then the name
+        3 | 4 => 10 + 2 * read_u16(data_offset + 8)? as usize,
+        kind => return Err(format!("Unknown catalog record type {}", kind)),
+    };
+
+    Ok(2 + key_length + data_length)
+}
+
+/// Order catalog keys as the volume does: by parent CNID, then by name.
+/// HFSX names compare as UTF-16 code units. HFS+ names are case folded
+/// first, approximating FastUnicodeCompare: NUL sorts after everything
+/// and the ignorable formatting characters are skipped.
+pub fn compare_keys(case_sensitive: bool, a: &(u32, Vec<u16>), b: &(u32, Vec<u16>)) -> Ordering {
+    let fold = |name: &[u16]| -> Vec<u16> {
+        if case_sensitive {
+            return name.to_vec();
+        }
+        name.iter()
+            .filter_map(|unit| match unit {
+                0 => Some(0xFFFF),
+                0x200C..=0x200F | 0x202A..=0x202E | 0x206A..=0x206F | 0xFEFF => None,
+                _ => {
+                    let lower = char::from_u32(u32::from(*unit)).map(|c| c.to_lowercase().collect::<Vec<char>>());
+                    match lower.as_deref() {
+                        Some([c]) if (*c as u32) <= 0xFFFF => Some(*c as u16),
+                        _ => Some(*unit),
+                    }
+                }
+            })
+            .collect()
+    };
+
+    a.0.cmp(&b.0).then_with(|| fold(&a.1).cmp(&fold(&b.1)))
+}
+
+fn read_record(node: &[u8], offset: usize, node_start: usize, ranges: &[(u64, u64)]) -> Result<Option<CatalogRecord>, String> {
+    let truncated = || "Truncated catalog record".to_string();
+
+    // key - length, parent CNID, name as UTF-16
+    let key = node.get(offset..offset + 8).ok_or_else(truncated)?;
+    let key_length = util::read_be_u16(&mut &key[0..2]) as usize;
+    let parent_id = util::read_be_u32(&mut &key[2..6]);
+    let name_length = util::read_be_u16(&mut &key[6..8]) as usize;
+
+    let name_bytes = node.get(offset + 8..offset + 8 + name_length * 2).ok_or_else(truncated)?;
+    let units: Vec<u16> = name_bytes.chunks_exact(2).map(|u| u16::from_be_bytes([u[0], u[1]])).collect();
+    let name = String::from_utf16_lossy(&units);
+
+    let data_offset = offset + 2 + key_length;
+    let kind = match node.get(data_offset..data_offset + 2).map(|k| util::read_be_u16(&mut &k[..])) {
+        Some(1) => CatalogKind::Folder,
+        Some(2) => CatalogKind::File,
+        // thread records
+        Some(_) => return Ok(None),
+        None => return Err(truncated()),
+    };
+
+    let size = if kind == CatalogKind::Folder { 88 } else { 248 };
+    let data = node.get(data_offset..data_offset + size).ok_or_else(truncated)?;
+
+    let (data_fork, resource_fork) = match kind {
+        CatalogKind::File => (
+            Some(ForkData::from_be_bytes(&data[88..168])),
+            Some(ForkData::from_be_bytes(&data[168..248])),
+        ),
+        CatalogKind::Folder => (None, None),
+    };
+
+    Ok(Some(CatalogRecord {
+        parent_id,
+        name,
+        kind,
+        id: util::read_be_u32(&mut &data[8..12]),
+        create_date: util::read_be_u32(&mut &data[12..16]),
+        content_mod_date: util::read_be_u32(&mut &data[16..20]),
+        file_type: if kind == CatalogKind::File { data[48..52].try_into().unwrap() } else { [0u8; 4] },
+        creator: if kind == CatalogKind::File { data[52..56].try_into().unwrap() } else { [0u8; 4] },
+        finder_flags: util::read_be_u16(&mut &data[56..58]),
+        mode: util::read_be_u16(&mut &data[42..44]),
+        data_fork,
+        resource_fork,
+        offset: volume_offset(ranges, (node_start + data_offset) as u64)?,
+    }))
+}
+
+/// Read byte ranges (offset, length) of the volume into one buffer
+fn read_ranges<R: Read + Seek>(reader: &mut R, ranges: &[(u64, u64)]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    for (offset, length) in ranges {
+        let start = data.len();
+        data.resize(start + *length as usize, 0);
+        reader.seek(SeekFrom::Start(*offset)).map_err(|e| e.to_string())?;
+        reader.read_exact(&mut data[start..]).map_err(|e| e.to_string())?;
+    }
+    Ok(data)
+}
+
+/// Translate an offset within the catalog file into one within the volume
+fn volume_offset(ranges: &[(u64, u64)], mut offset: u64) -> Result<u64, String> {
+    for (start, length) in ranges {
+        if offset < *length {
+            return Ok(start + offset);
+        }
+        offset -= length;
+    }
+
+    Err("Offset is outside of the catalog file".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(parent_id: u32, name: &str) -> (u32, Vec<u16>) {
+        (parent_id, name.encode_utf16().collect())
+    }
+
+    #[test]
+    fn keys_order_by_parent_then_name() {
+        assert_eq!(compare_keys(false, &key(2, "zebra"), &key(16, "apple")), Ordering::Less);
+        // the thread record of a folder comes before its contents
+        assert_eq!(compare_keys(false, &key(2, ""), &key(2, "a")), Ordering::Less);
+    }
+
+    #[test]
+    fn hfs_plus_names_are_case_folded() {
+        assert_eq!(compare_keys(false, &key(2, "Apple"), &key(2, "banana")), Ordering::Less);
+        assert_eq!(compare_keys(false, &key(2, "README"), &key(2, "readme")), Ordering::Equal);
+        // the private metadata folder sorts after everything
+        assert_eq!(compare_keys(false, &key(2, "\0\0\0\0HFS+ Private Data"), &key(2, "zzz")), Ordering::Greater);
+        assert_eq!(compare_keys(false, &key(2, "a\u{200C}b"), &key(2, "ab")), Ordering::Equal);
+    }
+
+    #[test]
+    fn hfsx_names_are_binary() {
+        assert_eq!(compare_keys(true, &key(2, "banana"), &key(2, "Apple")), Ordering::Greater);
+        assert_eq!(compare_keys(true, &key(2, "Zebra"), &key(2, "apple")), Ordering::Less);
+    }
+}
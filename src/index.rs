@@ -0,0 +1,261 @@
+use super::builder::MAX_CHUNK_SIZE;
+use super::dmg;
+use super::partition::PartitionEntry;
+
+/// A chunk entry placed on the whole disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexedChunk {
+    /// First sector of the chunk on the disk
+    pub sector: u64,
+    pub sector_count: u64,
+    /// Index of the partition in the plist
+    pub partition: usize,
+    /// Index of the entry in the partition's block_entries
+    pub entry: usize,
+}
+
+impl IndexedChunk {
+    /// The sector after the chunk
+    pub fn end(&self) -> u64 {
+        self.sector + self.sector_count
+    }
+}
+
+/// Every chunk of every partition, sorted by where it is on the disk,
+/// to find the chunk holding a sector with a binary search
+#[derive(Debug, Clone, Default)]
+pub struct SectorIndex {
+    chunks: Vec<IndexedChunk>,
+}
+
+impl SectorIndex {
+    /// Index the chunks of partitions, checking that the chunks of each
+    /// partition cover it exactly once and that no two partitions
+    /// overlap. Sectors holding nothing must still have a chunk, e.g. a
+    /// ZeroFill one. Chunks with data have to be within the data fork of
+    /// data_fork_length bytes, and not decompress to more than
+    /// MAX_CHUNK_SIZE sectors. Every problem found is returned.
+    pub fn build(partitions: &[PartitionEntry], data_fork_length: u64) -> Result<SectorIndex, Vec<String>> {
+        let mut problems = Vec::new();
+        let mut chunks = Vec::new();
+
+        for (index, partition) in partitions.iter().enumerate() {
+            let mish = &partition.data;
+            let name = format!("partition {} (ID {})", index, partition.id);
+
+            for (i, entry) in mish.block_entries.iter().enumerate().filter(|(_, e)| dmg::has_data(&e.entry_type)) {
+                let end = mish
+                    .data_offset
+                    .checked_add(entry.compressed_offset)
+                    .and_then(|start| start.checked_add(entry.compressed_length));
+                if end.map(|end| end > data_fork_length).unwrap_or(true) {
+                    problems.push(format!("{} entry {} runs past the end of the data fork ({} bytes)", name, i, data_fork_length));
+                }
+                if entry.sector_count > MAX_CHUNK_SIZE {
+                    problems.push(format!("{} entry {} has {} sectors, more than a chunk can hold", name, i, entry.sector_count));
+                }
+            }
+
+            let mut entries: Vec<(usize, u64, u64)> = mish
+                .block_entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.sector_count > 0)
+                .map(|(i, e)| (i, e.sector_number, e.sector_count))
+                .collect();
+            entries.sort_by_key(|(_, sector, _)| *sector);
+
+            // the next sector we expect a chunk for, and the entry that ended there
+            let mut expected = 0;
+            let mut previous: Option<usize> = None;
+            for (i, sector, count) in entries {
+                let end = match sector.checked_add(count) {
+                    Some(end) if end <= mish.sector_count => end,
+                    _ => {
+                        problems.push(format!("{} entry {} runs past the end of the partition ({} sectors)", name, i, mish.sector_count));
+                        continue;
+                    }
+                };
+
+                if sector < expected {
+                    let other = previous.map(|p| format!("entry {}", p)).unwrap_or_default();
+                    problems.push(format!("{} entry {} overlaps {} at sector {}", name, i, other, sector));
+                } else if sector > expected {
+                    problems.push(format!("{} has no chunk for sectors {} to {}, before entry {}", name, expected, sector - 1, i));
+                }
+                if end > expected {
+                    expected = end;
+                    previous = Some(i);
+                }
+
+                chunks.push(IndexedChunk {
+                    sector: mish.sector_number + sector,
+                    sector_count: count,
+                    partition: index,
+                    entry: i,
+                });
+            }
+
+            if expected < mish.sector_count {
+                problems.push(format!("{} has no chunk for sectors {} to {}", name, expected, mish.sector_count - 1));
+            }
+        }
+
+        // partitions may leave gaps between them, but not overlap
+        let mut ranges: Vec<(usize, u64, u64)> = partitions
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.data.sector_count > 0)
+            .map(|(i, p)| (i, p.data.sector_number, p.data.sector_number.saturating_add(p.data.sector_count)))
+            .collect();
+        ranges.sort_by_key(|(_, start, _)| *start);
+        for pair in ranges.windows(2) {
+            let ((a, _, a_end), (b, b_start, _)) = (pair[0], pair[1]);
+            if b_start < a_end {
+                problems.push(format!("partition {} overlaps partition {} at sector {}", b, a, b_start));
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        chunks.sort_by_key(|c| c.sector);
+        Ok(SectorIndex { chunks })
+    }
+
+    /// Every chunk, in disk order
+    pub fn chunks(&self) -> &[IndexedChunk] {
+        &self.chunks
+    }
+
+    /// The chunk holding a disk sector, if any
+    pub fn find(&self, sector: u64) -> Option<&IndexedChunk> {
+        let found = self.chunks.partition_point(|c| c.end() <= sector);
+        self.chunks.get(found).filter(|c| c.sector <= sector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blkx::{BlkxChunkEntry, DmgBlxx};
+    use crate::convert::{build_mish, final_blkx};
+
+    fn entry(sector_number: u64, sector_count: u64) -> BlkxChunkEntry {
+        BlkxChunkEntry {
+            entry_type: DmgBlxx::ZeroFill,
+            comment: 0,
+            sector_number,
+            sector_count,
+            compressed_offset: 0,
+            compressed_length: 0,
+        }
+    }
+
+    /// A partition at sector start, of sectors sectors, with chunks of (sector, count)
+    fn partition(id: i32, start: u64, sectors: u64, chunks: &[(u64, u64)]) -> PartitionEntry {
+        let mut entries: Vec<BlkxChunkEntry> = chunks.iter().map(|(sector, count)| entry(*sector, *count)).collect();
+        entries.push(final_blkx(sectors as usize, 0));
+        let mut data = build_mish(sectors, entries);
+        data.sector_number = start;
+
+        PartitionEntry {
+            attributes: "0x0050".to_string(),
+            cf_name: String::new(),
+            data,
+            id,
+            name: format!("partition {}", id),
+        }
+    }
+
+    #[test]
+    fn finds_chunks() {
+        // chunks out of order, and a second partition after a gap
+        let partitions = vec![
+            partition(0, 0, 30, &[(10, 20), (0, 10)]),
+            partition(1, 40, 5, &[(0, 5)]),
+        ];
+        let index = SectorIndex::build(&partitions, 0).unwrap();
+
+        let found: Vec<(u64, u64, usize, usize)> = index.chunks().iter().map(|c| (c.sector, c.end(), c.partition, c.entry)).collect();
+        assert_eq!(found, vec![(0, 10, 0, 1), (10, 30, 0, 0), (40, 45, 1, 0)]);
+
+        assert_eq!(index.find(0).map(|c| c.entry), Some(1));
+        assert_eq!(index.find(9).map(|c| c.entry), Some(1));
+        assert_eq!(index.find(10).map(|c| c.entry), Some(0));
+        assert_eq!(index.find(29).map(|c| c.entry), Some(0));
+        assert_eq!(index.find(44).map(|c| c.partition), Some(1));
+        // between and after the partitions
+        assert!(index.find(30).is_none());
+        assert!(index.find(39).is_none());
+        assert!(index.find(45).is_none());
+    }
+
+    #[test]
+    fn gaps() {
+        let problems = SectorIndex::build(&[partition(0, 0, 30, &[(0, 10), (15, 10)])], 0).unwrap_err();
+        assert_eq!(problems, vec![
+            "partition 0 (ID 0) has no chunk for sectors 10 to 14, before entry 1".to_string(),
+            "partition 0 (ID 0) has no chunk for sectors 25 to 29".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn overlaps() {
+        let problems = SectorIndex::build(&[partition(3, 0, 20, &[(0, 10), (5, 15)])], 0).unwrap_err();
+        assert_eq!(problems, vec!["partition 0 (ID 3) entry 1 overlaps entry 0 at sector 5".to_string()]);
+
+        // and the sectors it would have covered are missing
+        let problems = SectorIndex::build(&[partition(0, 0, 20, &[(0, 10), (10, 11)])], 0).unwrap_err();
+        assert_eq!(problems, vec![
+            "partition 0 (ID 0) entry 1 runs past the end of the partition (20 sectors)".to_string(),
+            "partition 0 (ID 0) has no chunk for sectors 10 to 19".to_string(),
+        ]);
+
+        let problems = SectorIndex::build(&[partition(0, 0, 20, &[(0, 20), (u64::MAX, 2)])], 0).unwrap_err();
+        assert_eq!(problems, vec!["partition 0 (ID 0) entry 1 runs past the end of the partition (20 sectors)".to_string()]);
+
+        let partitions = vec![partition(0, 0, 20, &[(0, 20)]), partition(1, 10, 20, &[(0, 20)])];
+        let problems = SectorIndex::build(&partitions, 0).unwrap_err();
+        assert_eq!(problems, vec!["partition 1 overlaps partition 0 at sector 10".to_string()]);
+    }
+
+    #[test]
+    fn empty_chunks_and_partitions() {
+        // comments and the LastEntry have no sectors
+        let partitions = vec![partition(0, 0, 0, &[]), partition(1, 0, 10, &[(0, 0), (0, 10)])];
+        let index = SectorIndex::build(&partitions, 0).unwrap();
+        assert_eq!(index.chunks().len(), 1);
+        assert_eq!(index.find(0).map(|c| (c.partition, c.entry)), Some((1, 1)));
+    }
+
+    #[test]
+    fn chunks_past_the_data_fork() {
+        let mut partitions = vec![partition(0, 0, 20, &[(0, 10), (10, 10)])];
+        let mish = &mut partitions[0].data;
+        mish.data_offset = 512;
+        for (i, entry) in mish.block_entries.iter_mut().take(2).enumerate() {
+            entry.entry_type = DmgBlxx::RawOrNullCompression;
+            entry.compressed_offset = i as u64 * 1000;
+            entry.compressed_length = 1000;
+        }
+
+        // the second chunk ends exactly at the end of the data fork
+        assert!(SectorIndex::build(&partitions, 2512).is_ok());
+        let problems = SectorIndex::build(&partitions, 2511).unwrap_err();
+        assert_eq!(problems, vec!["partition 0 (ID 0) entry 1 runs past the end of the data fork (2511 bytes)".to_string()]);
+
+        // starting past the end, and overflowing
+        partitions[0].data.block_entries[0].compressed_offset = 5000;
+        partitions[0].data.block_entries[1].compressed_offset = u64::MAX - 10;
+        let problems = SectorIndex::build(&partitions, 2512).unwrap_err();
+        assert_eq!(problems, vec![
+            "partition 0 (ID 0) entry 0 runs past the end of the data fork (2512 bytes)".to_string(),
+            "partition 0 (ID 0) entry 1 runs past the end of the data fork (2512 bytes)".to_string(),
+        ]);
+
+        // ZeroFill chunks have nothing in the data fork to check
+        assert!(SectorIndex::build(&[partition(0, 0, 20, &[(0, 20)])], 0).is_ok());
+    }
+}
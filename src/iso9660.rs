@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::prelude::{Read, Seek};
+use std::io::SeekFrom;
+
+/// Volume descriptors start at sector 16
+const DESCRIPTORS_OFFSET: u64 = 16 * 2048;
+
+/// "CD001" in ASCII
+pub const ISO_MAGIC: &[u8; 5] = b"CD001";
+
+/// Directory record flags
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Rock Ridge file types, from the PX entry
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// A file, folder or symlink on an ISO 9660 volume
+#[derive(Debug, Clone)]
+pub struct IsoEntry {
+    /// Index of the containing folder in IsoVolume::entries
+    pub parent: usize,
+    /// Rock Ridge name if there is one, otherwise the ISO 9660 name
+    /// without its version
+    pub name: String,
+    pub directory: bool,
+    /// Rock Ridge symlink target
+    pub symlink: Option<String>,
+    /// Rock Ridge POSIX mode
+    pub mode: Option<u32>,
+    /// Seconds since 1970, from the directory record
+    pub modified: u64,
+    /// Length of the data in bytes
+    pub size: u64,
+    /// Byte ranges (offset, length) on the volume holding the data
+    pub extents: Vec<(u64, u64)>,
+}
+
+/// An ISO 9660 volume, with every directory read into memory
+#[derive(Debug)]
+pub struct IsoVolume {
+    /// Volume identifier from the primary volume descriptor
+    pub name: String,
+    /// The root folder first, then everything below it
+    pub entries: Vec<IsoEntry>,
+}
+
+impl IsoVolume {
+    /// Read the primary volume descriptor and walk every directory
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<IsoVolume, String> {
+        let mut descriptor = vec![0u8; 2048];
+        let mut offset = DESCRIPTORS_OFFSET;
+
+        // type 1 is the primary volume descriptor, 255 ends the set
+        loop {
+            reader.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            reader.read_exact(&mut descriptor).map_err(|_| "Not an ISO 9660 volume".to_string())?;
+            if &descriptor[1..6] != ISO_MAGIC {
+                return Err("Not an ISO 9660 volume".to_string());
+            }
+            match descriptor[0] {
+                1 => break,
+                255 => return Err("ISO 9660 volume has no primary volume descriptor".to_string()),
+                _ => offset += 2048,
+            }
+        }
+
+        let name = String::from_utf8_lossy(&descriptor[40..72]).trim_end().to_string();
+        let block_size = u64::from(u16::from_le_bytes([descriptor[128], descriptor[129]]));
+        if block_size == 0 {
+            return Err("ISO 9660 volume has a logical block size of 0".to_string());
+        }
+        let root = parse_record(&descriptor[156..190]).ok_or("Invalid ISO 9660 root directory record")?;
+
+        let mut volume = IsoVolume {
+            name,
+            entries: vec![IsoEntry {
+                parent: 0,
+                name: String::new(),
+                directory: true,
+                symlink: None,
+                mode: None,
+                modified: root.modified,
+                size: root.size,
+                extents: vec![(root.extent * block_size, root.size)],
+            }],
+        };
+
+        // walk breadth first, never reading a directory twice
+        let mut visited = HashSet::new();
+        let mut index = 0;
+        while index < volume.entries.len() {
+            let entry = &volume.entries[index];
+            if entry.directory && visited.insert(entry.extents[0].0) {
+                let (start, length) = entry.extents[0];
+                let mut data = vec![0u8; length as usize];
+                reader.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+                reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+                volume.read_directory(reader, index, &data, block_size)?;
+            }
+            index += 1;
+        }
+
+        Ok(volume)
+    }
+
+    /// Entries directly inside a folder, as (index, entry)
+    pub fn children(&self, parent: usize) -> impl Iterator<Item = (usize, &IsoEntry)> {
+        self.entries.iter().enumerate().skip(1).filter(move |(_, e)| e.parent == parent)
+    }
+
+    /// Add the records of one directory, whose data is in data
+    fn read_directory<R: Read + Seek>(&mut self, reader: &mut R, parent: usize, data: &[u8], block_size: u64) -> Result<(), String> {
+        let mut position = 0;
+        // the previous record had more extents to come
+        let mut continued = false;
+
+        while position < data.len() {
+            let length = data[position] as usize;
+            // records don't cross blocks, the rest of this one is padding
+            if length == 0 {
+                position = (position / block_size as usize + 1) * block_size as usize;
+                continue;
+            }
+
+            let bytes = data.get(position..position + length).ok_or("Truncated ISO 9660 directory record")?;
+            position += length;
+
+            let record = parse_record(bytes).ok_or("Invalid ISO 9660 directory record")?;
+            // 0 and 1 are . and ..
+            if record.identifier == [0] || record.identifier == [1] {
+                continue;
+            }
+
+            let extent = (record.extent * block_size, record.size);
+            if continued {
+                let last = self.entries.last_mut().expect("A record was continued");
+                last.extents.push(extent);
+                last.size += record.size;
+            } else {
+                let rock_ridge = read_rock_ridge(reader, record.system_use, block_size)?;
+                let is_link = rock_ridge.mode.map(|m| m & S_IFMT == S_IFLNK).unwrap_or(true);
+                let symlink = rock_ridge.symlink.filter(|_| is_link);
+                self.entries.push(IsoEntry {
+                    parent,
+                    name: rock_ridge.name.unwrap_or_else(|| iso_name(record.identifier)),
+                    directory: record.flags & FLAG_DIRECTORY != 0,
+                    symlink,
+                    mode: rock_ridge.mode,
+                    modified: record.modified,
+                    size: record.size,
+                    extents: vec![extent],
+                });
+            }
+            continued = record.flags & FLAG_MULTI_EXTENT != 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of a directory record we use
+struct DirectoryRecord<'a> {
+    extent: u64,
+    size: u64,
+    modified: u64,
+    flags: u8,
+    identifier: &'a [u8],
+    system_use: &'a [u8],
+}
+
+/// Parse a directory record. Numbers are stored both little and big
+/// endian, we read the little endian half.
+fn parse_record(bytes: &[u8]) -> Option<DirectoryRecord<'_>> {
+    let identifier_length = *bytes.get(32)? as usize;
+    let identifier = bytes.get(33..33 + identifier_length)?;
+    // the identifier is padded to an even length
+    let system_use = bytes.get(33 + identifier_length + (1 - identifier_length % 2)..).unwrap_or(&[]);
+
+    Some(DirectoryRecord {
+        extent: u64::from(u32::from_le_bytes(bytes.get(2..6)?.try_into().ok()?)),
+        size: u64::from(u32::from_le_bytes(bytes.get(10..14)?.try_into().ok()?)),
+        modified: record_date(bytes.get(18..25)?),
+        flags: bytes[25],
+        identifier,
+        system_use,
+    })
+}
+
+/// Seconds since 1970 from a 7 byte directory record date: years since
+/// 1900, month, day, hour, minute, second and GMT offset in 15 minutes
+fn record_date(date: &[u8]) -> u64 {
+    let (year, month, day) = (1900 + i64::from(date[0]), i64::from(date[1]), i64::from(date[2]));
+    if month == 0 || day == 0 {
+        return 0;
+    }
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86400 + i64::from(date[3]) * 3600 + i64::from(date[4]) * 60 + i64::from(date[5]);
+    let offset = i64::from(date[6] as i8) * 15 * 60;
+    std::cmp::max(seconds - offset, 0) as u64
+}
+
+/// An ISO 9660 name, e.g. "README.TXT;1", without the version
+/// and the trailing dot of names without an extension
+fn iso_name(identifier: &[u8]) -> String {
+    let name = String::from_utf8_lossy(identifier);
+    let name = name.split(';').next().unwrap_or_default();
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+/// What the Rock Ridge entries of a record tell us
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    symlink: Option<String>,
+}
+
+/// Read the NM, PX and SL entries from the system use area of a
+/// record, following CE entries into continuation areas
+fn read_rock_ridge<R: Read + Seek>(reader: &mut R, system_use: &[u8], block_size: u64) -> Result<RockRidge, String> {
+    let mut rock_ridge = RockRidge::default();
+    let mut name: Option<Vec<u8>> = None;
+    let mut symlink: Option<Vec<u8>> = None;
+    let mut joined = false;
+
+    let mut area = system_use.to_vec();
+    // bounds the number of continuation areas, in case they form a loop
+    let mut continuations = 0;
+
+    loop {
+        let mut next = None;
+        let mut position = 0;
+
+        while position + 4 <= area.len() {
+            let length = area[position + 2] as usize;
+            if length < 4 || position + length > area.len() {
+                break;
+            }
+            let entry = &area[position..position + length];
+            position += length;
+
+            match &entry[0..2] {
+                b"NM" if length > 5 => {
+                    // flags 0x02 and 0x04 are . and .., 0x01 continues the name
+                    name.get_or_insert_with(Vec::new).extend_from_slice(&entry[5..]);
+                }
+                b"PX" if length >= 8 => {
+                    rock_ridge.mode = Some(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+                }
+                b"SL" if length > 5 => {
+                    let target = symlink.get_or_insert_with(Vec::new);
+                    read_symlink_components(&entry[5..], target, &mut joined);
+                }
+                b"CE" if length >= 28 => {
+                    let block = u64::from(u32::from_le_bytes(entry[4..8].try_into().unwrap()));
+                    let offset = u64::from(u32::from_le_bytes(entry[12..16].try_into().unwrap()));
+                    let size = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as usize;
+                    next = Some((block * block_size + offset, size));
+                }
+                b"ST" => break,
+                _ => (),
+            }
+        }
+
+        match next {
+            Some((offset, size)) if continuations < 16 => {
+                continuations += 1;
+                area = vec![0u8; size];
+                reader.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+                reader.read_exact(&mut area).map_err(|e| e.to_string())?;
+            }
+            _ => break,
+        }
+    }
+
+    rock_ridge.name = name.map(|n| String::from_utf8_lossy(&n).to_string());
+    rock_ridge.symlink = symlink.map(|s| String::from_utf8_lossy(&s).to_string());
+    Ok(rock_ridge)
+}
+
+/// Append the path components of an SL entry to target. joined is
+/// set when the last component continues into the next one, which
+/// then isn't preceded by a /.
+fn read_symlink_components(mut components: &[u8], target: &mut Vec<u8>, joined: &mut bool) {
+    while components.len() >= 2 {
+        let flags = components[0];
+        let length = components[1] as usize;
+        let content = match components.get(2..2 + length) {
+            Some(content) => content,
+            None => break,
+        };
+
+        if !target.is_empty() && !*joined && !target.ends_with(b"/") {
+            target.push(b'/');
+        }
+        match flags & 0x0E {
+            0x02 => target.push(b'.'),
+            0x04 => target.extend_from_slice(b".."),
+            0x08 => target.push(b'/'),
+            _ => target.extend_from_slice(content),
+        }
+        *joined = flags & 0x01 != 0;
+
+        components = &components[2 + length..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A directory record, with the numbers in both byte orders
+    fn record(extent: u32, size: u32, flags: u8, identifier: &[u8]) -> Vec<u8> {
+        let length = 33 + identifier.len() + (1 - identifier.len() % 2);
+        let mut record = vec![0u8; length];
+        record[0] = length as u8;
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        // 2001-02-03 04:05:06 GMT
+        record[18..25].copy_from_slice(&[101, 2, 3, 4, 5, 6, 0]);
+        record[25] = flags;
+        record[32] = identifier.len() as u8;
+        record[33..33 + identifier.len()].copy_from_slice(identifier);
+        record
+    }
+
+    /// A directory's records, starting with . and .. and laid out in
+    /// blocks of block_size that records don't cross
+    fn directory(extent: u32, parent: u32, block_size: usize, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let all = [vec![record(extent, 0, FLAG_DIRECTORY, &[0]), record(parent, 0, FLAG_DIRECTORY, &[1])], records.to_vec()].concat();
+        for record in all {
+            if data.len() / block_size != (data.len() + record.len() - 1) / block_size {
+                data.resize((data.len() / block_size + 1) * block_size, 0);
+            }
+            data.extend_from_slice(&record);
+        }
+        data.resize(data.len().div_ceil(block_size) * block_size, 0);
+        data
+    }
+
+    /// A volume with the root directory at block root, and the given
+    /// blocks, each as (block, contents)
+    fn volume(block_size: usize, root: u32, root_size: u32, blocks: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut image = vec![0u8; 18 * 2048];
+        let primary = &mut image[16 * 2048..17 * 2048];
+        primary[0] = 1;
+        primary[1..6].copy_from_slice(ISO_MAGIC);
+        primary[40..72].copy_from_slice(&[b' '; 32]);
+        primary[40..44].copy_from_slice(b"TEST");
+        primary[128..130].copy_from_slice(&(block_size as u16).to_le_bytes());
+        primary[156..190].copy_from_slice(&record(root, root_size, FLAG_DIRECTORY, &[0]));
+        image[17 * 2048] = 255;
+        image[17 * 2048 + 1..17 * 2048 + 6].copy_from_slice(ISO_MAGIC);
+
+        for (block, contents) in blocks {
+            let start = *block as usize * block_size;
+            if image.len() < start + contents.len() {
+                image.resize(start + contents.len(), 0);
+            }
+            image[start..start + contents.len()].copy_from_slice(contents);
+        }
+        image
+    }
+
+    fn names(volume: &IsoVolume, parent: usize) -> Vec<String> {
+        volume.children(parent).map(|(_, e)| e.name.clone()).collect()
+    }
+
+    #[test]
+    fn directory_tree() {
+        let root = directory(20, 20, 2048, &[
+            record(30, 5, 0, b"README.TXT;1"),
+            record(21, 2048, FLAG_DIRECTORY, b"SUB"),
+            record(31, 0, 0, b"EMPTY.;1"),
+        ]);
+        let sub = directory(21, 20, 2048, &[record(32, 3, 0, b"INNER.TXT;1")]);
+        let image = volume(2048, 20, root.len() as u32, &[(20, root), (21, sub), (30, b"hello".to_vec()), (32, b"abc".to_vec())]);
+
+        let volume = IsoVolume::read(&mut Cursor::new(image)).unwrap();
+        assert_eq!(volume.name, "TEST");
+        assert_eq!(names(&volume, 0), vec!["README.TXT", "SUB", "EMPTY"]);
+
+        let (sub, entry) = volume.children(0).find(|(_, e)| e.name == "SUB").unwrap();
+        assert!(entry.directory);
+        assert_eq!(names(&volume, sub), vec!["INNER.TXT"]);
+
+        let (_, readme) = volume.children(0).next().unwrap();
+        assert!(!readme.directory);
+        assert_eq!(readme.size, 5);
+        assert_eq!(readme.extents, vec![(30 * 2048, 5)]);
+        assert_eq!(readme.modified, 981_173_106);
+    }
+
+    #[test]
+    fn multi_extent_files() {
+        let root = directory(20, 20, 2048, &[
+            record(30, 2048, FLAG_MULTI_EXTENT, b"BIG.BIN;1"),
+            record(40, 2048, FLAG_MULTI_EXTENT, b"BIG.BIN;1"),
+            record(50, 100, 0, b"BIG.BIN;1"),
+            record(60, 1, 0, b"AFTER.TXT;1"),
+        ]);
+        let image = volume(2048, 20, root.len() as u32, &[(20, root)]);
+
+        let volume = IsoVolume::read(&mut Cursor::new(image)).unwrap();
+        assert_eq!(names(&volume, 0), vec!["BIG.BIN", "AFTER.TXT"]);
+        let big = &volume.entries[1];
+        assert_eq!(big.size, 4196);
+        assert_eq!(big.extents, vec![(30 * 2048, 2048), (40 * 2048, 2048), (50 * 2048, 100)]);
+        assert_eq!(volume.entries[2].extents, vec![(60 * 2048, 1)]);
+    }
+
+    #[test]
+    fn directory_loops() {
+        // SUB holds a link back to the root, which is not walked again
+        let root = directory(20, 20, 2048, &[record(21, 2048, FLAG_DIRECTORY, b"SUB")]);
+        let sub = directory(21, 20, 2048, &[record(20, 2048, FLAG_DIRECTORY, b"LOOP"), record(21, 2048, FLAG_DIRECTORY, b"SELF")]);
+        let image = volume(2048, 20, 2048, &[(20, root), (21, sub)]);
+
+        let volume = IsoVolume::read(&mut Cursor::new(image)).unwrap();
+        assert_eq!(volume.entries.len(), 4);
+        assert_eq!(names(&volume, 1), vec!["LOOP", "SELF"]);
+        assert_eq!(names(&volume, 2), Vec::<String>::new());
+        assert_eq!(names(&volume, 3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn records_padded_to_small_blocks() {
+        // enough records that the directory spans several 512 byte blocks
+        let files: Vec<Vec<u8>> = (0..20).map(|i| record(100 + i, 1, 0, format!("FILE{:02}.TXT;1", i).as_bytes())).collect();
+        let root = directory(80, 80, 512, &files);
+        assert!(root.len() > 512);
+        let image = volume(512, 80, root.len() as u32, &[(80, root)]);
+
+        let volume = IsoVolume::read(&mut Cursor::new(image)).unwrap();
+        let expected: Vec<String> = (0..20).map(|i| format!("FILE{:02}.TXT", i)).collect();
+        assert_eq!(names(&volume, 0), expected);
+        assert_eq!(volume.entries[20].extents, vec![(119 * 512, 1)]);
+    }
+
+    #[test]
+    fn truncated_records() {
+        // the last record says it runs past the end of the directory
+        let mut root = directory(20, 20, 2048, &[record(30, 5, 0, b"README.TXT;1")]);
+        let last = 68;
+        assert_eq!(&root[last + 33..last + 45], b"README.TXT;1");
+        root.truncate(last + 20);
+        let image = volume(2048, 20, root.len() as u32, &[(20, root)]);
+        assert_eq!(IsoVolume::read(&mut Cursor::new(image)).err().unwrap(), "Truncated ISO 9660 directory record");
+
+        // a record too short to hold its identifier
+        let mut root = directory(20, 20, 2048, &[record(30, 5, 0, b"README.TXT;1")]);
+        root[last] = 40;
+        let image = volume(2048, 20, 2048, &[(20, root)]);
+        assert_eq!(IsoVolume::read(&mut Cursor::new(image)).err().unwrap(), "Invalid ISO 9660 directory record");
+
+        // and a directory past the end of the volume
+        let image = volume(2048, 20, 2048, &[]);
+        assert!(IsoVolume::read(&mut Cursor::new(image)).is_err());
+    }
+
+    #[test]
+    fn not_iso9660() {
+        assert_eq!(IsoVolume::read(&mut Cursor::new(vec![0u8; 40_000])).err().unwrap(), "Not an ISO 9660 volume");
+
+        let mut image = volume(2048, 20, 2048, &[(20, directory(20, 20, 2048, &[]))]);
+        image[16 * 2048 + 128..16 * 2048 + 130].copy_from_slice(&[0, 0]);
+        assert!(IsoVolume::read(&mut Cursor::new(image)).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::io::{self, prelude::{Read, Seek}, SeekFrom};
+
 use super::util;
 
 const KOLY_MAGIC: &str = "0x6B6F6C79";
@@ -6,7 +8,7 @@ const KOLY_MAGIC: &str = "0x6B6F6C79";
 /// Typically found in the last 512 bytes of a DMG.
 /// All fields are in big endian ordering to maintain compatiblity
 /// with older versions of macOS.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KolyBlock {
     /// Magic - 0x6B6F6C79 "koly" in ASCII
     pub magic: u32,
@@ -64,12 +66,29 @@ pub struct KolyBlock {
 
 impl KolyBlock {
     pub fn new(buffer: Vec<u8>) -> Result<KolyBlock, &'static str> {
+        KolyBlock::from_bytes(&buffer)
+    }
+
+    /// Read the koly block from the last 512 bytes of reader
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<KolyBlock, io::Error> {
+        reader.seek(SeekFrom::End(-512))?;
+        let mut buffer = [0u8; 512];
+        reader.read_exact(&mut buffer)?;
+
+        KolyBlock::from_bytes(&buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<KolyBlock, &'static str> {
 
         // sanity check that we've got 512 bytes
         // and that the first 4 are the koly magic
-        assert_eq!(buffer.len(), 512);
+        if buffer.len() != 512 {
+            return Err("koly block must be 512 bytes");
+        }
         let magic = util::read_be_u32(&mut &buffer[0..4]);
-        assert_eq!(format!("{:#X}", magic), KOLY_MAGIC);
+        if format!("{:#X}", magic) != KOLY_MAGIC {
+            return Err("Invalid koly magic bytes");
+        }
 
         Ok(KolyBlock {
             magic,
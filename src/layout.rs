@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::alias::Alias;
+use super::bplist::{self, Value};
+use super::dsstore::{Record, RecordValue};
+use super::hfs::HfsVolume;
+use super::util;
+
+/// Finder window layout for the root of a DMG, read from a JSON or
+/// TOML file. Replaces the values hardcoded in custom_dsstore.py.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Layout {
+    /// Name of the volume, used for the background alias when
+    /// the volume itself isn't available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_name: Option<String>,
+    /// Position and size of the Finder window
+    pub window: Window,
+    /// Icon size in points
+    #[serde(default = "default_icon_size")]
+    pub icon_size: f64,
+    /// Label text size in points
+    #[serde(default = "default_text_size")]
+    pub text_size: f64,
+    /// Background picture, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<Background>,
+    /// Icon positions
+    #[serde(default)]
+    pub icons: Vec<Icon>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Window {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub show_toolbar: bool,
+    #[serde(default)]
+    pub show_sidebar: bool,
+    #[serde(default)]
+    pub show_status_bar: bool,
+    #[serde(default)]
+    pub show_path_bar: bool,
+}
+
+/// The background picture, either as a path on the volume
+/// (e.g. "/.background/background.tiff") that an alias is built for,
+/// or as an existing base64 encoded alias record
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Background {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Icon {
+    /// Name of the file or folder in the root of the volume
+    pub name: String,
+    /// Position of the centre of the icon
+    pub x: u32,
+    pub y: u32,
+}
+
+fn default_icon_size() -> f64 {
+    96.0
+}
+
+fn default_text_size() -> f64 {
+    12.0
+}
+
+impl Layout {
+    /// Read a layout, as TOML if the file ends in .toml, otherwise JSON
+    pub fn from_file(path: &Path) -> Result<Layout, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+
+        if path.extension().is_some_and(|e| e == "toml") {
+            toml::from_str(&contents).map_err(|e| format!("Invalid layout {:?}: {}", path, e))
+        } else {
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid layout {:?}: {}", path, e))
+        }
+    }
+
+    /// The .DS_Store records describing this layout. With the HFS+
+    /// volume the layout is for, the background alias gets the real
+    /// CNIDs and dates, otherwise placeholders.
+    pub fn records(&self, volume: Option<&HfsVolume>) -> Result<Vec<Record>, String> {
+        let mut records = Vec::new();
+
+        // window bounds and chrome
+        let window = &self.window;
+        let mut bwsp = BTreeMap::new();
+        bwsp.insert("ContainerShowSidebar".to_string(), Value::Bool(window.show_sidebar));
+        bwsp.insert("ShowPathbar".to_string(), Value::Bool(window.show_path_bar));
+        bwsp.insert("ShowSidebar".to_string(), Value::Bool(window.show_sidebar));
+        bwsp.insert("ShowStatusBar".to_string(), Value::Bool(window.show_status_bar));
+        bwsp.insert("ShowTabView".to_string(), Value::Bool(false));
+        bwsp.insert("ShowToolbar".to_string(), Value::Bool(window.show_toolbar));
+        bwsp.insert(
+            "WindowBounds".to_string(),
+            Value::String(format!("{{{{{}, {}}}, {{{}, {}}}}}", window.x, window.y, window.width, window.height)),
+        );
+        records.push(Record::new(".", b"bwsp", RecordValue::Blob(bplist::write(&Value::Dictionary(bwsp)))));
+
+        // icon view settings
+        let mut icvp = BTreeMap::new();
+        icvp.insert("arrangeBy".to_string(), Value::String("none".to_string()));
+        icvp.insert("backgroundColorBlue".to_string(), Value::Real(1.0));
+        icvp.insert("backgroundColorGreen".to_string(), Value::Real(1.0));
+        icvp.insert("backgroundColorRed".to_string(), Value::Real(1.0));
+        icvp.insert("backgroundType".to_string(), Value::Integer(0));
+        icvp.insert("gridOffsetX".to_string(), Value::Real(0.0));
+        icvp.insert("gridOffsetY".to_string(), Value::Real(0.0));
+        icvp.insert("gridSpacing".to_string(), Value::Real(100.0));
+        icvp.insert("iconSize".to_string(), Value::Real(self.icon_size));
+        icvp.insert("labelOnBottom".to_string(), Value::Bool(true));
+        icvp.insert("showIconPreview".to_string(), Value::Bool(true));
+        icvp.insert("showItemInfo".to_string(), Value::Bool(false));
+        icvp.insert("textSize".to_string(), Value::Real(self.text_size));
+        icvp.insert("viewOptionsVersion".to_string(), Value::Integer(1));
+
+        // the background picture is an alias, both in icvp for current
+        // versions of Finder and as pict / BKGD for older ones
+        if let Some(background) = &self.background {
+            let alias = match (&background.path, &background.alias, volume) {
+                (Some(path), _, Some(volume)) => Alias::for_path(volume, path)?.to_be_bytes(),
+                (Some(path), _, None) => {
+                    let volume_name = self.volume_name.as_deref().ok_or("A background path needs a volume_name")?;
+                    Alias::placeholder(volume_name, path).to_be_bytes()
+                }
+                (None, Some(alias), _) => base64::decode(alias).map_err(|e| format!("Invalid background alias: {}", e))?,
+                (None, None, _) => return Err("Background needs a path or an alias".to_string()),
+            };
+
+            icvp.insert("backgroundType".to_string(), Value::Integer(2));
+            icvp.insert("backgroundImageAlias".to_string(), Value::Data(alias.clone()));
+
+            let mut bkgd = b"PctB".to_vec();
+            bkgd.extend_from_slice(&(alias.len() as u32).to_be_bytes());
+            bkgd.extend_from_slice(&0u32.to_be_bytes());
+            records.push(Record::new(".", b"BKGD", RecordValue::Blob(bkgd)));
+            records.push(Record::new(".", b"pict", RecordValue::Blob(alias)));
+        }
+
+        records.push(Record::new(".", b"icvp", RecordValue::Blob(bplist::write(&Value::Dictionary(icvp)))));
+        records.push(Record::new(".", b"vSrn", RecordValue::Long(1)));
+
+        // icon locations - x, y, then 0xFFFFFFFF 0xFFFF0000
+        for icon in &self.icons {
+            let mut iloc = Vec::new();
+            iloc.extend_from_slice(&icon.x.to_be_bytes());
+            iloc.extend_from_slice(&icon.y.to_be_bytes());
+            iloc.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+            records.push(Record::new(&icon.name, b"Iloc", RecordValue::Blob(iloc)));
+        }
+
+        Ok(records)
+    }
+
+    /// Recover a layout from existing .DS_Store records, e.g. one made
+    /// on a Mac or by custom_dsstore.py. Anything not in a Layout is ignored.
+    pub fn from_records(records: &[Record]) -> Result<Layout, String> {
+        let blob_plist = |code: &[u8; 4]| -> Option<BTreeMap<String, Value>> {
+            let record = records.iter().find(|r| r.filename == "." && &r.code == code)?;
+            match &record.value {
+                RecordValue::Blob(blob) => match bplist::read(blob) {
+                    Ok(Value::Dictionary(dict)) => Some(dict),
+                    _ => None,
+                },
+                _ => None,
+            }
+        };
+
+        let bwsp = blob_plist(b"bwsp").ok_or("No bwsp record for the window")?;
+        let flag = |key: &str| matches!(bwsp.get(key), Some(Value::Bool(true)));
+
+        // "{{x, y}, {width, height}}"
+        let bounds: Vec<u32> = match bwsp.get("WindowBounds") {
+            Some(Value::String(bounds)) => bounds
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|n| !n.is_empty())
+                .filter_map(|n| n.parse().ok())
+                .collect(),
+            _ => Vec::new(),
+        };
+        if bounds.len() != 4 {
+            return Err("Could not parse WindowBounds".to_string());
+        }
+
+        let window = Window {
+            x: bounds[0],
+            y: bounds[1],
+            width: bounds[2],
+            height: bounds[3],
+            show_toolbar: flag("ShowToolbar"),
+            show_sidebar: flag("ShowSidebar"),
+            show_status_bar: flag("ShowStatusBar"),
+            show_path_bar: flag("ShowPathbar"),
+        };
+
+        let icvp = blob_plist(b"icvp").unwrap_or_default();
+        let real = |key: &str, default: f64| match icvp.get(key) {
+            Some(Value::Real(real)) => *real,
+            Some(Value::Integer(int)) => *int as f64,
+            _ => default,
+        };
+
+        // prefer the path, so the alias can be rebuilt for a new volume
+        let (background, volume_name) = match icvp.get("backgroundImageAlias") {
+            Some(Value::Data(alias)) => match Alias::from_be_bytes(alias) {
+                Ok(parsed) => (Some(Background { path: Some(parsed.path), alias: None }), Some(parsed.volume_name)),
+                Err(_) => (Some(Background { path: None, alias: Some(base64::encode(alias)) }), None),
+            },
+            _ => (None, None),
+        };
+
+        let icons = records
+            .iter()
+            .filter(|r| &r.code == b"Iloc")
+            .filter_map(|r| match &r.value {
+                RecordValue::Blob(blob) if blob.len() >= 8 => Some(Icon {
+                    name: r.filename.clone(),
+                    x: util::read_be_u32(&mut &blob[0..4]),
+                    y: util::read_be_u32(&mut &blob[4..8]),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Layout {
+            volume_name,
+            window,
+            icon_size: real("iconSize", default_icon_size()),
+            text_size: real("textSize", default_text_size()),
+            background,
+            icons,
+        })
+    }
+}
@@ -0,0 +1,36 @@
+//! Inspect and create DMG (UDIF) disk images.
+//!
+//! `dmg::Dmg` opens existing images, `builder::DmgBuilder` creates new ones.
+
+pub mod alias;
+pub mod blkx;
+pub mod builder;
+pub mod bplist;
+pub mod cache;
+pub mod convert;
+pub mod decompress;
+pub mod disk;
+pub mod dmg;
+pub mod dsstore;
+pub mod encryption;
+pub mod format;
+pub mod hfs;
+pub mod index;
+pub mod iso9660;
+pub mod koly;
+pub mod layout;
+pub mod lint;
+pub mod mish;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod nbd;
+pub mod partition;
+pub mod resource;
+pub mod resource_fork;
+pub mod segment;
+pub mod sla;
+pub mod sparse;
+pub mod sparsebundle;
+pub mod util;
+pub mod volume;
+pub mod xml;
@@ -0,0 +1,435 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::{Read, Seek}, SeekFrom};
+use std::path::Path;
+
+use super::blkx::DmgBlxx;
+use super::index::SectorIndex;
+use super::koly::KolyBlock;
+use super::segment;
+use super::xml::PList;
+
+/// "mish" in ASCII
+const MISH_SIGNATURE: u32 = 0x6D69_7368;
+
+/// How much a finding matters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Unusual, but readers cope with it
+    Info,
+    /// Against the format, but we and hdiutil can still read the image
+    Warning,
+    /// The image can't be read, or reads wrongly
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// Something lint found wrong with an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(severity: Severity, message: String) -> Finding {
+        Finding { severity, message }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Cross-check the koly block, plist and chunk tables of a DMG against
+/// each other and the length of the file. A segmented image also has the
+/// koly block of each of its .dmgpart segments checked, and its chunks
+/// checked against the data forks of all of them.
+pub fn lint_path(path: &Path) -> Result<Vec<Finding>, io::Error> {
+    let mut file = File::open(path)?;
+    let koly = KolyBlock::from_reader(&mut file)?;
+    if koly.segment_count <= 1 {
+        return lint(&mut file);
+    }
+
+    let mut findings = Vec::new();
+    let data_fork_length = lint_segments(path, &koly, &mut findings)?;
+    findings.append(&mut lint_image(&mut file, data_fork_length)?);
+    Ok(findings)
+}
+
+/// Cross-check the koly block, plist and chunk tables of a single file
+/// DMG (or the first segment of a segmented one) against each other and
+/// the length of the file. An image that can't be read at all is an
+/// io::Error, everything else is a finding.
+pub fn lint<R: Read + Seek>(reader: &mut R) -> Result<Vec<Finding>, io::Error> {
+    lint_image(reader, None)
+}
+
+/// Check the koly block of every segment after the first, returning the
+/// length of the whole data fork, if every segment could be read
+fn lint_segments(path: &Path, first: &KolyBlock, findings: &mut Vec<Finding>) -> Result<Option<u64>, io::Error> {
+    let mut running_data_fork_offset = first.data_fork_length;
+    let mut complete = true;
+
+    for (index, path) in segment::segment_paths(path, first.segment_count).into_iter().enumerate().skip(1) {
+        let number = index as u32 + 1;
+        let name = format!("segment {} ({})", number, path.display());
+
+        let (koly, length) = match File::open(&path).and_then(|mut file| {
+            let length = file.seek(SeekFrom::End(0))?;
+            Ok((KolyBlock::from_reader(&mut file)?, length))
+        }) {
+            Ok(segment) => segment,
+            Err(e) => {
+                findings.push(Finding::new(Severity::Error, format!("Can't read {}: {}", name, e)));
+                complete = false;
+                continue;
+            }
+        };
+
+        if koly.segment_id != first.segment_id {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} has segment ID {:#X}, the first segment has {:#X}", name, koly.segment_id, first.segment_id),
+            ));
+        }
+        if koly.segment_number != number || koly.segment_count != first.segment_count {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} says it is segment {} of {}", name, koly.segment_number, koly.segment_count),
+            ));
+        }
+        if complete && koly.running_data_fork_offset != running_data_fork_offset {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} starts at {} in the data fork, the segments before it end at {}", name, koly.running_data_fork_offset, running_data_fork_offset),
+            ));
+        }
+        if koly.data_fork_offset.checked_add(koly.data_fork_length).map(|end| end > length - 512).unwrap_or(true) {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} data fork ({} bytes at {}) runs past the end of the file", name, koly.data_fork_length, koly.data_fork_offset),
+            ));
+        }
+
+        running_data_fork_offset = running_data_fork_offset.saturating_add(koly.data_fork_length);
+    }
+
+    Ok(if complete { Some(running_data_fork_offset) } else { None })
+}
+
+/// Lint a single file, checking chunk offsets against the data fork of
+/// a whole segmented image if its length is known
+fn lint_image<R: Read + Seek>(reader: &mut R, segmented_length: Option<u64>) -> Result<Vec<Finding>, io::Error> {
+    let mut findings = Vec::new();
+
+    let file_length = reader.seek(SeekFrom::End(0))?;
+    let koly = KolyBlock::from_reader(reader)?;
+    // everything but the koly block itself
+    let length = file_length - 512;
+
+    if koly.version != 4 {
+        findings.push(Finding::new(Severity::Warning, format!("koly version is {}, not 4", koly.version)));
+    }
+    if koly.header_size != 512 {
+        findings.push(Finding::new(Severity::Error, format!("koly header size is {}, not 512", koly.header_size)));
+    }
+    if koly.reserved_one.iter().any(|b| *b != 0) || koly.reserved_two != 0 || koly.reserved_three != 0 || koly.reserved_four != 0 {
+        findings.push(Finding::new(Severity::Warning, "koly reserved fields are not zero".to_string()));
+    }
+    // unsegmented images may say segment 0 of 0
+    if koly.segment_number > koly.segment_count || (koly.segment_count > 0 && koly.segment_number == 0) {
+        findings.push(Finding::new(Severity::Warning, format!("koly is segment {} of {}", koly.segment_number, koly.segment_count)));
+    }
+
+    // chunk offsets are into the data forks of every segment together
+    let data_fork_length = match (koly.segment_count > 1, segmented_length) {
+        (false, _) => Some(koly.data_fork_length),
+        (true, Some(length)) => Some(length),
+        (true, None) => None,
+    };
+    if data_fork_length.is_none() {
+        findings.push(Finding::new(Severity::Info, format!("Segment {} of {}, chunk offsets were not checked", koly.segment_number, koly.segment_count)));
+    }
+    if koly.segment_count > 1 && koly.running_data_fork_offset != 0 {
+        findings.push(Finding::new(
+            Severity::Error,
+            format!("First segment starts at {} in the data fork, not 0", koly.running_data_fork_offset),
+        ));
+    }
+
+    for (name, offset, fork_length) in [
+        ("data fork", koly.data_fork_offset, koly.data_fork_length),
+        ("XML plist", koly.xml_offset, koly.xml_length),
+        ("resource fork", koly.source_fork_offset, koly.source_fork_length),
+    ] {
+        if offset.checked_add(fork_length).map(|end| end > length).unwrap_or(true) {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} ({} bytes at {}) runs past the end of the file ({} bytes before the koly block)", name, fork_length, offset, length),
+            ));
+        }
+    }
+
+    // the plist, or the resource fork of images from before it
+    let plist = match (koly.xml_length, koly.source_fork_length) {
+        (0, 0) => Err("There is no XML plist or resource fork".to_string()),
+        (0, fork_length) => read_at(reader, koly.source_fork_offset, fork_length)
+            .and_then(|fork| PList::from_resource_fork(&fork).map_err(|e| e.to_string())),
+        (xml_length, _) => read_at(reader, koly.xml_offset, xml_length)
+            .and_then(|xml| PList::from_bytes(&xml).map_err(|e| e.to_string())),
+    };
+    let plist = match plist {
+        Ok(plist) => plist,
+        Err(e) => {
+            findings.push(Finding::new(Severity::Error, format!("Can't read the partition table: {}", e)));
+            return Ok(findings);
+        }
+    };
+
+    let mut partitions_end = 0;
+    let mut partitions_sectors = 0u64;
+
+    for (index, partition) in plist.partitions.iter().enumerate() {
+        let mish = &partition.data;
+        let name = format!("partition {} (ID {})", index, partition.id);
+        partitions_end = std::cmp::max(partitions_end, mish.sector_number.saturating_add(mish.sector_count));
+        partitions_sectors = partitions_sectors.saturating_add(mish.sector_count);
+
+        if mish.signature != MISH_SIGNATURE {
+            findings.push(Finding::new(Severity::Error, format!("{} mish signature is 0x{:08X}", name, mish.signature)));
+        }
+        if mish.version != 1 {
+            findings.push(Finding::new(Severity::Warning, format!("{} mish version is {}, not 1", name, mish.version)));
+        }
+        let reserved = [mish.reserved_1, mish.reserved_2, mish.reserved_3, mish.reserved_4, mish.reserved_5, mish.reserved_6];
+        if reserved.iter().any(|r| *r != 0) {
+            findings.push(Finding::new(Severity::Warning, format!("{} mish reserved fields are not zero", name)));
+        }
+        // parsing stops after the declared chunks, anything else is kept
+        if !mish.trailing.is_empty() {
+            findings.push(Finding::new(
+                Severity::Warning,
+                format!("{} mish has {} bytes after its {} chunks", name, mish.trailing.len(), mish.number_block_chunks),
+            ));
+        }
+        match mish.block_entries.last() {
+            Some(entry) if matches!(entry.entry_type, DmgBlxx::LastEntry) => (),
+            _ => findings.push(Finding::new(Severity::Error, format!("{} does not end with a LastEntry chunk", name))),
+        }
+    }
+
+    // chunks covering each partition exactly once, and within the data fork
+    if let Err(problems) = SectorIndex::build(&plist.partitions, data_fork_length.unwrap_or(u64::MAX)) {
+        for problem in problems {
+            findings.push(Finding::new(Severity::Error, problem));
+        }
+    }
+
+    if partitions_end > koly.sector_count {
+        findings.push(Finding::new(
+            Severity::Error,
+            format!("Partitions run to sector {}, past the {} sectors in the koly block", partitions_end, koly.sector_count),
+        ));
+    } else if partitions_sectors != koly.sector_count {
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!("Partitions have {} sectors, the koly block says {}", partitions_sectors, koly.sector_count),
+        ));
+    }
+
+    Ok(findings)
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+    reader.take(length).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    if (data.len() as u64) < length {
+        return Err(format!("{} bytes at {} are past the end of the file", length, offset));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::DmgBuilder;
+    use crate::format::Format;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn disk() -> Vec<u8> {
+        (0..300 * 512u32).map(|i| (i / 512) as u8 ^ (i % 11) as u8).collect()
+    }
+
+    fn image() -> Vec<u8> {
+        let disk = disk();
+        let mut image = Cursor::new(Vec::new());
+        DmgBuilder::new().partition("disk image", &disk[..]).chunk_size(64).write_to(&mut image).unwrap();
+        image.into_inner()
+    }
+
+    /// Change the koly block at the end of image
+    fn patch_koly(image: &mut [u8], patch: impl FnOnce(&mut KolyBlock)) {
+        let start = image.len() - 512;
+        let mut koly = KolyBlock::from_bytes(&image[start..]).unwrap();
+        patch(&mut koly);
+        image[start..].copy_from_slice(&koly.to_be_bytes());
+    }
+
+    fn messages(findings: &[Finding]) -> Vec<String> {
+        findings.iter().map(Finding::to_string).collect()
+    }
+
+    fn lint_bytes(image: &[u8]) -> Vec<String> {
+        messages(&lint(&mut Cursor::new(image)).unwrap())
+    }
+
+    #[test]
+    fn clean_image() {
+        assert!(lint_bytes(&image()).is_empty());
+    }
+
+    #[test]
+    fn koly_findings() {
+        let mut image = image();
+        let length = image.len() as u64 - 512;
+        let data_fork_length = KolyBlock::from_bytes(&image[length as usize..]).unwrap().data_fork_length;
+        patch_koly(&mut image, |koly| {
+            koly.version = 3;
+            koly.reserved_two = 1;
+            koly.xml_length = length;
+            koly.sector_count = 299;
+        });
+
+        assert_eq!(lint_bytes(&image), vec![
+            "warning: koly version is 3, not 4".to_string(),
+            "warning: koly reserved fields are not zero".to_string(),
+            format!("error: XML plist ({} bytes at {}) runs past the end of the file ({} bytes before the koly block)", length, data_fork_length, length),
+            format!("error: Can't read the partition table: {} bytes at {} are past the end of the file", length, data_fork_length),
+        ]);
+    }
+
+    #[test]
+    fn chunk_findings() {
+        let mut image = image();
+        let mut data_fork_length = 0;
+        patch_koly(&mut image, |koly| {
+            koly.data_fork_length -= 1;
+            data_fork_length = koly.data_fork_length;
+        });
+
+        // the last of the five chunks
+        assert_eq!(lint_bytes(&image), vec![
+            format!("error: partition 0 (ID 0) entry 4 runs past the end of the data fork ({} bytes)", data_fork_length),
+        ]);
+
+        patch_koly(&mut image, |koly| {
+            koly.data_fork_length += 1;
+            koly.sector_count = 299;
+        });
+        assert_eq!(lint_bytes(&image), vec![
+            "error: Partitions run to sector 300, past the 299 sectors in the koly block".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn trailing_mish_data() {
+        let disk = disk();
+        let parts = DmgBuilder::new().partition("disk image", &disk[..]).chunk_size(64).build().unwrap();
+        let mut plist = PList::from_bytes(&parts.xml).unwrap();
+        plist.partitions[0].data.trailing = vec![0xAB; 40];
+        let xml = plist.to_xml();
+
+        let koly = crate::convert::build_koly(xml.len() as u64, parts.data_fork.len() as u64, 300);
+        let mut image = parts.data_fork;
+        image.extend_from_slice(&xml);
+        image.extend_from_slice(&koly.to_be_bytes());
+
+        assert_eq!(lint_bytes(&image), vec!["warning: partition 0 (ID 0) mish has 40 bytes after its 6 chunks".to_string()]);
+    }
+
+    /// A UDRO image over three segments in a fresh directory
+    fn segments(name: &str) -> Vec<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("libdmg-lint-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let disk = disk();
+        let parts = DmgBuilder::new().partition("disk image", &disk[..]).format(Format::Udro).build().unwrap();
+        let paths = segment::write_segments(&dir.join("image.dmg"), &parts.data_fork, parts.xml, &parts.resource_fork, 300, 64 * 1024, 0x1234).unwrap();
+        assert_eq!(paths.len(), 3);
+        paths
+    }
+
+    fn patch_segment(path: &Path, patch: impl FnOnce(&mut KolyBlock)) {
+        let mut segment = std::fs::read(path).unwrap();
+        patch_koly(&mut segment, patch);
+        std::fs::write(path, segment).unwrap();
+    }
+
+    fn lint_segments(paths: &[PathBuf]) -> Vec<String> {
+        let findings = messages(&lint_path(&paths[0]).unwrap());
+        std::fs::remove_dir_all(paths[0].parent().unwrap()).unwrap();
+        findings
+    }
+
+    #[test]
+    fn clean_segments() {
+        let paths = segments("clean");
+        // only the first segment, on its own
+        assert_eq!(messages(&lint(&mut File::open(&paths[0]).unwrap()).unwrap()), vec![
+            "info: Segment 1 of 3, chunk offsets were not checked".to_string(),
+        ]);
+        assert!(lint_segments(&paths).is_empty());
+    }
+
+    #[test]
+    fn segment_findings() {
+        let paths = segments("findings");
+        let first = KolyBlock::from_reader(&mut File::open(&paths[0]).unwrap()).unwrap().data_fork_length;
+        patch_segment(&paths[1], |koly| {
+            koly.segment_id = 0x5678;
+            koly.segment_number = 3;
+            koly.running_data_fork_offset += 1;
+        });
+        patch_segment(&paths[2], |koly| koly.data_fork_length -= 1);
+
+        let findings = lint_segments(&paths);
+        let second = format!("segment 2 ({})", paths[1].display());
+        let third = format!("segment 3 ({})", paths[2].display());
+        assert_eq!(findings[..3], [
+            format!("error: {} has segment ID 0x5678, the first segment has 0x1234", second),
+            format!("error: {} says it is segment 3 of 3", second),
+            format!("error: {} starts at {} in the data fork, the segments before it end at {}", second, first + 1, first),
+        ]);
+        // the chunks now run past the data forks of the segments together
+        assert_eq!(findings.len(), 4);
+        assert!(findings[3].starts_with("error: partition 0 (ID 0) entry"), "{}", findings[3]);
+        assert!(!findings.iter().any(|f| f.contains(&third)));
+    }
+
+    #[test]
+    fn missing_segment() {
+        let paths = segments("missing");
+        std::fs::remove_file(&paths[2]).unwrap();
+
+        let findings = lint_segments(&paths);
+        assert_eq!(findings.len(), 2);
+        assert!(findings[0].starts_with(&format!("error: Can't read segment 3 ({})", paths[2].display())));
+        assert_eq!(findings[1], "info: Segment 1 of 3, chunk offsets were not checked");
+    }
+}
@@ -1,23 +1,15 @@
-use std::fs::{File};
+use std::fs::File;
 use std::io;
-use std::io::{prelude::{Read, Seek}, SeekFrom};
 use structopt::StructOpt;
 
-extern crate base64;
-extern crate libflate;
-extern crate xmltree;
-
-pub mod blkx;
-pub mod convert;
-pub mod koly;
-pub mod mish;
-pub mod partition;
-pub mod util;
-pub mod xml;
-
-use convert::*;
-use koly::*;
-use xml::*;
+use libdmg::{dsstore, encryption, format, lint, nbd, segment, sla, sparse, sparsebundle, util};
+use libdmg::disk::VirtualDisk;
+use libdmg::convert::*;
+use libdmg::dmg::Dmg;
+use libdmg::encryption::*;
+use libdmg::layout::*;
+use libdmg::sla::*;
+use libdmg::xml::*;
 
 #[derive(StructOpt)]
 #[structopt(name = "libdmg", about = "DMG inspection and creation")]
@@ -27,6 +19,25 @@ enum Cli {
     Inspect {
         /// path to a DMG file
         file: std::path::PathBuf,
+        /// file containing the passphrase of an encrypted DMG
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// file containing the unwrapped key of an encrypted DMG, raw or as hex
+        #[structopt(long = "keyfile", parse(from_os_str))]
+        keyfile: Option<std::path::PathBuf>,
+    },
+    #[structopt(name = "lint")]
+    /// Check that the koly block, plist and chunk tables of a DMG agree,
+    /// printing each problem found with its severity
+    Lint {
+        /// path to a DMG file
+        file: std::path::PathBuf,
+        /// file containing the passphrase of an encrypted DMG
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// file containing the unwrapped key of an encrypted DMG, raw or as hex
+        #[structopt(long = "keyfile", parse(from_os_str))]
+        keyfile: Option<std::path::PathBuf>,
     },
     #[structopt(name = "convert")]
     /// Create a DMG file from the given folder
@@ -35,6 +46,147 @@ enum Cli {
         iso: std::path::PathBuf,
         /// where to create the DMG
         dmg: std::path::PathBuf,
+        /// split the DMG into .dmgpart segments of this size (e.g. 100m)
+        #[structopt(long = "segment-size", parse(try_from_str = "segment::parse_size"))]
+        segment_size: Option<u64>,
+        /// encrypt the DMG, with aes-128 or aes-256
+        #[structopt(long = "encrypt", parse(try_from_str = "encryption::parse_algorithm"))]
+        encrypt: Option<u32>,
+        /// file containing the passphrase for --encrypt
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// derive keys, salts and IVs from the passphrase, for reproducible
+        /// test fixtures only
+        #[structopt(long = "deterministic-encryption")]
+        deterministic_encryption: bool,
+        /// license agreement shown on attach, as <file> or <language>:<file>
+        /// (en, fr, de, it, nl, sv, es). May be repeated, the first is the default.
+        #[structopt(long = "license", parse(try_from_str = "sla::parse_license"))]
+        license: Vec<License>,
+        /// rewrite the /.DS_Store of an HFS+ image from this layout, so the
+        /// background alias matches the volume
+        #[structopt(long = "layout", parse(from_os_str))]
+        layout: Option<std::path::PathBuf>,
+        /// write this icns into /.VolumeIcon.icns of an HFS+ image and
+        /// show it as the volume icon. The file must already be on the volume.
+        #[structopt(long = "volume-icon", parse(from_os_str))]
+        volume_icon: Option<std::path::PathBuf>,
+        /// mark a file or folder on an HFS+ image invisible in Finder. May be repeated.
+        #[structopt(long = "hide", raw(number_of_values = "1"))]
+        hide: Vec<String>,
+        /// pin every date written to this Unix timestamp, for reproducible
+        /// builds. Defaults to SOURCE_DATE_EPOCH if that is set. Only HFS+
+        /// images can be pinned, anything else is an error.
+        #[structopt(long = "timestamp")]
+        timestamp: Option<u64>,
+        /// also describe the partition with a classic resource fork, for
+        /// versions of Mac OS X older than 10.2
+        #[structopt(long = "resource-fork")]
+        resource_fork: bool,
+        /// image format: UDZO (the default), UDRO, UDBZ, ULFO or UDRW
+        #[structopt(long = "format", parse(try_from_str = "format::parse_format"))]
+        format: Option<format::Format>,
+        /// write the plist as a binary plist instead of XML, as newer
+        /// versions of hdiutil can
+        #[structopt(long = "binary-plist")]
+        binary_plist: bool,
+    },
+    #[structopt(name = "convert-format")]
+    /// Re-encode an existing DMG in another format, like hdiutil convert -format
+    ConvertFormat {
+        /// path to the DMG to convert
+        input: std::path::PathBuf,
+        /// where to write the converted DMG
+        output: std::path::PathBuf,
+        /// UDZO, UDRO, UDBZ, ULFO or UDRW
+        #[structopt(long = "format", parse(try_from_str = "format::parse_format"))]
+        format: format::Format,
+    },
+    #[structopt(name = "export")]
+    /// Write a DMG out as a flat, uncompressed disk image, e.g. .cdr (UDTO) or .img
+    Export {
+        /// path to a DMG file
+        file: std::path::PathBuf,
+        /// where to write the raw image
+        output: std::path::PathBuf,
+        /// only write the partition with this ID, e.g. the HFS+ volume
+        #[structopt(long = "partition")]
+        partition: Option<i32>,
+        /// write a .sparseimage, leaving out runs of zeros, instead of a flat image
+        #[structopt(long = "sparse")]
+        sparse: bool,
+        /// file containing the passphrase of an encrypted DMG
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// file containing the unwrapped key of an encrypted DMG, raw or as hex
+        #[structopt(long = "keyfile", parse(from_os_str))]
+        keyfile: Option<std::path::PathBuf>,
+    },
+    #[cfg(feature = "fuse")]
+    #[structopt(name = "mount")]
+    /// Mount the HFS+ or ISO 9660 volume in a DMG read-only with FUSE,
+    /// until it is unmounted with fusermount -u
+    Mount {
+        /// path to a DMG file
+        file: std::path::PathBuf,
+        /// folder to mount the volume on
+        mountpoint: std::path::PathBuf,
+        /// mount the partition with this ID, instead of the first one with a volume
+        #[structopt(long = "partition")]
+        partition: Option<i32>,
+        /// memory to keep decompressed chunks in (e.g. 256m), 64m by default
+        #[structopt(long = "cache-size", parse(try_from_str = "segment::parse_size"))]
+        cache_size: Option<u64>,
+        /// file containing the passphrase of an encrypted DMG
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// file containing the unwrapped key of an encrypted DMG, raw or as hex
+        #[structopt(long = "keyfile", parse(from_os_str))]
+        keyfile: Option<std::path::PathBuf>,
+    },
+    #[structopt(name = "serve-nbd")]
+    /// Serve the decompressed disk of a DMG read-only over NBD, e.g. for
+    /// nbd-client or qemu. Listens on 127.0.0.1 unless --socket is given.
+    ServeNbd {
+        /// path to a DMG, .sparseimage or .sparsebundle
+        file: std::path::PathBuf,
+        /// listen on this Unix domain socket instead of TCP
+        #[cfg(unix)]
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<std::path::PathBuf>,
+        /// TCP port to listen on, 10809 by default
+        #[structopt(long = "port")]
+        port: Option<u16>,
+        /// only serve the partition with this ID, e.g. the HFS+ volume
+        #[structopt(long = "partition")]
+        partition: Option<i32>,
+        /// memory to keep decompressed chunks in (e.g. 256m), 64m by default
+        #[structopt(long = "cache-size", parse(try_from_str = "segment::parse_size"))]
+        cache_size: Option<u64>,
+        /// file containing the passphrase of an encrypted DMG
+        #[structopt(long = "passphrase-file", parse(from_os_str))]
+        passphrase_file: Option<std::path::PathBuf>,
+        /// file containing the unwrapped key of an encrypted DMG, raw or as hex
+        #[structopt(long = "keyfile", parse(from_os_str))]
+        keyfile: Option<std::path::PathBuf>,
+    },
+    #[structopt(name = "write-ds-store")]
+    /// Write a .DS_Store describing the Finder window layout into a folder,
+    /// before the folder is turned into an image
+    WriteDsStore {
+        /// JSON or TOML layout configuration
+        layout: std::path::PathBuf,
+        /// folder that will become the root of the volume
+        folder: std::path::PathBuf,
+    },
+    #[structopt(name = "dump-ds-store")]
+    /// Print the records of a .DS_Store file as JSON
+    DumpDsStore {
+        /// path to a .DS_Store file
+        file: std::path::PathBuf,
+        /// print a layout configuration for write-ds-store instead
+        #[structopt(long = "layout")]
+        layout: bool,
     },
 }
 
@@ -42,45 +194,189 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::from_args();
 
     match args {
-        Cli::Inspect { file } => inspect(&file)?,
-        Cli::Convert { iso, dmg } => conversion(iso, dmg)?,
+        Cli::Inspect { file, passphrase_file, keyfile } => {
+            inspect(&file, read_unlock(passphrase_file, keyfile)?)?
+        }
+        Cli::Lint { file, passphrase_file, keyfile } => {
+            let findings = match (is_encrypted(&mut File::open(&file)?)?, read_unlock(passphrase_file, keyfile)?) {
+                (true, Some(unlock)) => lint::lint(&mut EncryptedReader::new(File::open(&file)?, &unlock)?)?,
+                (true, None) => return Err("DMG is encrypted, use --passphrase-file or --keyfile".into()),
+                (false, _) => lint::lint_path(&file)?,
+            };
+
+            for finding in &findings {
+                println!("{}", finding);
+            }
+            let errors = findings.iter().filter(|f| f.severity == lint::Severity::Error).count();
+            let warnings = findings.iter().filter(|f| f.severity == lint::Severity::Warning).count();
+            println!("{} errors, {} warnings", errors, warnings);
+            if errors > 0 {
+                return Err(format!("{} failed lint", file.display()).into());
+            }
+        }
+        Cli::Export { file, output, partition, sparse, passphrase_file, keyfile } => {
+            let written = match read_unlock(passphrase_file, keyfile)? {
+                Some(unlock) => export(&mut Dmg::open_encrypted(&file, &unlock)?, partition, sparse, &output)?,
+                None => export(&mut Dmg::open(&file)?, partition, sparse, &output)?,
+            };
+            println!("Wrote {} bytes", written);
+        }
+        #[cfg(feature = "fuse")]
+        Cli::Mount { file, mountpoint, partition, cache_size, passphrase_file, keyfile } => {
+            match read_unlock(passphrase_file, keyfile)? {
+                Some(unlock) => libdmg::mount::mount(with_cache(Dmg::open_encrypted(&file, &unlock)?, cache_size), partition, &mountpoint)?,
+                None => libdmg::mount::mount(with_cache(Dmg::open(&file)?, cache_size), partition, &mountpoint)?,
+            }
+        }
+        Cli::ServeNbd {
+            file,
+            #[cfg(unix)]
+            socket,
+            port,
+            partition,
+            cache_size,
+            passphrase_file,
+            keyfile,
+        } => {
+            let address = nbd::Address::Tcp(port.unwrap_or(nbd::DEFAULT_PORT));
+            #[cfg(unix)]
+            let address = socket.map(nbd::Address::Unix).unwrap_or(address);
+            if sparsebundle::is_sparse_bundle(&file) {
+                nbd::listen(&mut sparsebundle::SparseBundle::open(&file)?, &address)?;
+            } else if sparse::is_sparse(&mut File::open(&file)?)? {
+                nbd::listen(&mut sparse::SparseImage::new(File::open(&file)?)?, &address)?;
+            } else {
+                match read_unlock(passphrase_file, keyfile)? {
+                    Some(unlock) => serve_nbd(&mut with_cache(Dmg::open_encrypted(&file, &unlock)?, cache_size), partition, &address)?,
+                    None => serve_nbd(&mut with_cache(Dmg::open(&file)?, cache_size), partition, &address)?,
+                }
+            }
+        }
+        Cli::Convert { iso, dmg, segment_size, encrypt, passphrase_file, deterministic_encryption, license, layout, volume_icon, hide, timestamp, resource_fork, format, binary_plist } => {
+            let encryption = match (encrypt, passphrase_file) {
+                (Some(key_bits), Some(path)) => Some(Encryption {
+                    key_bits,
+                    passphrase: read_passphrase(&path)?,
+                    deterministic: deterministic_encryption,
+                }),
+                (Some(_), None) => return Err("--encrypt requires --passphrase-file".into()),
+                (None, _) => None,
+            };
+            let layout = match layout {
+                Some(path) => Some(Layout::from_file(&path)?),
+                None => None,
+            };
+            let volume_icon = match volume_icon {
+                Some(path) => Some(std::fs::read(path)?),
+                None => None,
+            };
+            conversion(iso, dmg, ConvertOptions {
+                segment_size,
+                encryption,
+                licenses: license,
+                layout,
+                volume_icon,
+                hidden: hide,
+                timestamp: match timestamp {
+                    Some(timestamp) => Some(timestamp),
+                    None => source_date_epoch()?,
+                },
+                resource_fork,
+                format,
+                binary_plist,
+            })?
+        }
+        Cli::ConvertFormat { input, output, format } => {
+            let summary = format::convert_format(&input, &output, format)?;
+            println!("{:#?}", summary);
+        }
+        Cli::WriteDsStore { layout, folder } => {
+            let mut layout = Layout::from_file(&layout)?;
+            if layout.volume_name.is_none() {
+                layout.volume_name = folder.file_name().map(|n| n.to_string_lossy().to_string());
+            }
+            let records = layout.records(None)?;
+            std::fs::write(folder.join(".DS_Store"), dsstore::write(records)?)?;
+        }
+        Cli::DumpDsStore { file, layout } => {
+            let records = dsstore::read(&std::fs::read(file)?)?;
+            let json = if layout {
+                serde_json::to_value(Layout::from_records(&records)?)?
+            } else {
+                serde_json::Value::Array(records.iter().map(dsstore::Record::to_json).collect())
+            };
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
     }
 
     Ok(())
 }
 
-fn inspect(file: &std::path::PathBuf) -> Result<(), io::Error> {
+/// SOURCE_DATE_EPOCH, see https://reproducible-builds.org/specs/source-date-epoch/
+fn source_date_epoch() -> Result<Option<u64>, String> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid SOURCE_DATE_EPOCH {:?}", value)),
+        Err(_) => Ok(None),
+    }
+}
 
-    // Open the file, and dump some metadata
-    let mut f = File::open(file)?;
+fn inspect(file: &std::path::Path, unlock: Option<Unlock>) -> Result<(), io::Error> {
+
+    // Open the file (and any .dmgpart segments), and dump some metadata.
+    // The koly block is read from the last 512 bytes of each segment.
     println!("Inspecting: {:#?}", file.file_name().expect("Could not retrieve file name.."));
 
-    // 13741856
-    //println!("{:#?}", f.metadata()?.len());
+    // sparse images and bundles hold a raw disk, there is no koly block or plist
+    if sparsebundle::is_sparse_bundle(file) {
+        let bundle = sparsebundle::SparseBundle::open(file)?;
+        println!("sparse bundle: {} bytes, in bands of {} bytes", bundle.size(), bundle.band_size());
+        return Ok(());
+    }
+    if sparse::is_sparse(&mut File::open(file)?)? {
+        let image = sparse::SparseImage::new(File::open(file)?)?;
+        println!("sparse image: {} bytes", image.size());
+        return Ok(());
+    }
 
-    // Seek -512 bytes from the end of the file,
-    // this should be the start of the "koly block"
-    f.seek(SeekFrom::End(-512))?;
+    // Encrypted images wrap the whole UDIF image, so decrypt first
+    // and then read the koly block and plist from the decrypted data.
+    if is_encrypted(&mut File::open(file)?)? {
+        let unlock = unlock.ok_or_else(|| io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "DMG is encrypted, use --passphrase-file or --keyfile",
+        ))?;
+
+        let dmg = Dmg::open_encrypted(file, &unlock)?;
+        println!("encryption: {:#?}", dmg.get_ref().header);
+        println!("udif: {:#?}", dmg.koly());
+        println!("parsed: {:#?}", dmg.plist());
+        print_licenses(dmg.plist());
+
+        return Ok(());
+    }
 
-    let mut koly_block = Vec::new();
-    f.read_to_end(&mut koly_block)?;
+    let dmg = Dmg::open(file)?;
 
-    let udif_res = KolyBlock::new(koly_block).expect("Could not read koly block!");
+    let udif_res = dmg.koly();
     println!("udif: {:#?}", udif_res);
 
-    // Once we've parsed the koly block, we can grab the xml length and offset
-    // and use that to extract and parse the plist xml.
-    f.seek(SeekFrom::Start(udif_res.xml_offset))?;
-
-    // 10667
-    println!("{:#?}", &udif_res.xml_length);
-
-    let mut plist = vec![0u8; udif_res.xml_length as usize];
-    f.read_exact(&mut plist)?;
-
-    let _parsed = PList::from_bytes(plist).unwrap();
+    if udif_res.segment_count > 1 {
+        let image = dmg.get_ref();
+        for segment in &image.segments {
+            println!(
+                "segment {} of {}: {:#?}, data fork length: {}",
+                segment.koly.segment_number, segment.koly.segment_count, segment.path, segment.koly.data_fork_length
+            );
+        }
+        println!("logical data fork length: {}", image.len());
+    }
 
-    println!("parsed: {:#?}", _parsed);
+    println!("parsed: {:#?}", dmg.plist());
+    print_licenses(dmg.plist());
 
     //println!("chunk 0: {:#?}", parsed.partitions[0]);
     // println!("chunk 1: {:#?}", parsed.partitions[0].data.block_entries[1]);
@@ -120,6 +416,67 @@ fn inspect(file: &std::path::PathBuf) -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Write a whole DMG, or one of its partitions, as a flat or sparse image.
+/// The partition is found before anything is written, and output is only
+/// replaced once the whole image has been written.
+fn export<R: io::Read + io::Seek>(dmg: &mut Dmg<R>, partition: Option<i32>, sparse: bool, output: &std::path::Path) -> Result<u64, io::Error> {
+    let mut disk = match partition {
+        Some(id) => dmg.open_partition(id)?,
+        None => dmg.open_disk(),
+    };
+
+    util::write_atomically(output, |out| match sparse {
+        true => {
+            let length = disk.len();
+            sparse::write_sparse(&mut disk, length, out)?;
+            io::Seek::seek(out, io::SeekFrom::End(0))
+        }
+        false => io::copy(&mut disk, out),
+    })
+}
+
+/// Give a DMG a chunk cache of this many bytes, if set
+fn with_cache<R: io::Read + io::Seek>(mut dmg: Dmg<R>, cache_size: Option<u64>) -> Dmg<R> {
+    if let Some(size) = cache_size {
+        dmg.cache().set_budget(size as usize);
+    }
+    dmg
+}
+
+/// Serve a whole DMG, or one of its partitions, over NBD
+fn serve_nbd<R: io::Read + io::Seek>(dmg: &mut Dmg<R>, partition: Option<i32>, address: &nbd::Address) -> Result<(), io::Error> {
+    let mut disk = match partition {
+        Some(id) => dmg.open_partition(id)?,
+        None => dmg.open_disk(),
+    };
+    nbd::listen(&mut disk, address)
+}
+
+/// Print the text of any license agreements
+fn print_licenses(plist: &PList) {
+    for (language, text) in licenses(&plist.resources) {
+        println!("license ({}):\n{}", language, text);
+    }
+}
+
+/// How to unlock an encrypted DMG, if either option was given
+fn read_unlock(passphrase_file: Option<std::path::PathBuf>, keyfile: Option<std::path::PathBuf>) -> Result<Option<Unlock>, io::Error> {
+    Ok(match (passphrase_file, keyfile) {
+        (Some(path), _) => Some(Unlock::Passphrase(read_passphrase(&path)?)),
+        (None, Some(path)) => Some(Unlock::Keyfile(std::fs::read(path)?)),
+        (None, None) => None,
+    })
+}
+
+/// Read a passphrase from a file, ignoring a trailing newline
+fn read_passphrase(path: &std::path::Path) -> Result<Vec<u8>, io::Error> {
+    let mut passphrase = std::fs::read(path)?;
+    while passphrase.last() == Some(&b'\n') || passphrase.last() == Some(&b'\r') {
+        passphrase.pop();
+    }
+    Ok(passphrase)
+}
+
 // fn find(search_in: Vec<u8>, for_bytes: Vec<u8>) -> usize {
 //     search_in.windows(for_bytes.len()).position(|x| x.to_vec() == for_bytes).unwrap()
 // }
@@ -5,13 +5,15 @@ use super::blkx::{BlkxChunkEntry, BLKX_CHUNK_ENTRY_SIZE};
 use base64::{decode};
 
 const MISH_MAGIC: &str = "0x6D697368";
+/// Bytes before the first blkx chunk entry
+const MISH_HEADER_SIZE: usize = 204;
 // used by libdmg-hfsplus, hdiutil just sets this to the partition number?
 //const ENTIRE_DEVICE_DESCRIPTOR: u32 = 4_294_967_294;
 
 /// Decoded from a base64 string
 /// All fields are in big endian ordering to maintain compatiblity
 /// with older versions of macOS.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MishBlock {
     /// Magic - "mish" in ASCII
     pub signature: u32,
@@ -44,31 +46,54 @@ pub struct MishBlock {
     pub number_block_chunks: u32,
     /// [ num_block_chunks * blkxChunkEntry (40 bytes each)]
     pub block_entries: Vec<BlkxChunkEntry>,
+    /// Anything after the block entries, kept so it is written back out
+    pub trailing: Vec<u8>,
 }
 
 impl MishBlock {
-    pub fn from_base64(encoded: String) -> Result<MishBlock, XMLError> {
+    pub fn from_base64(encoded: &str) -> Result<MishBlock, XMLError> {
         // trim leading and trailing whitespace, tabs and newlines
         let stripped = encoded.trim().replace("\t", "").replace("\n", "");
 
         let decoded = decode(&stripped)?;
 
         //println!("Decoded mish bytes: {:#?}", decoded[0..30].to_vec());
-        MishBlock::from_be_bytes(decoded)
+        MishBlock::from_be_bytes(&decoded)
     }
 
-    pub fn from_be_bytes(buffer: Vec<u8>) -> Result<MishBlock, XMLError> {
+    pub fn from_be_bytes(buffer: &[u8]) -> Result<MishBlock, XMLError> {
+        if buffer.len() < MISH_HEADER_SIZE {
+            return Err(XMLError::Mish(format!(
+                "Truncated mish block: {} bytes, the header alone is {}",
+                buffer.len(),
+                MISH_HEADER_SIZE
+            )));
+        }
+
         let signature = util::read_be_u32(&mut &buffer[0..4]);
 
         if format!("{:#X}", signature) != MISH_MAGIC {
             return Err(XMLError::Mish("Invalid mish magic bytes".to_string()));
         }
 
-        let build = MishBlock::build_block_entries(&buffer[204..]);
+        let number_block_chunks = util::read_be_u32(&mut &buffer[200..204]);
+        let entries_end = (number_block_chunks as usize)
+            .checked_mul(BLKX_CHUNK_ENTRY_SIZE)
+            .and_then(|length| length.checked_add(MISH_HEADER_SIZE))
+            .filter(|end| *end <= buffer.len())
+            .ok_or_else(|| {
+                XMLError::Mish(format!(
+                    "Truncated mish block: {} block entries declared, but only room for {}",
+                    number_block_chunks,
+                    (buffer.len() - MISH_HEADER_SIZE) / BLKX_CHUNK_ENTRY_SIZE
+                ))
+            })?;
+
+        let build = MishBlock::build_block_entries(&buffer[MISH_HEADER_SIZE..entries_end]);
 
         let block_entries = match build {
             Ok(entries) => entries,
-            Err(_e) => return Err(XMLError::Mish("Could not build block entries".to_string())),
+            Err(e) => return Err(XMLError::Mish(format!("Could not build block entries: {}", e))),
         };
 
         Ok(MishBlock {
@@ -94,8 +119,9 @@ impl MishBlock {
                 data: buffer[72..200].to_vec(),
             },
 
-            number_block_chunks: util::read_be_u32(&mut &buffer[200..204]),
+            number_block_chunks,
             block_entries,
+            trailing: buffer[entries_end..].to_vec(),
         })
     }
 
@@ -138,6 +164,7 @@ impl MishBlock {
         //assert!(buffer.len() == 204);
         let mut block_entries: Vec<u8> = self.block_entries.into_iter().flat_map(|block| block.to_be_bytes()).collect();
         buffer.append(&mut block_entries);
+        buffer.extend_from_slice(&self.trailing);
 
         buffer
     }
@@ -145,7 +172,90 @@ impl MishBlock {
     fn build_block_entries(buffer: &[u8]) -> Result<Vec<BlkxChunkEntry>, XMLError> {
         buffer
             .chunks_exact(BLKX_CHUNK_ENTRY_SIZE)
-            .map(|c| BlkxChunkEntry::new(c))
+            .map(BlkxChunkEntry::new)
             .collect()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blkx::DmgBlxx;
+    use crate::convert::{build_mish, final_blkx};
+
+    fn mish() -> MishBlock {
+        let raw = BlkxChunkEntry {
+            entry_type: DmgBlxx::RawOrNullCompression,
+            comment: 0,
+            sector_number: 0,
+            sector_count: 8,
+            compressed_offset: 0,
+            compressed_length: 8 * 512,
+        };
+        build_mish(8, vec![raw, final_blkx(8, 8 * 512)])
+    }
+
+    fn error(buffer: &[u8]) -> String {
+        match MishBlock::from_be_bytes(buffer) {
+            Err(XMLError::Mish(message)) => message,
+            other => panic!("Expected a mish error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = mish().to_be_bytes();
+        assert_eq!(bytes.len(), MISH_HEADER_SIZE + 2 * BLKX_CHUNK_ENTRY_SIZE);
+
+        let parsed = MishBlock::from_be_bytes(&bytes).unwrap();
+        assert_eq!(parsed.number_block_chunks, 2);
+        assert_eq!(parsed.block_entries.len(), 2);
+        assert_eq!((parsed.sector_count, parsed.block_entries[0].compressed_length), (8, 8 * 512));
+        assert!(parsed.trailing.is_empty());
+        assert_eq!(parsed.to_be_bytes(), bytes);
+    }
+
+    #[test]
+    fn keeps_trailing_bytes() {
+        // less than a whole entry, and more than one
+        for length in [1, 60] {
+            let mut bytes = mish().to_be_bytes();
+            bytes.extend((0..length).map(|i| i as u8));
+
+            let parsed = MishBlock::from_be_bytes(&bytes).unwrap();
+            assert_eq!(parsed.block_entries.len(), 2);
+            assert_eq!(parsed.trailing.len(), length);
+            assert_eq!(parsed.to_be_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn truncated_header() {
+        let bytes = mish().to_be_bytes();
+        assert_eq!(error(&[]), "Truncated mish block: 0 bytes, the header alone is 204");
+        assert_eq!(error(&bytes[..203]), "Truncated mish block: 203 bytes, the header alone is 204");
+    }
+
+    #[test]
+    fn count_past_the_buffer() {
+        let mut bytes = mish().to_be_bytes();
+        bytes[200..204].copy_from_slice(&3u32.to_be_bytes());
+        assert_eq!(error(&bytes), "Truncated mish block: 3 block entries declared, but only room for 2");
+
+        // large enough to overflow the length of the entries
+        bytes[200..204].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(error(&bytes), format!("Truncated mish block: {} block entries declared, but only room for 2", u32::MAX));
+
+        // an entry cut short
+        let mut bytes = mish().to_be_bytes();
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(error(&bytes), "Truncated mish block: 2 block entries declared, but only room for 1");
+    }
+
+    #[test]
+    fn invalid_magic() {
+        let mut bytes = mish().to_be_bytes();
+        bytes[0] = b'M';
+        assert_eq!(error(&bytes), "Invalid mish magic bytes");
+    }
+}
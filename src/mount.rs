@@ -0,0 +1,187 @@
+use std::ffi::OsStr;
+use std::io::{self, prelude::{Read, Seek}};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
+
+use super::dmg::Dmg;
+use super::volume::{Node, NodeKind, Volume};
+
+/// The image never changes, so the kernel can cache for as long as it likes
+const TTL: Duration = Duration::from_secs(3600);
+
+/// FUSE numbers the root 1, we number it 0 in Volume::nodes
+const ROOT_INODE: u64 = 1;
+
+/// Mount the HFS+ or ISO 9660 volume in a DMG read-only at mountpoint,
+/// until it is unmounted (e.g. with fusermount -u). Without a partition
+/// ID, the first partition holding a volume we can read is used.
+pub fn mount<R: Read + Seek>(mut dmg: Dmg<R>, partition: Option<i32>, mountpoint: &Path) -> Result<(), io::Error> {
+    let ids: Vec<i32> = match partition {
+        Some(id) => vec![id],
+        None => dmg.partitions().map(|p| p.id).collect(),
+    };
+
+    let mut errors = Vec::new();
+    for id in ids {
+        match Volume::read(&mut dmg.open_partition(id)?) {
+            Ok(volume) => {
+                println!("Mounting {:?} (partition {}) at {}", volume.name, id, mountpoint.display());
+                let options = [
+                    MountOption::RO,
+                    MountOption::FSName(volume.name.clone()),
+                    MountOption::Subtype(String::from("libdmg")),
+                ];
+                let metadata = std::fs::metadata(mountpoint)?;
+                let owner = (metadata.uid(), metadata.gid());
+                return fuser::mount2(DmgFilesystem { dmg, partition: id, volume, owner }, mountpoint, &options);
+            }
+            Err(e) => errors.push(format!("partition {}: {}", id, e)),
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, format!("No volume to mount: {}", errors.join("; "))))
+}
+
+/// A read-only file system over one partition of a DMG. File data is
+/// decompressed chunk by chunk as it is read.
+struct DmgFilesystem<R> {
+    dmg: Dmg<R>,
+    partition: i32,
+    volume: Volume,
+    /// uid and gid every node is owned by, as the IDs on the image mean
+    /// nothing here. Taken from the mountpoint.
+    owner: (u32, u32),
+}
+
+impl<R: Read + Seek> DmgFilesystem<R> {
+    fn node(&self, inode: u64) -> Option<&Node> {
+        self.volume.nodes.get(index(inode)?)
+    }
+
+    fn attributes(&self, inode: u64, node: &Node) -> FileAttr {
+        let modified = UNIX_EPOCH + Duration::from_secs(node.modified);
+        let (kind, size) = match &node.kind {
+            NodeKind::Directory => (FileType::Directory, 0),
+            NodeKind::File => (FileType::RegularFile, node.size),
+            NodeKind::Symlink(target) => (FileType::Symlink, target.len() as u64),
+        };
+
+        FileAttr {
+            ino: inode,
+            size,
+            blocks: size.div_ceil(512),
+            atime: modified,
+            mtime: modified,
+            ctime: modified,
+            crtime: modified,
+            kind,
+            perm: node.mode,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.owner.0,
+            gid: self.owner.1,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Filesystem for DmgFilesystem<R> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = index(parent).and_then(|parent| {
+            self.volume.children(parent).find(|(_, node)| OsStr::new(&node.name) == name)
+        });
+
+        match found {
+            Some((index, node)) => reply.entry(&TTL, &self.attributes(inode(index), node), 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attributes(ino, node)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino).map(|n| &n.kind) {
+            Some(NodeKind::Symlink(target)) => reply.data(target.as_bytes()),
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        // borrow the fields separately, the partition reader needs dmg mutably
+        let DmgFilesystem { dmg, partition, volume, .. } = self;
+        let node = match index(ino).and_then(|i| volume.nodes.get(i)) {
+            Some(node) if node.kind == NodeKind::File => node,
+            Some(_) => return reply.error(libc::EISDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let data = dmg
+            .open_partition(*partition)
+            .map_err(|e| e.to_string())
+            .and_then(|mut partition| volume.read_node(&mut partition, node, offset.max(0) as u64, u64::from(size)));
+
+        match data {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                eprintln!("Reading {}: {}", node.name, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let parent = match self.node(ino) {
+            Some(node) if node.kind == NodeKind::Directory => node.parent,
+            Some(_) => return reply.error(libc::ENOTDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+
+        let entries = vec![(ino, FileType::Directory, String::from(".")), (inode(parent), FileType::Directory, String::from(".."))];
+        let children = self.volume.children(index(ino).expect("Node exists")).map(|(index, node)| {
+            let kind = match node.kind {
+                NodeKind::Directory => FileType::Directory,
+                NodeKind::File => FileType::RegularFile,
+                NodeKind::Symlink(_) => FileType::Symlink,
+            };
+            (inode(index), kind, node.name.clone())
+        });
+
+        // offset is the position of the last entry returned, plus one
+        for (position, (inode, kind, name)) in entries.into_iter().chain(children).enumerate().skip(offset.max(0) as usize) {
+            if reply.add(inode, position as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Index into Volume::nodes of a FUSE inode number
+fn index(inode: u64) -> Option<usize> {
+    inode.checked_sub(ROOT_INODE).map(|i| i as usize)
+}
+
+/// FUSE inode number of an index into Volume::nodes
+fn inode(index: usize) -> u64 {
+    index as u64 + ROOT_INODE
+}
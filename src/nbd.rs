@@ -0,0 +1,470 @@
+use std::io::{self, prelude::{Read, Write}, SeekFrom};
+use std::net::{Ipv4Addr, TcpListener};
+
+use super::disk::VirtualDisk;
+
+/// "NBDMAGIC" then "IHAVEOPT", opening the fixed newstyle handshake
+const NBD_MAGIC: u64 = 0x4E42_444D_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454F_5054;
+
+/// Magic numbers of option replies, requests and simple replies
+const OPTION_REPLY_MAGIC: u64 = 0x0003_E889_0455_65A9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+/// Handshake flags: fixed newstyle, and no 124 bytes of zeros after NBD_OPT_EXPORT_NAME
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+
+/// Transmission flags: flags are valid, and the export is read-only
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+
+/// Options sent by the client during the handshake
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+/// Option reply types
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_REP_ERR_INVALID: u32 = (1 << 31) + 3;
+
+/// NBD_REP_INFO type carrying the size and transmission flags
+const NBD_INFO_EXPORT: u16 = 0;
+
+/// Request types
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+/// Errors returned in simple replies, with their errno values
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_EINVAL: u32 = 22;
+
+/// The port registered for NBD
+pub const DEFAULT_PORT: u16 = 10809;
+
+/// Reads larger than this are refused, as the kernel client never sends them
+const MAX_READ: u32 = 32 * 1024 * 1024;
+
+/// Where to listen for NBD clients
+#[derive(Debug, Clone)]
+pub enum Address {
+    /// A TCP port on 127.0.0.1
+    Tcp(u16),
+    /// A Unix domain socket, created at this path
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+/// Serve disk read-only over NBD until the process is stopped. Clients
+/// are served one at a time, under any export name; errors from one
+/// client are reported and the next one is accepted.
+pub fn listen<D: VirtualDisk>(disk: &mut D, address: &Address) -> Result<(), io::Error> {
+    match address {
+        Address::Tcp(port) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, *port))?;
+            println!("Serving {} bytes on nbd://127.0.0.1:{}", disk.size(), port);
+            for stream in listener.incoming() {
+                let stream = stream?;
+                // replies are written whole, don't hold them back
+                stream.set_nodelay(true)?;
+                report(serve(disk, stream));
+            }
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+            use std::os::unix::net::UnixListener;
+
+            // a socket left behind by a previous run
+            if std::fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            println!("Serving {} bytes on nbd+unix:///?socket={}", disk.size(), path.display());
+            for stream in listener.incoming() {
+                report(serve(disk, stream?));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn report(result: Result<(), io::Error>) {
+    match result {
+        Ok(()) => println!("Client disconnected"),
+        Err(e) => eprintln!("Client disconnected: {}", e),
+    }
+}
+
+/// Handshake with, then answer the requests of, a single client
+pub fn serve<D: VirtualDisk, S: Read + Write>(disk: &mut D, mut stream: S) -> Result<(), io::Error> {
+    if negotiate(disk.size(), &mut stream)? {
+        transmit(disk, &mut stream)?;
+    }
+    Ok(())
+}
+
+/// The fixed newstyle handshake. Returns whether the client went on to
+/// the transmission phase, rather than aborting.
+fn negotiate<S: Read + Write>(size: u64, stream: &mut S) -> Result<bool, io::Error> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let transmission_flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY;
+
+    let mut greeting = NBD_MAGIC.to_be_bytes().to_vec();
+    greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&greeting)?;
+    stream.flush()?;
+
+    let client_flags = read_u32(stream)?;
+    if u32::from(NBD_FLAG_FIXED_NEWSTYLE) & client_flags == 0 {
+        return Err(invalid("Client does not support the fixed newstyle handshake"));
+    }
+    let no_zeroes = u32::from(NBD_FLAG_NO_ZEROES) & client_flags != 0;
+
+    loop {
+        if read_u64(stream)? != IHAVEOPT {
+            return Err(invalid("Invalid NBD option magic"));
+        }
+        let option = read_u32(stream)?;
+        let length = read_u32(stream)?;
+        if length > 4096 {
+            return Err(invalid("NBD option data is too long"));
+        }
+        let mut data = vec![0u8; length as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            // the old way in, with no way to report an error
+            NBD_OPT_EXPORT_NAME => {
+                let mut reply = size.to_be_bytes().to_vec();
+                reply.extend_from_slice(&transmission_flags.to_be_bytes());
+                if !no_zeroes {
+                    reply.extend_from_slice(&[0u8; 124]);
+                }
+                stream.write_all(&reply)?;
+                stream.flush()?;
+                return Ok(true);
+            }
+            NBD_OPT_ABORT => {
+                option_reply(stream, option, NBD_REP_ACK, &[])?;
+                return Ok(false);
+            }
+            // a single export, with an empty name
+            NBD_OPT_LIST => {
+                option_reply(stream, option, NBD_REP_SERVER, &0u32.to_be_bytes())?;
+                option_reply(stream, option, NBD_REP_ACK, &[])?;
+            }
+            NBD_OPT_INFO | NBD_OPT_GO => {
+                // name length, name, number of info requests, info requests
+                let name_length = data.get(0..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+                if name_length.map(|n| data.len() < 4 + n + 2).unwrap_or(true) {
+                    option_reply(stream, option, NBD_REP_ERR_INVALID, &[])?;
+                    continue;
+                }
+
+                let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&size.to_be_bytes());
+                info.extend_from_slice(&transmission_flags.to_be_bytes());
+                option_reply(stream, option, NBD_REP_INFO, &info)?;
+                option_reply(stream, option, NBD_REP_ACK, &[])?;
+
+                if option == NBD_OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn option_reply<S: Write>(stream: &mut S, option: u32, reply: u32, data: &[u8]) -> Result<(), io::Error> {
+    let mut buffer = Vec::with_capacity(20 + data.len());
+    buffer.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+    buffer.extend_from_slice(&option.to_be_bytes());
+    buffer.extend_from_slice(&reply.to_be_bytes());
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(data);
+    stream.write_all(&buffer)?;
+    stream.flush()
+}
+
+/// Answer requests until the client disconnects
+fn transmit<D: VirtualDisk, S: Read + Write>(disk: &mut D, stream: &mut S) -> Result<(), io::Error> {
+    let size = disk.size();
+
+    loop {
+        let mut request = [0u8; 28];
+        match stream.read_exact(&mut request) {
+            // clients may hang up without NBD_CMD_DISC
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        if read_u32(&mut &request[0..4])? != REQUEST_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid NBD request magic"));
+        }
+        let kind = u16::from_be_bytes([request[6], request[7]]);
+        let handle = &request[8..16];
+        let offset = read_u64(&mut &request[16..24])?;
+        let length = read_u32(&mut &request[24..28])?;
+
+        match kind {
+            NBD_CMD_READ => {
+                if length > MAX_READ || offset.checked_add(u64::from(length)).map(|end| end > size).unwrap_or(true) {
+                    simple_reply(stream, NBD_EINVAL, handle, &[])?;
+                    continue;
+                }
+
+                let mut data = vec![0u8; length as usize];
+                match disk.seek(SeekFrom::Start(offset)).and_then(|_| disk.read_exact(&mut data)) {
+                    Ok(()) => simple_reply(stream, 0, handle, &data)?,
+                    Err(e) => {
+                        eprintln!("Reading {} bytes at {}: {}", length, offset, e);
+                        simple_reply(stream, NBD_EIO, handle, &[])?;
+                    }
+                }
+            }
+            NBD_CMD_WRITE => {
+                // the data follows the request, and has to be skipped
+                io::copy(&mut Read::by_ref(stream).take(u64::from(length)), &mut io::sink())?;
+                simple_reply(stream, NBD_EPERM, handle, &[])?;
+            }
+            NBD_CMD_DISC => return Ok(()),
+            NBD_CMD_FLUSH => simple_reply(stream, 0, handle, &[])?,
+            _ => simple_reply(stream, NBD_EINVAL, handle, &[])?,
+        }
+    }
+}
+
+fn simple_reply<S: Write>(stream: &mut S, error: u32, handle: &[u8], data: &[u8]) -> Result<(), io::Error> {
+    let mut buffer = Vec::with_capacity(16 + data.len());
+    buffer.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    buffer.extend_from_slice(&error.to_be_bytes());
+    buffer.extend_from_slice(handle);
+    buffer.extend_from_slice(data);
+    stream.write_all(&buffer)?;
+    stream.flush()
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, io::Error> {
+    let mut buffer = [0u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, io::Error> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_be_bytes(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek};
+
+    struct Memory(Cursor<Vec<u8>>);
+
+    impl Read for Memory {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Seek for Memory {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl VirtualDisk for Memory {
+        fn size(&self) -> u64 {
+            self.0.get_ref().len() as u64
+        }
+    }
+
+    /// A client that has sent everything up front, and what the server replied
+    struct Client {
+        sent: Cursor<Vec<u8>>,
+        received: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.sent.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn disk() -> Memory {
+        Memory(Cursor::new((0..8192u32).map(|i| (i % 251) as u8).collect()))
+    }
+
+    fn option(option: u32, data: &[u8]) -> Vec<u8> {
+        let mut buffer = IHAVEOPT.to_be_bytes().to_vec();
+        buffer.extend_from_slice(&option.to_be_bytes());
+        buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    fn request(kind: u16, handle: u64, offset: u64, length: u32) -> Vec<u8> {
+        let mut buffer = REQUEST_MAGIC.to_be_bytes().to_vec();
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&kind.to_be_bytes());
+        buffer.extend_from_slice(&handle.to_be_bytes());
+        buffer.extend_from_slice(&offset.to_be_bytes());
+        buffer.extend_from_slice(&length.to_be_bytes());
+        buffer
+    }
+
+    fn serve_client(sent: Vec<u8>) -> (Result<(), io::Error>, Vec<u8>) {
+        let mut client = Client { sent: Cursor::new(sent), received: Vec::new() };
+        let result = serve(&mut disk(), &mut client);
+        (result, client.received)
+    }
+
+    /// Take length bytes from the front of received
+    fn take<'a>(received: &mut &'a [u8], length: usize) -> &'a [u8] {
+        let (taken, rest) = received.split_at(length);
+        *received = rest;
+        taken
+    }
+
+    /// Check an option reply, returning its data
+    fn option_reply<'a>(received: &mut &'a [u8], option: u32, reply: u32) -> &'a [u8] {
+        assert_eq!(take(received, 8), OPTION_REPLY_MAGIC.to_be_bytes());
+        assert_eq!(take(received, 4), option.to_be_bytes());
+        assert_eq!(take(received, 4), reply.to_be_bytes());
+        let length = read_u32(&mut take(received, 4)).unwrap() as usize;
+        take(received, length)
+    }
+
+    /// Check a simple reply, returning its error
+    fn simple_reply(received: &mut &[u8], handle: u64) -> u32 {
+        assert_eq!(take(received, 4), SIMPLE_REPLY_MAGIC.to_be_bytes());
+        let error = read_u32(&mut take(received, 4)).unwrap();
+        assert_eq!(take(received, 8), handle.to_be_bytes());
+        error
+    }
+
+    fn greeting(received: &mut &[u8]) {
+        assert_eq!(take(received, 8), NBD_MAGIC.to_be_bytes());
+        assert_eq!(take(received, 8), IHAVEOPT.to_be_bytes());
+        assert_eq!(take(received, 2), (NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+    }
+
+    #[test]
+    fn go_and_read() {
+        let flags = u32::from(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES);
+        let mut sent = flags.to_be_bytes().to_vec();
+        sent.append(&mut option(NBD_OPT_LIST, &[]));
+        sent.append(&mut option(99, &[]));
+        // an empty name, and no info requests
+        sent.append(&mut option(NBD_OPT_GO, &[0; 6]));
+        sent.append(&mut request(NBD_CMD_READ, 1, 1000, 3000));
+        sent.append(&mut request(NBD_CMD_READ, 2, 8000, 200));
+        sent.append(&mut request(NBD_CMD_WRITE, 3, 0, 4));
+        sent.extend_from_slice(&[0xFF; 4]);
+        sent.append(&mut request(NBD_CMD_FLUSH, 4, 0, 0));
+        sent.append(&mut request(NBD_CMD_DISC, 5, 0, 0));
+
+        let (result, received) = serve_client(sent);
+        result.unwrap();
+        let received = &mut &received[..];
+
+        greeting(received);
+        assert_eq!(option_reply(received, NBD_OPT_LIST, NBD_REP_SERVER), 0u32.to_be_bytes());
+        option_reply(received, NBD_OPT_LIST, NBD_REP_ACK);
+        option_reply(received, 99, NBD_REP_ERR_UNSUP);
+
+        let info = option_reply(received, NBD_OPT_GO, NBD_REP_INFO);
+        let mut expected = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+        expected.extend_from_slice(&8192u64.to_be_bytes());
+        expected.extend_from_slice(&(NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY).to_be_bytes());
+        assert_eq!(info, &expected[..]);
+        option_reply(received, NBD_OPT_GO, NBD_REP_ACK);
+
+        assert_eq!(simple_reply(received, 1), 0);
+        assert_eq!(take(received, 3000), &disk().0.into_inner()[1000..4000]);
+        // past the end of the disk
+        assert_eq!(simple_reply(received, 2), NBD_EINVAL);
+        // read-only, but the data is skipped so the next request is read
+        assert_eq!(simple_reply(received, 3), NBD_EPERM);
+        assert_eq!(simple_reply(received, 4), 0);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn export_name() {
+        // no NBD_FLAG_NO_ZEROES, and the client hangs up without NBD_CMD_DISC
+        let mut sent = u32::from(NBD_FLAG_FIXED_NEWSTYLE).to_be_bytes().to_vec();
+        sent.append(&mut option(NBD_OPT_EXPORT_NAME, b"disk"));
+
+        let (result, received) = serve_client(sent);
+        result.unwrap();
+        let received = &mut &received[..];
+
+        greeting(received);
+        assert_eq!(take(received, 8), 8192u64.to_be_bytes());
+        assert_eq!(take(received, 2), (NBD_FLAG_HAS_FLAGS | NBD_FLAG_READ_ONLY).to_be_bytes());
+        assert_eq!(take(received, 124), [0u8; 124]);
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn abort_and_errors() {
+        let flags = u32::from(NBD_FLAG_FIXED_NEWSTYLE).to_be_bytes().to_vec();
+
+        let mut sent = flags.clone();
+        // a name longer than the option data
+        sent.append(&mut option(NBD_OPT_INFO, &[0, 0, 0, 9]));
+        sent.append(&mut option(NBD_OPT_ABORT, &[]));
+        let (result, received) = serve_client(sent);
+        result.unwrap();
+        let received = &mut &received[..];
+        greeting(received);
+        option_reply(received, NBD_OPT_INFO, NBD_REP_ERR_INVALID);
+        option_reply(received, NBD_OPT_ABORT, NBD_REP_ACK);
+        assert!(received.is_empty());
+
+        // an old style client
+        let (result, _) = serve_client(0u32.to_be_bytes().to_vec());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // option data too long to buffer
+        let mut sent = flags.clone();
+        sent.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        sent.extend_from_slice(&NBD_OPT_GO.to_be_bytes());
+        sent.extend_from_slice(&u32::MAX.to_be_bytes());
+        let (result, _) = serve_client(sent);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // a request without the magic
+        let mut sent = flags;
+        sent.append(&mut option(NBD_OPT_EXPORT_NAME, &[]));
+        sent.extend_from_slice(&[0u8; 28]);
+        let (result, _) = serve_client(sent);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
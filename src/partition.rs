@@ -1,8 +1,9 @@
 use super::xml::XMLError;
 use super::mish::MishBlock;
+use super::resource::ResourceEntry;
 
 /// Describes a GPT partition
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PartitionEntry {
     /// Some attributes as a hex string. Generally 0x0050 ?
     pub attributes: String,
@@ -21,32 +22,57 @@ impl PartitionEntry {
         let children = &element.children;
 
         // TODO: extract strings and turn static?
-        let attributes = PartitionEntry::find_index_for(String::from("Attributes"), &children)?;
-        let cf_name = String::from("whatever"); // PartitionEntry::find_index_for(String::from("CFName"), &children)?;
-        let data = PartitionEntry::find_index_for(String::from("Data"), &children)?;
+        let attributes = PartitionEntry::find_index_for(String::from("Attributes"), children)?;
+        let cf_name = String::from("whatever"); // PartitionEntry::find_index_for(String::from("CFName"), children)?;
+        let data = PartitionEntry::find_index_for(String::from("Data"), children)?;
         // TODO: yuck?
-        let id: i32 = PartitionEntry::find_index_for(String::from("ID"), &children)?
+        let id = PartitionEntry::find_index_for(String::from("ID"), children)?;
+        let id: i32 = id
             .parse()
-            .unwrap();
-        let name = PartitionEntry::find_index_for(String::from("Name"), &children)?;
+            .map_err(|_| XMLError::Partition(format!("Invalid partition ID {:?}", id)))?;
+        let name = PartitionEntry::find_index_for(String::from("Name"), children)?;
 
         Ok(PartitionEntry {
             attributes,
             cf_name,
-            data: MishBlock::from_base64(data)?,
+            data: MishBlock::from_base64(&data)?,
             id,
             name,
         })
     }
 
+    /// A partition from the blkx resource of a classic resource fork
+    pub fn from_resource(resource: &ResourceEntry) -> Result<PartitionEntry, XMLError> {
+        Ok(PartitionEntry {
+            attributes: resource.attributes.clone(),
+            cf_name: resource.name.clone(),
+            data: MishBlock::from_be_bytes(&resource.data)?,
+            id: resource.id,
+            name: resource.name.clone(),
+        })
+    }
+
+    /// The resource describing this partition, as stored in a resource
+    /// fork or binary plist
+    pub fn to_resource(&self) -> ResourceEntry {
+        ResourceEntry {
+            attributes: self.attributes.clone(),
+            data: self.data.clone().to_be_bytes(),
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+
     fn find_index_for(key: String, elements: &[xmltree::Element]) -> Result<String, XMLError> {
         let key_index = elements
             .iter()
             .position(|x| x.text == Some(key.clone()))
-            .unwrap();
+            .ok_or_else(|| XMLError::Partition(format!("Could not find {} key", key)))?;
 
         // This assumes that the XML elements are always ordered correctly
-        let value = &elements[key_index + 1];
+        let value = elements
+            .get(key_index + 1)
+            .ok_or_else(|| XMLError::Partition(format!("No value for {} key", key)))?;
 
         match &value.text {
             Some(text) => Ok(text.to_string()),
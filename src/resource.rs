@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use super::bplist::Value;
+use super::xml::{ElementType, PList, XMLError};
+
+use base64::{decode, encode};
+
+/// A resource from the resource-fork dictionary of the plist, such as
+/// an LPic, STR# or TEXT entry. blkx entries are parsed separately,
+/// see PartitionEntry.
+#[derive(Debug, Clone)]
+pub struct ResourceEntry {
+    /// Attributes as a hex string, usually 0x0000
+    pub attributes: String,
+    /// Raw resource data, base64 encoded in the plist
+    pub data: Vec<u8>,
+    /// Resource ID, e.g. 5000
+    pub id: i32,
+    /// Resource name, may be empty
+    pub name: String,
+}
+
+impl ResourceEntry {
+    pub fn new(element: &xmltree::Element) -> Result<ResourceEntry, XMLError> {
+        let children = &element.children;
+
+        let attributes = ResourceEntry::find_index_for("Attributes", children).unwrap_or_default();
+        let data = ResourceEntry::find_index_for("Data", children)
+            .ok_or_else(|| XMLError::Resource("Resource has no Data".to_string()))?;
+        let id = ResourceEntry::find_index_for("ID", children)
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| XMLError::Resource("Resource has no valid ID".to_string()))?;
+        let name = ResourceEntry::find_index_for("Name", children).unwrap_or_default();
+
+        // trim leading and trailing whitespace, tabs and newlines
+        let stripped = data.trim().replace("\t", "").replace("\n", "");
+
+        Ok(ResourceEntry {
+            attributes,
+            data: decode(&stripped)?,
+            id,
+            name,
+        })
+    }
+
+    /// Read a resource from a dictionary in a binary plist
+    pub fn from_value(value: &Value) -> Result<ResourceEntry, XMLError> {
+        let dict = match value {
+            Value::Dictionary(dict) => dict,
+            _ => return Err(XMLError::Resource("Resource is not a dictionary".to_string())),
+        };
+
+        let string = |key: &str| match dict.get(key) {
+            Some(Value::String(string)) => string.clone(),
+            _ => String::new(),
+        };
+
+        let data = match dict.get("Data") {
+            Some(Value::Data(data)) => data.clone(),
+            _ => return Err(XMLError::Resource("Resource has no Data".to_string())),
+        };
+        // hdiutil writes the ID as a string, but be lenient
+        let id = match dict.get("ID") {
+            Some(Value::Integer(id)) => Some(*id as i32),
+            Some(Value::String(id)) => id.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| XMLError::Resource("Resource has no valid ID".to_string()))?;
+
+        Ok(ResourceEntry {
+            attributes: string("Attributes"),
+            data,
+            id,
+            name: string("Name"),
+        })
+    }
+
+    /// Build the binary plist dictionary for this resource
+    pub fn to_value(&self) -> Value {
+        let mut dict = BTreeMap::new();
+        dict.insert(String::from("Attributes"), Value::String(self.attributes.clone()));
+        dict.insert(String::from("Data"), Value::Data(self.data.clone()));
+        dict.insert(String::from("ID"), Value::String(self.id.to_string()));
+        dict.insert(String::from("Name"), Value::String(self.name.clone()));
+        Value::Dictionary(dict)
+    }
+
+    /// Build the plist dictionary for this resource
+    pub fn to_element(&self) -> xmltree::Element {
+        let mut dict = xmltree::Element::new(ElementType::DictElm.to_str());
+
+        dict.children.push(PList::component(ElementType::KeyElm, String::from("Attributes")));
+        dict.children.push(PList::component(ElementType::StringElm, self.attributes.clone()));
+        dict.children.push(PList::component(ElementType::KeyElm, String::from("Data")));
+        dict.children.push(PList::component(ElementType::DataElm, encode(&self.data)));
+        dict.children.push(PList::component(ElementType::KeyElm, String::from("ID")));
+        dict.children.push(PList::component(ElementType::StringElm, self.id.to_string()));
+        dict.children.push(PList::component(ElementType::KeyElm, String::from("Name")));
+        dict.children.push(PList::component(ElementType::StringElm, self.name.clone()));
+
+        dict
+    }
+
+    // Unlike PartitionEntry, a missing or empty value isn't an error here,
+    // resources commonly have an empty Name.
+    fn find_index_for(key: &str, elements: &[xmltree::Element]) -> Option<String> {
+        let key_index = elements
+            .iter()
+            .position(|x| x.text.as_deref() == Some(key))?;
+
+        elements
+            .get(key_index + 1)
+            .map(|value| value.text.clone().unwrap_or_default())
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use super::resource::ResourceEntry;
+use super::util;
+use super::xml::XMLError;
+
+/// Where the resource data starts, after the header and the
+/// 240 bytes reserved for the system and application
+const DATA_OFFSET: usize = 256;
+
+/// Header copy, next map handle, file reference number and attributes
+const MAP_HEADER_SIZE: usize = 28;
+
+/// Type code, resource count - 1 and reference list offset
+const TYPE_ENTRY_SIZE: usize = 8;
+
+/// ID, name offset, attributes, data offset and handle
+const REFERENCE_ENTRY_SIZE: usize = 12;
+
+/// Parse a classic Mac OS resource fork, as used instead of the XML plist
+/// by the earliest UDIF images. Resources are grouped by type, e.g. blkx.
+pub fn parse(fork: &[u8]) -> Result<BTreeMap<String, Vec<ResourceEntry>>, XMLError> {
+    let truncated = || XMLError::Resource("Truncated resource fork".to_string());
+    let read_u16 = |offset: usize| fork.get(offset..offset + 2).map(|b| util::read_be_u16(&mut &b[..])).ok_or_else(truncated);
+    let read_u32 = |offset: usize| fork.get(offset..offset + 4).map(|b| util::read_be_u32(&mut &b[..])).ok_or_else(truncated);
+
+    let data_offset = read_u32(0)? as usize;
+    let map_offset = read_u32(4)? as usize;
+
+    let type_list = map_offset + read_u16(map_offset + 24)? as usize;
+    let name_list = map_offset + read_u16(map_offset + 26)? as usize;
+
+    // counts are stored minus one, so an empty list is 0xFFFF
+    let type_count = (read_u16(type_list)? as usize + 1) & 0xFFFF;
+
+    let mut resources = BTreeMap::new();
+    for type_index in 0..type_count {
+        let entry = type_list + 2 + type_index * TYPE_ENTRY_SIZE;
+        let kind = util::from_mac_roman(fork.get(entry..entry + 4).ok_or_else(truncated)?);
+        let count = read_u16(entry + 4)? as usize + 1;
+        let references = type_list + read_u16(entry + 6)? as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        for index in 0..count {
+            let reference = references + index * REFERENCE_ENTRY_SIZE;
+            let id = read_u16(reference)? as i16;
+            let name_offset = read_u16(reference + 2)? as i16;
+            // the top byte is the attributes, the rest the offset into the data
+            let packed = read_u32(reference + 4)?;
+            let attributes = packed >> 24;
+            let offset = data_offset + (packed & 0x00FF_FFFF) as usize;
+
+            let length = read_u32(offset)? as usize;
+            let data = fork.get(offset + 4..offset + 4 + length).ok_or_else(truncated)?.to_vec();
+
+            let name = match name_offset {
+                -1 => String::new(),
+                _ => {
+                    let start = name_list + name_offset as u16 as usize;
+                    let length = *fork.get(start).ok_or_else(truncated)? as usize;
+                    util::from_mac_roman(fork.get(start + 1..start + 1 + length).ok_or_else(truncated)?)
+                }
+            };
+
+            entries.push(ResourceEntry {
+                attributes: format!("0x{:04X}", attributes),
+                data,
+                id: i32::from(id),
+                name,
+            });
+        }
+
+        resources.insert(kind, entries);
+    }
+
+    Ok(resources)
+}
+
+/// Build a classic Mac OS resource fork holding resources
+pub fn build(resources: &BTreeMap<String, Vec<ResourceEntry>>) -> Result<Vec<u8>, XMLError> {
+    let too_large = || XMLError::Resource("Resources do not fit in a resource fork".to_string());
+
+    let mut data = Vec::new();
+    let mut types = Vec::new();
+    let mut references = Vec::new();
+    let mut names = Vec::new();
+
+    let kinds: Vec<(&String, &Vec<ResourceEntry>)> = resources.iter().filter(|(_, entries)| !entries.is_empty()).collect();
+    if kinds.len() > usize::from(u16::MAX) {
+        return Err(too_large());
+    }
+    let type_list_length = 2 + kinds.len() * TYPE_ENTRY_SIZE;
+
+    for (kind, entries) in &kinds {
+        let mut code = util::to_mac_roman(kind);
+        code.resize(4, b' ');
+        types.extend_from_slice(&code[..4]);
+        let count = u16::try_from(entries.len() - 1).map_err(|_| too_large())?;
+        types.extend_from_slice(&count.to_be_bytes());
+        let offset = u16::try_from(type_list_length + references.len()).map_err(|_| too_large())?;
+        types.extend_from_slice(&offset.to_be_bytes());
+
+        for entry in entries.iter() {
+            let name_offset = match entry.name.is_empty() {
+                true => -1i16,
+                false => {
+                    let offset = i16::try_from(names.len()).map_err(|_| too_large())?;
+                    let mut name = util::to_mac_roman(&entry.name);
+                    name.truncate(255);
+                    names.push(name.len() as u8);
+                    names.append(&mut name);
+                    offset
+                }
+            };
+
+            let attributes = u32::from_str_radix(entry.attributes.trim_start_matches("0x"), 16).unwrap_or(0) & 0xFF;
+            if data.len() > 0x00FF_FFFF {
+                return Err(too_large());
+            }
+
+            let id = i16::try_from(entry.id).map_err(|_| too_large())?;
+            references.extend_from_slice(&id.to_be_bytes());
+            references.extend_from_slice(&name_offset.to_be_bytes());
+            references.extend_from_slice(&(attributes << 24 | data.len() as u32).to_be_bytes());
+            references.extend_from_slice(&0u32.to_be_bytes());
+
+            data.extend_from_slice(&(entry.data.len() as u32).to_be_bytes());
+            data.extend_from_slice(&entry.data);
+        }
+    }
+
+    let map_offset = DATA_OFFSET + data.len();
+    let name_list_offset = MAP_HEADER_SIZE + type_list_length + references.len();
+    let map_length = name_list_offset + names.len();
+    let name_list_offset = u16::try_from(name_list_offset).map_err(|_| too_large())?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(DATA_OFFSET as u32).to_be_bytes());
+    header.extend_from_slice(&(map_offset as u32).to_be_bytes());
+    header.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    header.extend_from_slice(&(map_length as u32).to_be_bytes());
+
+    let mut fork = header.clone();
+    fork.resize(DATA_OFFSET, 0);
+    fork.append(&mut data);
+
+    // the map starts with a copy of the header
+    fork.extend_from_slice(&header);
+    fork.extend_from_slice(&[0u8; 6]);
+    fork.extend_from_slice(&0u16.to_be_bytes());
+    fork.extend_from_slice(&(MAP_HEADER_SIZE as u16).to_be_bytes());
+    fork.extend_from_slice(&name_list_offset.to_be_bytes());
+    fork.extend_from_slice(&(kinds.len() as u16).wrapping_sub(1).to_be_bytes());
+    fork.append(&mut types);
+    fork.append(&mut references);
+    fork.append(&mut names);
+
+    Ok(fork)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i32, name: &str, attributes: &str, data: &[u8]) -> ResourceEntry {
+        ResourceEntry { attributes: attributes.to_string(), data: data.to_vec(), id, name: name.to_string() }
+    }
+
+    fn summary(resources: &BTreeMap<String, Vec<ResourceEntry>>) -> Vec<(String, i32, String, String, Vec<u8>)> {
+        resources
+            .iter()
+            .flat_map(|(kind, entries)| {
+                entries.iter().map(move |e| (kind.clone(), e.id, e.name.clone(), e.attributes.clone(), e.data.clone()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut resources = BTreeMap::new();
+        resources.insert("blkx".to_string(), vec![
+            entry(-1, "Driver Descriptor Map (DDM : 0)", "0x0050", &[1u8; 204]),
+            entry(0, "disk image (Apple_HFS : 1)", "0x0050", &[2u8; 244]),
+        ]);
+        resources.insert("plst".to_string(), vec![entry(0, "", "0x0050", &[3u8; 1])]);
+        resources.insert("TEXT".to_string(), vec![entry(5000, "English", "0x0000", b"Licence")]);
+
+        let fork = build(&resources).unwrap();
+        assert_eq!(summary(&parse(&fork).unwrap()), summary(&resources));
+    }
+
+    #[test]
+    fn empty() {
+        let fork = build(&BTreeMap::new()).unwrap();
+        assert!(parse(&fork).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut resources = BTreeMap::new();
+        resources.insert("blkx".to_string(), vec![entry(0, "disk image", "0x0050", &[2u8; 244])]);
+        let fork = build(&resources).unwrap();
+
+        assert!(parse(&fork[..fork.len() - 4]).is_err());
+        assert!(parse(&fork[..6]).is_err());
+    }
+
+    #[test]
+    fn data_past_the_end() {
+        let mut resources = BTreeMap::new();
+        resources.insert("blkx".to_string(), vec![entry(0, "", "0x0050", &[2u8; 16])]);
+        let mut fork = build(&resources).unwrap();
+
+        // the length of the only resource
+        fork[DATA_OFFSET..DATA_OFFSET + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(parse(&fork).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        // IDs are 16 bit in a resource fork
+        for id in [32768, -32769] {
+            let mut resources = BTreeMap::new();
+            resources.insert("blkx".to_string(), vec![entry(id, "", "0x0050", &[])]);
+            assert!(build(&resources).is_err(), "{}", id);
+        }
+        let mut resources = BTreeMap::new();
+        resources.insert("blkx".to_string(), vec![entry(-32768, "", "0x0050", &[]), entry(32767, "", "0x0050", &[])]);
+        let fork = build(&resources).unwrap();
+        assert_eq!(summary(&parse(&fork).unwrap()), summary(&resources));
+
+        // and so is the count of each type
+        let mut resources = BTreeMap::new();
+        resources.insert("blkx".to_string(), vec![entry(0, "", "0x0050", &[]); 65537]);
+        assert!(build(&resources).is_err());
+    }
+}
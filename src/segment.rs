@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io::{self, prelude::{Read, Seek, Write}, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::convert::build_koly;
+use super::koly::KolyBlock;
+use super::util;
+
+/// A single file of a segmented image.
+/// The first segment is the .dmg itself, the rest are named
+/// <name>.002.dmgpart, <name>.003.dmgpart, ...
+#[derive(Debug)]
+pub struct Segment {
+    /// Path of this segment on disk
+    pub path: PathBuf,
+    /// The koly block found at the end of this segment
+    pub koly: KolyBlock,
+    file: File,
+}
+
+/// A set of segments, presented as one logical data fork.
+/// The plist only lives in the first segment, and chunk offsets
+/// in the mish blocks are relative to the logical data fork.
+#[derive(Debug)]
+pub struct SegmentedImage {
+    pub segments: Vec<Segment>,
+    /// Combined length of all the data forks
+    length: u64,
+    /// Current position in the logical data fork
+    position: u64,
+}
+
+impl SegmentedImage {
+    /// Open a DMG, and if its koly block says it is segmented,
+    /// every .dmgpart that belongs to it.
+    pub fn open(first: &Path) -> Result<SegmentedImage, io::Error> {
+        let segment = Segment::open(first.to_path_buf())?;
+        let segment_count = segment.koly.segment_count;
+        let segment_id = segment.koly.segment_id;
+
+        let mut segments = vec![segment];
+
+        for path in segment_paths(first, segment_count).into_iter().skip(1) {
+            let segment = Segment::open(path)?;
+            let expected = segments.len() as u32 + 1;
+
+            if segment.koly.segment_id != segment_id {
+                return Err(invalid(format!("{:?} has segment id {:#X}, expected {:#X}", segment.path, segment.koly.segment_id, segment_id)));
+            }
+            if segment.koly.segment_number != expected || segment.koly.segment_count != segment_count {
+                return Err(invalid(format!("{:?} is segment {} of {}, expected {} of {}", segment.path, segment.koly.segment_number, segment.koly.segment_count, expected, segment_count)));
+            }
+
+            segments.push(segment);
+        }
+
+        // Each segment records where its data fork starts in the logical one
+        let mut length = 0;
+        for segment in &segments {
+            if segment.koly.running_data_fork_offset != length {
+                return Err(invalid(format!("{:?} starts at {}, expected {}", segment.path, segment.koly.running_data_fork_offset, length)));
+            }
+            length = length
+                .checked_add(segment.koly.data_fork_length)
+                .ok_or_else(|| invalid(format!("{:?} data fork is too long", segment.path)))?;
+        }
+
+        Ok(SegmentedImage { segments, length, position: 0 })
+    }
+
+    /// The koly block of the first segment, which describes the whole image
+    pub fn koly(&self) -> &KolyBlock {
+        &self.segments[0].koly
+    }
+
+    /// Read the plist data from the first segment
+    pub fn xml(&mut self) -> Result<Vec<u8>, io::Error> {
+        let first = &mut self.segments[0];
+        let length = first.file.seek(SeekFrom::End(0))?;
+        util::read_fork(&mut first.file, "XML plist", first.koly.xml_offset, first.koly.xml_length, length)
+    }
+
+    /// The classic resource fork, which the earliest images have instead of the plist
+    pub fn resource_fork(&mut self) -> Result<Vec<u8>, io::Error> {
+        let first = &mut self.segments[0];
+        let length = first.file.seek(SeekFrom::End(0))?;
+        util::read_fork(&mut first.file, "resource fork", first.koly.source_fork_offset, first.koly.source_fork_length, length)
+    }
+
+    /// Length of the logical data fork
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl Read for SegmentedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // find the segment that holds the current position
+        let position = self.position;
+        let segment = self.segments
+            .iter_mut()
+            .find(|s| position < s.koly.running_data_fork_offset + s.koly.data_fork_length)
+            .expect("Position is within the logical data fork");
+
+        let relative = self.position - segment.koly.running_data_fork_offset;
+        let available = segment.koly.data_fork_length - relative;
+        let wanted = std::cmp::min(available, buf.len() as u64) as usize;
+
+        segment.file.seek(SeekFrom::Start(segment.koly.data_fork_offset + relative))?;
+        let read = segment.file.read(&mut buf[..wanted])?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for SegmentedImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.length as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of data fork"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl Segment {
+    fn open(path: PathBuf) -> Result<Segment, io::Error> {
+        let mut file = File::open(&path)?;
+        let koly = KolyBlock::from_reader(&mut file)?;
+
+        Ok(Segment { path, koly, file })
+    }
+}
+
+/// Paths of all the segments in a set, given the path of the first.
+/// foo.dmg -> [foo.dmg, foo.002.dmgpart, foo.003.dmgpart, ...]
+pub fn segment_paths(first: &Path, segment_count: u32) -> Vec<PathBuf> {
+    let stem = first.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+    let mut paths = vec![first.to_path_buf()];
+    for number in 2..=segment_count {
+        paths.push(first.with_file_name(format!("{}.{:03}.dmgpart", stem, number)));
+    }
+
+    paths
+}
+
+/// Split a converted image over as many segments as needed so that no
+/// file is larger than segment_size. The first segment carries the plist
+/// and any resource fork, and every segment ends with its own koly block.
+pub fn write_segments(dmg: &Path, data_fork: &[u8], xml: Vec<u8>, resource_fork: &[u8], sector_count: u64, segment_size: u64, segment_id: u128) -> Result<Vec<PathBuf>, io::Error> {
+    // the first segment also has to fit the plist
+    let metadata_length = xml.len() + resource_fork.len();
+    if segment_size <= 512 + metadata_length as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Segment size must be larger than {} bytes", 512 + metadata_length)));
+    }
+
+    let first_length = (segment_size - 512 - metadata_length as u64) as usize;
+    let rest_length = (segment_size - 512) as usize;
+
+    // carve up the data fork
+    let mut parts = Vec::new();
+    let (first, mut remaining) = data_fork.split_at(std::cmp::min(first_length, data_fork.len()));
+    parts.push(first);
+    while !remaining.is_empty() {
+        let (part, rest) = remaining.split_at(std::cmp::min(rest_length, remaining.len()));
+        parts.push(part);
+        remaining = rest;
+    }
+
+    let segment_count = parts.len() as u32;
+    let paths = segment_paths(dmg, segment_count);
+    let mut running_data_fork_offset = 0;
+
+    for (index, (part, path)) in parts.into_iter().zip(paths.iter()).enumerate() {
+        let xml_length = if index == 0 { xml.len() as u64 } else { 0 };
+
+        let mut koly = build_koly(xml_length, part.len() as u64, sector_count);
+        koly.running_data_fork_offset = running_data_fork_offset;
+        koly.segment_number = index as u32 + 1;
+        koly.segment_count = segment_count;
+        koly.segment_id = segment_id;
+        if index != 0 {
+            koly.xml_offset = 0;
+        } else if !resource_fork.is_empty() {
+            koly.source_fork_offset = koly.xml_offset + xml_length;
+            koly.source_fork_length = resource_fork.len() as u64;
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(part)?;
+        if index == 0 {
+            file.write_all(&xml)?;
+            file.write_all(resource_fork)?;
+        }
+        file.write_all(&koly.to_be_bytes())?;
+
+        running_data_fork_offset += part.len() as u64;
+    }
+
+    Ok(paths)
+}
+
+/// Parse a size such as "4096", "100k", "10m" or "1g", like hdiutil's -segmentSize
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim().to_lowercase();
+    let (digits, multiplier) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 1024),
+        Some('m') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('g') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (&size[..], 1),
+    };
+
+    let n = digits.parse::<u64>().map_err(|e| format!("Invalid size {:?}: {}", size, e))?;
+    n.checked_mul(multiplier).ok_or_else(|| format!("Invalid size {:?}: too large", size))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A data fork split over three segments, the last one short
+    fn segmented(name: &str) -> (PathBuf, Vec<u8>) {
+        let dir = std::env::temp_dir().join(format!("libdmg-segment-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let data_fork: Vec<u8> = (0..10_000u32).map(|i| (i % 253) as u8).collect();
+        let first = dir.join("image.dmg");
+        let paths = write_segments(&first, &data_fork, b"<plist/>".to_vec(), &[], 20, 4096, 0x1234).unwrap();
+        assert_eq!(paths.len(), 3);
+        (first, data_fork)
+    }
+
+    /// Change the koly block at the end of a segment
+    fn patch_koly(path: &Path, offset: usize, value: &[u8]) {
+        let mut contents = std::fs::read(path).unwrap();
+        let koly = contents.len() - 512;
+        contents[koly + offset..koly + offset + value.len()].copy_from_slice(value);
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn reads_across_segments() {
+        let (first, data_fork) = segmented("reads");
+        let mut image = SegmentedImage::open(&first).unwrap();
+        assert_eq!(image.segments.len(), 3);
+        assert_eq!(image.len(), data_fork.len() as u64);
+        assert_eq!(image.xml().unwrap(), b"<plist/>");
+
+        let mut contents = Vec::new();
+        image.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data_fork);
+
+        // a read across the end of the first segment's data fork
+        let boundary = image.segments[1].koly.running_data_fork_offset;
+        image.seek(SeekFrom::Start(boundary - 10)).unwrap();
+        let mut buffer = [0u8; 20];
+        image.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data_fork[boundary as usize - 10..boundary as usize + 10]);
+
+        // seeking back, relative to the end, and past it
+        assert_eq!(image.seek(SeekFrom::Current(-30)).unwrap(), boundary - 20);
+        image.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data_fork[boundary as usize - 20..boundary as usize]);
+        assert_eq!(image.seek(SeekFrom::End(-5)).unwrap(), data_fork.len() as u64 - 5);
+        assert_eq!(image.read(&mut buffer).unwrap(), 5);
+        image.seek(SeekFrom::End(100)).unwrap();
+        assert_eq!(image.read(&mut buffer).unwrap(), 0);
+        assert!(image.seek(SeekFrom::Current(-100_000)).is_err());
+
+        std::fs::remove_dir_all(first.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn missing_segment() {
+        let (first, _) = segmented("missing");
+        std::fs::remove_file(first.with_file_name("image.002.dmgpart")).unwrap();
+
+        let error = SegmentedImage::open(&first).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(first.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wrong_segment_number() {
+        let (first, _) = segmented("number");
+        patch_koly(&first.with_file_name("image.003.dmgpart"), 56, &2u32.to_be_bytes());
+
+        let error = SegmentedImage::open(&first).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("is segment 2 of 3, expected 3 of 3"), "{}", error);
+
+        std::fs::remove_dir_all(first.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wrong_segment_id() {
+        let (first, _) = segmented("id");
+        patch_koly(&first.with_file_name("image.003.dmgpart"), 64, &0x5678u128.to_be_bytes());
+
+        let error = SegmentedImage::open(&first).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("has segment id 0x5678, expected 0x1234"), "{}", error);
+
+        std::fs::remove_dir_all(first.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("100k"), Ok(100 * 1024));
+        assert_eq!(parse_size(" 10M "), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12q").is_err());
+        assert!(parse_size(&format!("{}", u64::MAX)).is_ok());
+        assert!(parse_size(&format!("{}k", u64::MAX)).is_err());
+        assert!(parse_size("17179869184g").is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+
+use super::resource::ResourceEntry;
+use super::util;
+
+/// Resource ID of the LPic resource, and the first TEXT / STR# resource
+const BASE_RESOURCE_ID: i32 = 5000;
+
+/// A language a license agreement can be shown in.
+/// Region codes are the classic Mac OS ones (verUS, verFrance, ...).
+#[derive(Debug)]
+pub struct Language {
+    /// Short code used on the command line, e.g. "en"
+    pub code: &'static str,
+    /// Mac OS region code
+    pub region: u16,
+    /// Language name, shown in the language picker
+    pub name: &'static str,
+    /// Agree, Disagree, Print, Save... and the message above the buttons
+    pub buttons: [&'static str; 5],
+}
+
+pub const LANGUAGES: &[Language] = &[
+    Language {
+        code: "en",
+        region: 0,
+        name: "English",
+        buttons: [
+            "Agree",
+            "Disagree",
+            "Print",
+            "Save...",
+            "If you agree with the terms of this license, press \"Agree\" to install the software. If you do not agree, press \"Disagree\".",
+        ],
+    },
+    Language {
+        code: "fr",
+        region: 1,
+        name: "Français",
+        buttons: [
+            "Accepter",
+            "Refuser",
+            "Imprimer",
+            "Enregistrer...",
+            "Si vous acceptez les termes de la présente licence, cliquez sur \"Accepter\" afin d'installer le logiciel. Si vous n'êtes pas d'accord avec les termes de la licence, cliquez sur \"Refuser\".",
+        ],
+    },
+    Language {
+        code: "de",
+        region: 3,
+        name: "Deutsch",
+        buttons: [
+            "Akzeptieren",
+            "Ablehnen",
+            "Drucken",
+            "Sichern...",
+            "Klicken Sie auf \"Akzeptieren\", wenn Sie mit den Bestimmungen des Lizenzvertrags einverstanden sind. Falls nicht, klicken Sie auf \"Ablehnen\".",
+        ],
+    },
+    Language {
+        code: "it",
+        region: 4,
+        name: "Italiano",
+        buttons: [
+            "Accetto",
+            "Rifiuto",
+            "Stampa",
+            "Registra...",
+            "Se accetti le condizioni di questa licenza, fai clic su \"Accetto\" per installare il software. Altrimenti fai clic su \"Rifiuto\".",
+        ],
+    },
+    Language {
+        code: "nl",
+        region: 5,
+        name: "Nederlands",
+        buttons: [
+            "Ja",
+            "Nee",
+            "Print",
+            "Bewaar...",
+            "Indien u akkoord gaat met de voorwaarden van deze licentie, kunt u op 'Ja' klikken om de programmatuur te installeren. Indien u niet akkoord gaat, klikt u op 'Nee'.",
+        ],
+    },
+    Language {
+        code: "sv",
+        region: 7,
+        name: "Svensk",
+        buttons: [
+            "Godkänns",
+            "Avböjs",
+            "Skriv ut",
+            "Spara...",
+            "Om Ni godkänner licensvillkoren klicka på \"Godkänns\" för att installera programprodukten. Om Ni inte godkänner licensvillkoren, klicka på \"Avböjs\".",
+        ],
+    },
+    Language {
+        code: "es",
+        region: 8,
+        name: "Español",
+        buttons: [
+            "Aceptar",
+            "No aceptar",
+            "Imprimir",
+            "Guardar...",
+            "Si está de acuerdo con los términos de esta licencia, pulse \"Aceptar\" para instalar el software. Si no está de acuerdo, pulse \"No aceptar\".",
+        ],
+    },
+];
+
+/// Body of a license agreement
+#[derive(Debug)]
+pub enum LicenseText {
+    /// Plain text, stored as a Mac OS Roman TEXT resource. Characters
+    /// Mac OS Roman can't represent are stored as '?', so parse_license
+    /// refuses them; use RTF for anything else.
+    Text(String),
+    /// Rich text, stored as-is in an "RTF " resource
+    Rtf(Vec<u8>),
+}
+
+/// A license agreement in a single language
+#[derive(Debug)]
+pub struct License {
+    pub language: &'static Language,
+    pub text: LicenseText,
+}
+
+/// Parse a --license argument, either "<file>" for English
+/// or "<language>:<file>", e.g. "de:LIZENZ.txt".
+/// Files ending in .rtf are stored as rich text. Plain text has to be
+/// representable in Mac OS Roman.
+pub fn parse_license(arg: &str) -> Result<License, String> {
+    let (code, path) = match arg.find(':') {
+        Some(index) if LANGUAGES.iter().any(|l| l.code == &arg[..index]) => (&arg[..index], &arg[index + 1..]),
+        _ => ("en", arg),
+    };
+
+    let language = LANGUAGES.iter().find(|l| l.code == code).expect("Language was just matched");
+    let contents = std::fs::read(path).map_err(|e| format!("Could not read license {:?}: {}", path, e))?;
+
+    let text = if path.to_lowercase().ends_with(".rtf") || contents.starts_with(b"{\\rtf") {
+        LicenseText::Rtf(contents)
+    } else {
+        let text = String::from_utf8_lossy(&contents).to_string();
+        if let Some((line, c)) = text
+            .lines()
+            .enumerate()
+            .find_map(|(i, line)| line.chars().find(|c| !util::is_mac_roman(*c)).map(|c| (i + 1, c)))
+        {
+            return Err(format!("License {:?} line {} has {:?}, which Mac OS Roman can't represent; use an RTF license instead", path, line, c));
+        }
+        LicenseText::Text(text)
+    };
+
+    Ok(License { language, text })
+}
+
+/// Build the resources hdiutil expects for a license agreement:
+/// an LPic listing the languages, then a STR# (button labels) and a
+/// TEXT or RTF resource for each language, with IDs from 5000.
+/// The first license is the default.
+pub fn resources(licenses: &[License]) -> BTreeMap<String, Vec<ResourceEntry>> {
+    let mut resources: BTreeMap<String, Vec<ResourceEntry>> = BTreeMap::new();
+    if licenses.is_empty() {
+        return resources;
+    }
+
+    // LPic - default region, count, then (region, id offset, two byte flag)
+    let mut lpic = Vec::new();
+    lpic.extend_from_slice(&licenses[0].language.region.to_be_bytes());
+    lpic.extend_from_slice(&(licenses.len() as u16).to_be_bytes());
+
+    for (index, license) in licenses.iter().enumerate() {
+        let id = BASE_RESOURCE_ID + index as i32;
+        let language = license.language;
+
+        lpic.extend_from_slice(&language.region.to_be_bytes());
+        lpic.extend_from_slice(&(index as u16).to_be_bytes());
+        lpic.extend_from_slice(&0u16.to_be_bytes());
+
+        // STR# - count, then Pascal strings: name, agree, disagree, print, save, message
+        let mut strings = Vec::new();
+        strings.extend_from_slice(&6u16.to_be_bytes());
+        for string in std::iter::once(&language.name).chain(language.buttons.iter()) {
+            let mut encoded = util::to_mac_roman(string);
+            encoded.truncate(255);
+            strings.push(encoded.len() as u8);
+            strings.append(&mut encoded);
+        }
+        resources.entry(String::from("STR#")).or_default().push(ResourceEntry {
+            attributes: String::from("0x0000"),
+            data: strings,
+            id,
+            name: format!("{} buttons", language.name),
+        });
+
+        let (kind, data) = match &license.text {
+            LicenseText::Text(text) => ("TEXT", util::to_mac_roman(&text.replace("\r\n", "\r").replace('\n', "\r"))),
+            LicenseText::Rtf(rtf) => ("RTF ", rtf.clone()),
+        };
+        resources.entry(String::from(kind)).or_default().push(ResourceEntry {
+            attributes: String::from("0x0000"),
+            data,
+            id,
+            name: format!("{} SLA", language.name),
+        });
+    }
+
+    resources.insert(String::from("LPic"), vec![ResourceEntry {
+        attributes: String::from("0x0000"),
+        data: lpic,
+        id: BASE_RESOURCE_ID,
+        name: String::new(),
+    }]);
+
+    resources
+}
+
+/// Find any license agreements in a plist's resources.
+/// Returns the language name (or region code, if unknown) and the text.
+pub fn licenses(resources: &BTreeMap<String, Vec<ResourceEntry>>) -> Vec<(String, String)> {
+    let lpic = match resources.get("LPic").and_then(|l| l.first()) {
+        Some(lpic) if lpic.data.len() >= 4 => &lpic.data,
+        _ => return Vec::new(),
+    };
+
+    let count = util::read_be_u16(&mut &lpic[2..4]) as usize;
+
+    lpic[4..]
+        .chunks_exact(6)
+        .take(count)
+        .filter_map(|entry| {
+            let region = util::read_be_u16(&mut &entry[0..2]);
+            let id = BASE_RESOURCE_ID + i32::from(util::read_be_u16(&mut &entry[2..4]));
+
+            let language = LANGUAGES
+                .iter()
+                .find(|l| l.region == region)
+                .map(|l| l.name.to_string())
+                .unwrap_or_else(|| format!("region {}", region));
+
+            let find = |kind: &str| resources.get(kind).and_then(|r| r.iter().find(|r| r.id == id));
+
+            let text = match (find("TEXT"), find("RTF ")) {
+                (Some(text), _) => util::from_mac_roman(&text.data).replace('\r', "\n"),
+                (None, Some(rtf)) => String::from_utf8_lossy(&rtf.data).to_string(),
+                (None, None) => return None,
+            };
+
+            Some((language, text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(code: &str) -> &'static Language {
+        LANGUAGES.iter().find(|l| l.code == code).unwrap()
+    }
+
+    fn licenses_for_test() -> Vec<License> {
+        vec![
+            License { language: language("fr"), text: LicenseText::Text("Licence\r\nvalidée\n".to_string()) },
+            License { language: language("de"), text: LicenseText::Rtf(b"{\\rtf1 Lizenz}".to_vec()) },
+        ]
+    }
+
+    #[test]
+    fn builds_resources() {
+        let resources = resources(&licenses_for_test());
+        assert_eq!(resources.keys().collect::<Vec<_>>(), vec!["LPic", "RTF ", "STR#", "TEXT"]);
+
+        // French is the default, then each language with its ID offset
+        let lpic = &resources["LPic"][0];
+        assert_eq!(lpic.id, 5000);
+        assert_eq!(lpic.data, vec![0, 1, 0, 2, 0, 1, 0, 0, 0, 0, 0, 3, 0, 1, 0, 0]);
+
+        let ids = |kind: &str| resources[kind].iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids("STR#"), vec![5000, 5001]);
+        assert_eq!(ids("TEXT"), vec![5000]);
+        assert_eq!(ids("RTF "), vec![5001]);
+
+        // Mac OS Roman, with classic line endings
+        assert_eq!(resources["TEXT"][0].data, b"Licence\rvalid\x8Ee\r");
+        assert_eq!(resources["RTF "][0].data, b"{\\rtf1 Lizenz}");
+
+        // six Pascal strings, starting with the language name
+        let strings = &resources["STR#"][0].data;
+        assert_eq!(&strings[..2], &[0, 6]);
+        assert_eq!(&strings[2..11], b"\x08Fran\x8Dais");
+        assert_eq!(&strings[11..20], b"\x08Accepter");
+    }
+
+    #[test]
+    fn round_trip() {
+        let found = licenses(&resources(&licenses_for_test()));
+        assert_eq!(found, vec![
+            ("Français".to_string(), "Licence\nvalidée\n".to_string()),
+            ("Deutsch".to_string(), "{\\rtf1 Lizenz}".to_string()),
+        ]);
+
+        assert!(resources(&[]).is_empty());
+        assert!(licenses(&BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn unknown_regions() {
+        let mut resources = resources(&licenses_for_test());
+        resources.get_mut("LPic").unwrap()[0].data[4..6].copy_from_slice(&99u16.to_be_bytes());
+        assert_eq!(licenses(&resources)[0].0, "region 99");
+    }
+
+    #[test]
+    fn parses_license_arguments() {
+        let dir = std::env::temp_dir().join(format!("libdmg-sla-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        let english = parse_license(&write("LICENSE.txt", "Terms © 2001")).unwrap();
+        assert_eq!(english.language.code, "en");
+        assert!(matches!(english.text, LicenseText::Text(ref t) if t == "Terms © 2001"));
+
+        let german = parse_license(&format!("de:{}", write("LIZENZ.rtf", "plain"))).unwrap();
+        assert_eq!(german.language.code, "de");
+        assert!(matches!(german.text, LicenseText::Rtf(_)));
+
+        // no Mac OS Roman encoding for these
+        let error = parse_license(&write("emoji.txt", "Terms\nwith \u{1F600}")).err().unwrap();
+        assert!(error.contains("line 2 has '\u{1f600}'"), "{}", error);
+        assert!(parse_license(&write("cyrillic.rtf", "{\\rtf1 Лицензия}")).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::{Read, Seek, Write}, SeekFrom};
+
+use super::disk::VirtualDisk;
+use super::util;
+
+/// "sprs" in ASCII
+pub const SPARSE_MAGIC: &[u8; 4] = b"sprs";
+
+/// Version written by hdiutil
+const SPARSE_VERSION: u32 = 3;
+
+/// Each header takes up 4 KiB, with the bands in its table after it
+const HEADER_SIZE: u64 = 4096;
+
+/// Where the band table starts in the first header
+const BAND_TABLE_OFFSET: usize = 64;
+
+/// Where the band table starts in the index headers that follow the
+/// first once its table is full
+const INDEX_TABLE_OFFSET: usize = 56;
+
+/// Bands in the table of the first header, and of each index header
+const HEADER_BANDS: usize = (HEADER_SIZE as usize - BAND_TABLE_OFFSET) / 4;
+const INDEX_BANDS: usize = (HEADER_SIZE as usize - INDEX_TABLE_OFFSET) / 4;
+
+/// hdiutil's default band size, 1 MiB
+pub const DEFAULT_SECTORS_PER_BAND: u32 = 2048;
+
+/// Whether reader holds a .sparseimage
+pub fn is_sparse<R: Read + Seek>(reader: &mut R) -> Result<bool, io::Error> {
+    let mut magic = [0u8; 4];
+    reader.seek(SeekFrom::Start(0))?;
+    let read = reader.read(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(read == 4 && &magic == SPARSE_MAGIC)
+}
+
+/// A .sparseimage, as created by hdiutil create -type SPARSE. The disk
+/// is split into bands which are only stored once written to, in the
+/// order they were written. Bands that were never written read as zeros.
+///
+/// The first header holds a table of the first 1008 bands stored, which
+/// follow it. Once that is full, hdiutil writes an index header with a
+/// table of the next 1010 and links it from the one before.
+pub struct SparseImage<R> {
+    inner: R,
+    sectors_per_band: u32,
+    sector_count: u64,
+    /// File offset of each stored band of the disk, by band number
+    bands: HashMap<u64, u64>,
+    position: u64,
+}
+
+impl<R: Read + Seek> SparseImage<R> {
+    pub fn new(mut inner: R) -> Result<SparseImage<R>, io::Error> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+
+        if &header[0..4] != SPARSE_MAGIC {
+            return Err(invalid("Invalid sparse image magic bytes"));
+        }
+        let sectors_per_band = util::read_be_u32(&mut &header[8..12]);
+        // a 64 bit count, or if that isn't set, the 32 bit one before it
+        let sector_count = match util::read_be_u64(&mut &header[28..36]) {
+            0 => u64::from(util::read_be_u32(&mut &header[16..20])),
+            sector_count => sector_count,
+        };
+
+        if sectors_per_band == 0 {
+            return Err(invalid("Sparse image has no band size"));
+        }
+        if sector_count > u64::MAX / 512 {
+            return Err(invalid("Sparse image is too large"));
+        }
+
+        let band_size = u64::from(sectors_per_band) * 512;
+        let band_count = sector_count.div_ceil(u64::from(sectors_per_band));
+
+        // entry i of a table is the (1 based) band stored i-th after its header
+        let mut bands = HashMap::new();
+        let (mut node, mut table, mut next) = (0, BAND_TABLE_OFFSET, util::read_be_u64(&mut &header[20..28]));
+        loop {
+            for (index, entry) in header[table..].chunks_exact(4).enumerate() {
+                let band = u64::from(util::read_be_u32(&mut &entry[..]));
+                if band == 0 {
+                    continue;
+                }
+                if band > band_count {
+                    return Err(invalid("Sparse image band is out of range"));
+                }
+                bands.insert(band - 1, node + HEADER_SIZE + index as u64 * band_size);
+            }
+
+            if next == 0 {
+                break;
+            }
+            // headers only go forwards through the file, so they can't loop
+            if next < node + HEADER_SIZE {
+                return Err(invalid("Sparse image index header is not after the one before it"));
+            }
+            node = next;
+            inner.seek(SeekFrom::Start(node))?;
+            inner.read_exact(&mut header).map_err(|_| invalid("Sparse image index header is past the end of the file"))?;
+            if &header[0..4] != SPARSE_MAGIC {
+                return Err(invalid("Invalid sparse image index header magic bytes"));
+            }
+            table = INDEX_TABLE_OFFSET;
+            next = util::read_be_u64(&mut &header[12..20]);
+        }
+
+        Ok(SparseImage { inner, sectors_per_band, sector_count, bands, position: 0 })
+    }
+
+    /// Sectors in the disk, stored or not
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn band_size(&self) -> u64 {
+        u64::from(self.sectors_per_band) * 512
+    }
+}
+
+impl<R: Read + Seek> Read for SparseImage<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.sector_count * 512;
+        if self.position >= length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let band_size = self.band_size();
+        let band = self.position / band_size;
+        let offset = self.position % band_size;
+        let count = std::cmp::min(buf.len() as u64, std::cmp::min(band_size - offset, length - self.position)) as usize;
+
+        match self.bands.get(&band).copied() {
+            Some(start) => {
+                self.inner.seek(SeekFrom::Start(start + offset))?;
+                self.inner.read_exact(&mut buf[..count])?;
+            }
+            None => buf[..count].iter_mut().for_each(|b| *b = 0),
+        }
+
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<R: Read + Seek> Seek for SparseImage<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => (self.sector_count * 512) as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the sparse image"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl<R: Read + Seek> VirtualDisk for SparseImage<R> {
+    fn size(&self) -> u64 {
+        self.sector_count * 512
+    }
+}
+
+/// Write length bytes of source as a .sparseimage, leaving out bands
+/// that are all zeros. Returns the number of bands stored.
+pub fn write_sparse<R: Read, W: Write + Seek>(source: &mut R, length: u64, out: &mut W) -> Result<u64, io::Error> {
+    write_bands(source, length, DEFAULT_SECTORS_PER_BAND, out)
+}
+
+fn write_bands<R: Read, W: Write + Seek>(source: &mut R, length: u64, sectors_per_band: u32, out: &mut W) -> Result<u64, io::Error> {
+    let sector_count = length.div_ceil(512);
+    let band_size = u64::from(sectors_per_band) * 512;
+    if sector_count.div_ceil(u64::from(sectors_per_band)) > u64::from(u32::MAX) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many bands for a sparse image"));
+    }
+
+    // each header, and the bands stored after it
+    let mut headers: Vec<(u64, Vec<u32>)> = vec![(0, Vec::new())];
+    let mut position = HEADER_SIZE;
+    out.seek(SeekFrom::Start(HEADER_SIZE))?;
+
+    let mut buffer = vec![0u8; band_size as usize];
+    let mut remaining = length;
+    let mut band = 0u32;
+
+    while remaining > 0 {
+        let count = std::cmp::min(remaining, band_size) as usize;
+        buffer.iter_mut().for_each(|b| *b = 0);
+        source.read_exact(&mut buffer[..count])?;
+
+        band += 1;
+        if buffer.iter().any(|b| *b != 0) {
+            let capacity = if headers.len() == 1 { HEADER_BANDS } else { INDEX_BANDS };
+            if headers[headers.len() - 1].1.len() == capacity {
+                // filled in once every band is written
+                out.write_all(&[0u8; HEADER_SIZE as usize])?;
+                headers.push((position, Vec::new()));
+                position += HEADER_SIZE;
+            }
+
+            out.write_all(&buffer)?;
+            let last = headers.len() - 1;
+            headers[last].1.push(band);
+            position += band_size;
+        }
+        remaining -= count as u64;
+    }
+
+    for (i, (offset, table)) in headers.iter().enumerate() {
+        let next = headers.get(i + 1).map(|(offset, _)| *offset).unwrap_or(0);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(SPARSE_MAGIC);
+        if i == 0 {
+            header.extend_from_slice(&SPARSE_VERSION.to_be_bytes());
+            header.extend_from_slice(&sectors_per_band.to_be_bytes());
+            header.extend_from_slice(&1u32.to_be_bytes());
+            // the low 32 bits, the whole count follows
+            header.extend_from_slice(&(sector_count as u32).to_be_bytes());
+            header.extend_from_slice(&next.to_be_bytes());
+            header.extend_from_slice(&sector_count.to_be_bytes());
+            header.resize(BAND_TABLE_OFFSET, 0);
+        } else {
+            header.extend_from_slice(&(i as u32).to_be_bytes());
+            header.extend_from_slice(&1u32.to_be_bytes());
+            header.extend_from_slice(&next.to_be_bytes());
+            header.resize(INDEX_TABLE_OFFSET, 0);
+        }
+        for band in table {
+            header.extend_from_slice(&band.to_be_bytes());
+        }
+        header.resize(HEADER_SIZE as usize, 0);
+
+        out.seek(SeekFrom::Start(*offset))?;
+        out.write_all(&header)?;
+    }
+    out.flush()?;
+
+    Ok(headers.iter().map(|(_, table)| table.len() as u64).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bands 1 to bands, of a sector each, with every third left as zeros
+    fn disk(bands: u64) -> Vec<u8> {
+        (0..bands * 512)
+            .map(|i| if (i / 512) % 3 == 1 { 0 } else { (i / 512) as u8 | 1 })
+            .collect()
+    }
+
+    fn sparse(disk: &[u8], sectors_per_band: u32) -> (Vec<u8>, u64) {
+        let mut out = io::Cursor::new(Vec::new());
+        let stored = write_bands(&mut &disk[..], disk.len() as u64, sectors_per_band, &mut out).unwrap();
+        (out.into_inner(), stored)
+    }
+
+    fn read_all(image: Vec<u8>) -> Vec<u8> {
+        let mut contents = Vec::new();
+        SparseImage::new(io::Cursor::new(image)).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn round_trip() {
+        // a partial last band
+        let mut disk = disk(40);
+        disk.truncate(disk.len() - 100);
+        let (image, stored) = sparse(&disk, 4);
+
+        assert!(is_sparse(&mut io::Cursor::new(&image)).unwrap());
+        assert_eq!(stored, 10);
+        assert_eq!(image.len() as u64, HEADER_SIZE + stored * 4 * 512);
+
+        let mut expected = disk;
+        expected.resize(40 * 512, 0);
+        assert_eq!(read_all(image), expected);
+    }
+
+    #[test]
+    fn index_headers() {
+        // enough stored bands to fill the first header and one index header
+        let disk = disk(3300);
+        let (image, stored) = sparse(&disk, 1);
+        assert_eq!(stored as usize, 2200);
+        assert!(stored as usize > HEADER_BANDS + INDEX_BANDS);
+
+        let next = util::read_be_u64(&mut &image[20..28]);
+        assert_eq!(next, HEADER_SIZE + HEADER_BANDS as u64 * 512);
+        assert_eq!(&image[next as usize..next as usize + 4], SPARSE_MAGIC);
+
+        assert_eq!(read_all(image), disk);
+    }
+
+    #[test]
+    fn sector_count_fields() {
+        let (mut image, _) = sparse(&disk(8), 1);
+
+        // only the 32 bit count
+        image[28..36].copy_from_slice(&0u64.to_be_bytes());
+        let sparse = SparseImage::new(io::Cursor::new(image.clone())).unwrap();
+        assert_eq!(sparse.sector_count(), 8);
+
+        // the 64 bit count wins
+        image[28..36].copy_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        let sparse = SparseImage::new(io::Cursor::new(image)).unwrap();
+        assert_eq!(sparse.sector_count(), 0x1_0000_0000);
+    }
+
+    #[test]
+    fn malformed() {
+        let (image, _) = sparse(&disk(3300), 1);
+        let next = util::read_be_u64(&mut &image[20..28]) as usize;
+        let open = |image: Vec<u8>| SparseImage::new(io::Cursor::new(image)).err().unwrap().kind();
+
+        // truncated header
+        assert_eq!(open(image[..100].to_vec()), io::ErrorKind::UnexpectedEof);
+
+        // a band past the end of the disk
+        let mut broken = image.clone();
+        broken[64..68].copy_from_slice(&3301u32.to_be_bytes());
+        assert_eq!(open(broken), io::ErrorKind::InvalidData);
+
+        // an index header pointing back at itself
+        let mut broken = image.clone();
+        broken[20..28].copy_from_slice(&(next as u64).to_be_bytes());
+        broken[next + 12..next + 20].copy_from_slice(&(next as u64).to_be_bytes());
+        assert_eq!(open(broken), io::ErrorKind::InvalidData);
+
+        // an index header past the end of the file
+        let mut broken = image.clone();
+        broken[20..28].copy_from_slice(&(image.len() as u64).to_be_bytes());
+        assert_eq!(open(broken), io::ErrorKind::InvalidData);
+
+        // an index header that isn't one
+        let mut broken = image;
+        broken[next..next + 4].copy_from_slice(b"junk");
+        assert_eq!(open(broken), io::ErrorKind::InvalidData);
+    }
+}
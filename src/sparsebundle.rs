@@ -0,0 +1,270 @@
+use std::fs::File;
+use std::io::{self, prelude::{Read, Seek}, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::bplist::{self, Value};
+use super::disk::VirtualDisk;
+
+/// The diskimage-bundle-type of a sparse bundle
+const BUNDLE_TYPE: &str = "com.apple.diskimage.sparsebundle";
+
+/// Whether path is a .sparsebundle directory
+pub fn is_sparse_bundle(path: &Path) -> bool {
+    path.is_dir() && path.join("Info.plist").is_file()
+}
+
+/// A .sparsebundle, as created by hdiutil create -type SPARSEBUNDLE.
+/// The disk is split into bands stored as files in bands/, named by
+/// their index in lower case hex. Missing bands, and anything past
+/// the end of a short band, read as zeros.
+pub struct SparseBundle {
+    path: PathBuf,
+    band_size: u64,
+    size: u64,
+    position: u64,
+    /// The last band opened, as (band index, file if it exists)
+    band: Option<(u64, Option<File>)>,
+}
+
+impl SparseBundle {
+    pub fn open(path: &Path) -> Result<SparseBundle, io::Error> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let info = read_info(&std::fs::read(path.join("Info.plist"))?).map_err(invalid)?;
+        let integer = |key: &str| match info.iter().find(|(k, _)| k == key) {
+            Some((_, Value::Integer(value))) if *value >= 0 => Ok(*value as u64),
+            _ => Err(invalid(format!("Info.plist has no valid {}", key))),
+        };
+
+        match info.iter().find(|(k, _)| k == "diskimage-bundle-type") {
+            Some((_, Value::String(kind))) if kind == BUNDLE_TYPE => (),
+            _ => return Err(invalid(format!("Info.plist is not for a {}", BUNDLE_TYPE))),
+        }
+
+        let band_size = integer("band-size")?;
+        let size = integer("size")?;
+        if band_size == 0 {
+            return Err(invalid("Sparse bundle has no band size".to_string()));
+        }
+
+        // encrypted bundles keep their encrcdsa header in the token
+        let mut token = Vec::new();
+        if let Ok(mut file) = File::open(path.join("token")) {
+            file.by_ref().take(8).read_to_end(&mut token)?;
+        }
+        if token.starts_with(b"encrcdsa") {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Encrypted sparse bundles are not supported"));
+        }
+
+        Ok(SparseBundle { path: path.to_path_buf(), band_size, size, position: 0, band: None })
+    }
+
+    pub fn band_size(&self) -> u64 {
+        self.band_size
+    }
+
+    /// The file holding band index, if it was ever written
+    fn band_file(&mut self, index: u64) -> Result<Option<&mut File>, io::Error> {
+        if self.band.as_ref().map(|(i, _)| *i) != Some(index) {
+            let path = self.path.join("bands").join(format!("{:x}", index));
+            let file = match File::open(path) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            self.band = Some((index, file));
+        }
+
+        Ok(self.band.as_mut().and_then(|(_, file)| file.as_mut()))
+    }
+}
+
+impl Read for SparseBundle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.position / self.band_size;
+        let offset = self.position % self.band_size;
+        let count = std::cmp::min(buf.len() as u64, std::cmp::min(self.band_size - offset, self.size - self.position)) as usize;
+        let buf = &mut buf[..count];
+
+        let mut filled = 0;
+        if let Some(file) = self.band_file(index)? {
+            file.seek(SeekFrom::Start(offset))?;
+            while filled < count {
+                match file.read(&mut buf[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        buf[filled..].iter_mut().for_each(|b| *b = 0);
+
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for SparseBundle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => self.size as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the sparse bundle"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl VirtualDisk for SparseBundle {
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// The top level keys and values of Info.plist, which is usually XML
+/// but may be a binary plist
+fn read_info(data: &[u8]) -> Result<Vec<(String, Value)>, String> {
+    if data.starts_with(bplist::BPLIST_MAGIC) {
+        return match bplist::read(data).map_err(|e| e.to_string())? {
+            Value::Dictionary(dict) => Ok(dict.into_iter().collect()),
+            _ => Err("Info.plist is not a dictionary".to_string()),
+        };
+    }
+
+    let xml = xmltree::Element::parse(data).map_err(|e| e.to_string())?;
+    let dict = xml.get_child("dict").ok_or("Info.plist is not a dictionary")?;
+
+    let mut info = Vec::new();
+    for pair in dict.children.chunks(2) {
+        let (key, value) = match pair {
+            [key, value] if key.name == "key" => (key, value),
+            _ => return Err("Info.plist has a key without a value".to_string()),
+        };
+
+        let text = value.text.clone().unwrap_or_default();
+        let value = match value.name.as_str() {
+            "integer" => Value::Integer(text.trim().parse().map_err(|_| format!("Invalid integer {:?} in Info.plist", text))?),
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(text),
+        };
+        info.push((key.text.clone().unwrap_or_default(), value));
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn info(band_size: u64, size: u64, kind: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>CFBundleInfoDictionaryVersion</key>
+	<string>6.0</string>
+	<key>band-size</key>
+	<integer>{}</integer>
+	<key>bundle-backingstore-version</key>
+	<integer>1</integer>
+	<key>diskimage-bundle-type</key>
+	<string>{}</string>
+	<key>size</key>
+	<integer>{}</integer>
+</dict>
+</plist>
+"#,
+            band_size, kind, size
+        )
+    }
+
+    /// A bundle of 1 KiB bands: band 0 whole, band 1 missing, band 2 short
+    fn bundle(name: &str, info: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("libdmg-{}-{}.sparsebundle", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(path.join("bands")).unwrap();
+        std::fs::write(path.join("Info.plist"), info).unwrap();
+        std::fs::write(path.join("token"), b"").unwrap();
+        std::fs::write(path.join("bands").join("0"), [0x11; 1024]).unwrap();
+        std::fs::write(path.join("bands").join("2"), [0x33; 100]).unwrap();
+        path
+    }
+
+    fn expected() -> Vec<u8> {
+        let mut disk = vec![0x11; 1024];
+        disk.resize(2048, 0);
+        disk.extend_from_slice(&[0x33; 100]);
+        disk.resize(3000, 0);
+        disk
+    }
+
+    #[test]
+    fn reads_bands() {
+        let path = bundle("read", info(1024, 3000, BUNDLE_TYPE).as_bytes());
+        assert!(is_sparse_bundle(&path));
+
+        let mut bundle = SparseBundle::open(&path).unwrap();
+        assert_eq!((bundle.band_size(), bundle.size()), (1024, 3000));
+        let mut contents = Vec::new();
+        bundle.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, expected());
+
+        // across the missing band
+        let mut middle = [0xFFu8; 1100];
+        bundle.seek(SeekFrom::Start(1000)).unwrap();
+        bundle.read_exact(&mut middle).unwrap();
+        assert_eq!(&middle[..], &expected()[1000..2100]);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn binary_info() {
+        let mut dict = BTreeMap::new();
+        dict.insert("band-size".to_string(), Value::Integer(1024));
+        dict.insert("diskimage-bundle-type".to_string(), Value::String(BUNDLE_TYPE.to_string()));
+        dict.insert("size".to_string(), Value::Integer(3000));
+        let path = bundle("binary", &bplist::write(&Value::Dictionary(dict)));
+
+        let mut contents = Vec::new();
+        SparseBundle::open(&path).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, expected());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn rejected() {
+        let open = |name: &str, info: &str| {
+            let path = bundle(name, info.as_bytes());
+            let error = SparseBundle::open(&path).err().unwrap().kind();
+            std::fs::remove_dir_all(path).unwrap();
+            error
+        };
+
+        assert_eq!(open("type", &info(1024, 3000, "com.apple.diskimage.sparseimage")), io::ErrorKind::InvalidData);
+        assert_eq!(open("band-size", &info(0, 3000, BUNDLE_TYPE)), io::ErrorKind::InvalidData);
+        assert_eq!(open("size", &info(1024, 3000, BUNDLE_TYPE).replace("<integer>3000", "<integer>-1")), io::ErrorKind::InvalidData);
+        assert_eq!(open("unpaired", &info(1024, 3000, BUNDLE_TYPE).replace("<string>6.0</string>", "")), io::ErrorKind::InvalidData);
+
+        let path = bundle("encrypted", info(1024, 3000, BUNDLE_TYPE).as_bytes());
+        std::fs::write(path.join("token"), b"encrcdsa\0\0\0\x02").unwrap();
+        assert_eq!(SparseBundle::open(&path).err().unwrap().kind(), io::ErrorKind::Unsupported);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
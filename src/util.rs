@@ -1,8 +1,11 @@
 use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, prelude::{Read, Seek}, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
 
 /// Represents a Universal Disk Image Format (UDIF) checksum
 /// structure.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct UDIFChecksum {
     /// Data fork
     pub fork_type: u32,
@@ -13,6 +16,15 @@ pub struct UDIFChecksum {
 }
 
 impl UDIFChecksum {
+    /// No checksum, for data that changed after it was summed
+    pub fn none() -> UDIFChecksum {
+        UDIFChecksum {
+            fork_type: 0,
+            size: 0,
+            data: vec![0u8; 128],
+        }
+    }
+
     pub fn to_be_bytes(self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -27,6 +39,13 @@ impl UDIFChecksum {
     }
 }
 
+/// Create a u16 from big-endian ordered bytes
+pub fn read_be_u16(input: &mut &[u8]) -> u16 {
+    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
+    *input = rest;
+    u16::from_be_bytes(int_bytes.try_into().unwrap())
+}
+
 /// Create a u32 from big-endian ordered bytes
 pub fn read_be_u32(input: &mut &[u8]) -> u32 {
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
@@ -47,3 +66,136 @@ pub fn read_be_u128(input: &mut &[u8]) -> u128 {
     *input = rest;
     u128::from_be_bytes(int_bytes.try_into().unwrap())
 }
+
+/// Create a random u128, used for identifiers such as the segment id.
+/// Reads from /dev/urandom, falling back to the current time.
+pub fn random_u128() -> u128 {
+    let mut bytes = [0u8; 16];
+
+    let read = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes));
+    if read.is_err() {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        return nanos ^ (u128::from(std::process::id()) << 64);
+    }
+
+    u128::from_be_bytes(bytes)
+}
+
+/// Read len bytes from /dev/urandom, for key material
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut bytes = vec![0u8; len];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read a fork described by a koly block, checking that it lies within
+/// the first stream_length bytes before allocating anything for it
+pub fn read_fork<R: Read + Seek>(reader: &mut R, name: &str, offset: u64, length: u64, stream_length: u64) -> Result<Vec<u8>, std::io::Error> {
+    if offset.checked_add(length).is_none_or(|end| end > stream_length) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("The {} ({} bytes at {}) runs past the end of the image ({} bytes)", name, length, offset, stream_length),
+        ));
+    }
+
+    let mut fork = vec![0u8; length as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut fork)?;
+    Ok(fork)
+}
+
+/// Decode a hex string such as "0a1b2c", returning None if it isn't valid hex
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Characters 0x80 - 0xFF of the Mac OS Roman encoding
+const MAC_ROMAN: [char; 128] = [
+    '\u{00C4}', '\u{00C5}', '\u{00C7}', '\u{00C9}', '\u{00D1}', '\u{00D6}', '\u{00DC}', '\u{00E1}',
+    '\u{00E0}', '\u{00E2}', '\u{00E4}', '\u{00E3}', '\u{00E5}', '\u{00E7}', '\u{00E9}', '\u{00E8}',
+    '\u{00EA}', '\u{00EB}', '\u{00ED}', '\u{00EC}', '\u{00EE}', '\u{00EF}', '\u{00F1}', '\u{00F3}',
+    '\u{00F2}', '\u{00F4}', '\u{00F6}', '\u{00F5}', '\u{00FA}', '\u{00F9}', '\u{00FB}', '\u{00FC}',
+    '\u{2020}', '\u{00B0}', '\u{00A2}', '\u{00A3}', '\u{00A7}', '\u{2022}', '\u{00B6}', '\u{00DF}',
+    '\u{00AE}', '\u{00A9}', '\u{2122}', '\u{00B4}', '\u{00A8}', '\u{2260}', '\u{00C6}', '\u{00D8}',
+    '\u{221E}', '\u{00B1}', '\u{2264}', '\u{2265}', '\u{00A5}', '\u{00B5}', '\u{2202}', '\u{2211}',
+    '\u{220F}', '\u{03C0}', '\u{222B}', '\u{00AA}', '\u{00BA}', '\u{03A9}', '\u{00E6}', '\u{00F8}',
+    '\u{00BF}', '\u{00A1}', '\u{00AC}', '\u{221A}', '\u{0192}', '\u{2248}', '\u{2206}', '\u{00AB}',
+    '\u{00BB}', '\u{2026}', '\u{00A0}', '\u{00C0}', '\u{00C3}', '\u{00D5}', '\u{0152}', '\u{0153}',
+    '\u{2013}', '\u{2014}', '\u{201C}', '\u{201D}', '\u{2018}', '\u{2019}', '\u{00F7}', '\u{25CA}',
+    '\u{00FF}', '\u{0178}', '\u{2044}', '\u{20AC}', '\u{2039}', '\u{203A}', '\u{FB01}', '\u{FB02}',
+    '\u{2021}', '\u{00B7}', '\u{201A}', '\u{201E}', '\u{2030}', '\u{00C2}', '\u{00CA}', '\u{00C1}',
+    '\u{00CB}', '\u{00C8}', '\u{00CD}', '\u{00CE}', '\u{00CF}', '\u{00CC}', '\u{00D3}', '\u{00D4}',
+    '\u{F8FF}', '\u{00D2}', '\u{00DA}', '\u{00DB}', '\u{00D9}', '\u{0131}', '\u{02C6}', '\u{02DC}',
+    '\u{00AF}', '\u{02D8}', '\u{02D9}', '\u{02DA}', '\u{00B8}', '\u{02DD}', '\u{02DB}', '\u{02C7}',
+];
+
+/// Encode a string as Mac OS Roman, as used by classic resources
+/// such as TEXT and STR#. Unmappable characters become '?'.
+pub fn to_mac_roman(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                c as u8
+            } else {
+                MAC_ROMAN.iter().position(|&m| m == c).map(|p| p as u8 + 0x80).unwrap_or(b'?')
+            }
+        })
+        .collect()
+}
+
+/// Whether a character has a Mac OS Roman encoding
+pub fn is_mac_roman(c: char) -> bool {
+    c.is_ascii() || MAC_ROMAN.contains(&c)
+}
+
+/// Decode Mac OS Roman bytes into a string
+pub fn from_mac_roman(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| if b < 0x80 { b as char } else { MAC_ROMAN[(b - 0x80) as usize] })
+        .collect()
+}
+
+/// Encode bytes as a lowercase hex string
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write to a file next to path, renaming it over path on success
+/// and removing it on failure, so path is never left half written
+pub fn write_atomically<T, F>(path: &Path, write: F) -> Result<T, io::Error>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<T, io::Error>,
+{
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let result = File::create(&partial).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        let written = write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(written)
+    });
+
+    match result {
+        Ok(written) => {
+            std::fs::rename(&partial, path)?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::prelude::{Read, Seek};
+use std::io::SeekFrom;
+
+use super::hfs::{self, CatalogKind, HfsVolume};
+use super::iso9660::IsoVolume;
+
+/// Default permissions when the file system has none
+const DIRECTORY_MODE: u16 = 0o755;
+const FILE_MODE: u16 = 0o644;
+
+/// What a node is
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeKind {
+    Directory,
+    File,
+    /// A symbolic link, with its target
+    Symlink(String),
+}
+
+/// A file, folder or symlink, whichever file system it came from
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// Index of the containing folder in Volume::nodes
+    pub parent: usize,
+    pub name: String,
+    pub kind: NodeKind,
+    /// Length of the data in bytes
+    pub size: u64,
+    /// Permission bits
+    pub mode: u16,
+    /// Seconds since 1970
+    pub modified: u64,
+    /// Byte ranges (offset, length) on the volume holding the data
+    pub extents: Vec<(u64, u64)>,
+}
+
+/// The files on an HFS+ or ISO 9660 volume, as a tree of nodes to
+/// browse without knowing which file system it is
+#[derive(Debug)]
+pub struct Volume {
+    pub name: String,
+    /// The root folder first
+    pub nodes: Vec<Node>,
+}
+
+impl Volume {
+    /// Read the HFS+ or ISO 9660 file system on a partition
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Volume, String> {
+        let hfs_error = match HfsVolume::read(reader) {
+            Ok(hfs) => return from_hfs(reader, &hfs),
+            Err(e) => e,
+        };
+        match IsoVolume::read(reader) {
+            Ok(iso) => Ok(from_iso(&iso)),
+            Err(iso_error) => Err(format!("No supported file system found: {}, {}", hfs_error, iso_error)),
+        }
+    }
+
+    /// Nodes directly inside a folder, as (index, node)
+    pub fn children(&self, parent: usize) -> impl Iterator<Item = (usize, &Node)> {
+        self.nodes.iter().enumerate().skip(1).filter(move |(_, n)| n.parent == parent)
+    }
+
+    /// Read up to size bytes of a file, starting offset bytes in
+    pub fn read_node<R: Read + Seek>(&self, reader: &mut R, node: &Node, offset: u64, size: u64) -> Result<Vec<u8>, String> {
+        let end = std::cmp::min(node.size, offset.saturating_add(size));
+        let mut data = Vec::new();
+        let mut start = 0;
+
+        for (extent, length) in &node.extents {
+            let (from, to) = (std::cmp::max(offset, start), std::cmp::min(end, start + length));
+            if from < to {
+                let position = data.len();
+                data.resize(position + (to - from) as usize, 0);
+                reader.seek(SeekFrom::Start(extent + from - start)).map_err(|e| e.to_string())?;
+                reader.read_exact(&mut data[position..]).map_err(|e| e.to_string())?;
+            }
+            start += length;
+        }
+
+        Ok(data)
+    }
+}
+
+fn from_hfs<R: Read + Seek>(reader: &mut R, hfs: &HfsVolume) -> Result<Volume, String> {
+    let block_size = hfs.header.block_size;
+    let root = hfs
+        .records
+        .iter()
+        .find(|r| r.id == hfs::ROOT_FOLDER_ID && r.kind == CatalogKind::Folder)
+        .ok_or("HFS+ volume has no root folder")?;
+
+    let mut children: HashMap<u32, Vec<&hfs::CatalogRecord>> = HashMap::new();
+    for record in hfs.records.iter().filter(|r| r.id != hfs::ROOT_FOLDER_ID) {
+        children.entry(record.parent_id).or_default().push(record);
+    }
+
+    // walk down from the root, so every node comes after its folder.
+    // The private folders holding hard link targets start with NUL.
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut queue = VecDeque::from(vec![(0, root)]);
+    while let Some((parent, record)) = queue.pop_front() {
+        if record.name.starts_with('\0') || nodes.len() > hfs.records.len() {
+            continue;
+        }
+        if record.kind == CatalogKind::Folder {
+            let index = nodes.len();
+            for child in children.get(&record.id).into_iter().flatten() {
+                queue.push_back((index, child));
+            }
+        }
+
+        let (kind, size, extents) = match (&record.data_fork, record.kind) {
+            (Some(fork), CatalogKind::File) if &record.file_type == b"slnk" => {
+                let target = hfs.read_file(reader, record)?;
+                (NodeKind::Symlink(String::from_utf8_lossy(&target).to_string()), fork.logical_size, Vec::new())
+            }
+            (Some(fork), CatalogKind::File) => (NodeKind::File, fork.logical_size, fork.ranges(block_size)?),
+            _ => (NodeKind::Directory, 0, Vec::new()),
+        };
+
+        let mode = match record.mode & 0o7777 {
+            0 if kind == NodeKind::Directory => DIRECTORY_MODE,
+            0 => FILE_MODE,
+            mode => mode,
+        };
+
+        nodes.push(Node {
+            parent,
+            // : on HFS+ is / on POSIX and the other way around
+            name: record.name.replace('/', ":"),
+            kind,
+            size,
+            mode,
+            modified: u64::from(record.content_mod_date).saturating_sub(hfs::HFS_EPOCH_OFFSET),
+            extents,
+        });
+    }
+
+    Ok(Volume { name: hfs.name(), nodes })
+}
+
+fn from_iso(iso: &IsoVolume) -> Volume {
+    let nodes = iso
+        .entries
+        .iter()
+        .map(|entry| {
+            let kind = match (&entry.symlink, entry.directory) {
+                (Some(target), false) => NodeKind::Symlink(target.clone()),
+                (_, true) => NodeKind::Directory,
+                (None, false) => NodeKind::File,
+            };
+            let mode = match entry.mode {
+                Some(mode) => (mode & 0o7777) as u16,
+                None if kind == NodeKind::Directory => DIRECTORY_MODE,
+                None => FILE_MODE,
+            };
+
+            Node {
+                parent: entry.parent,
+                name: entry.name.clone(),
+                size: if kind == NodeKind::File { entry.size } else { 0 },
+                extents: if kind == NodeKind::File { entry.extents.clone() } else { Vec::new() },
+                kind,
+                mode,
+                modified: entry.modified,
+            }
+        })
+        .collect();
+
+    Volume { name: iso.name.clone(), nodes }
+}
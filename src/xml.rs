@@ -1,17 +1,22 @@
-use std::io::Read;
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
 use xmltree;
 
+use crate::bplist::{self, Value};
 use crate::partition::PartitionEntry;
+use crate::resource::ResourceEntry;
+use crate::resource_fork;
 
 /// Ways that XML parsing might fail
 #[derive(Debug)]
 pub enum XMLError {
     Base64(String),
+    BPList(String),
     Blxx(String),
     Mish(String),
     Partition(String),
+    Resource(String),
     XML(String),
 }
 
@@ -19,9 +24,11 @@ impl fmt::Display for XMLError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             XMLError::Base64(e) => fmt::Display::fmt(e, f),
+            XMLError::BPList(e) => fmt::Display::fmt(e, f),
             XMLError::Blxx(e) => fmt::Display::fmt(e, f),
             XMLError::Mish(e) => fmt::Display::fmt(e, f),
             XMLError::Partition(e) => fmt::Display::fmt(e, f),
+            XMLError::Resource(e) => fmt::Display::fmt(e, f),
             XMLError::XML(e) => fmt::Display::fmt(e, f),
         }
     }
@@ -31,9 +38,11 @@ impl error::Error for XMLError {
     fn description(&self) -> &str {
         match self {
             XMLError::Base64(e) => e,
+            XMLError::BPList(e) => e,
             XMLError::Blxx(e) => e,
             XMLError::Mish(e) => e,
             XMLError::Partition(e) => e,
+            XMLError::Resource(e) => e,
             XMLError::XML(e) => e,
         }
     }
@@ -55,52 +64,151 @@ impl From<xmltree::ParseError> for XMLError {
 pub struct PList {
     /// Vector of GPT partitions
     pub partitions: Vec<PartitionEntry>,
+    /// Every other resource type (plst, LPic, TEXT, ...), keyed by type
+    pub resources: BTreeMap<String, Vec<ResourceEntry>>,
 }
 
 impl PList {
 
-    pub fn from_bytes(data: Vec<u8>) -> Result<PList, XMLError> {
-        let string = String::from_utf8(data).unwrap();
-        //println!("xml: {:#?}", string.clone());
+    /// Parse the plist, which is usually XML but may be a binary plist
+    pub fn from_bytes(data: &[u8]) -> Result<PList, XMLError> {
+        if data.starts_with(bplist::BPLIST_MAGIC) {
+            return PList::from_bplist(data);
+        }
 
-        let xml = xmltree::Element::parse(string.as_bytes())?;
+        let xml = xmltree::Element::parse(data)?;
 
-        let outer_dict = xml.get_child("dict").unwrap();
+        let outer_dict = xml
+            .get_child("dict")
+            .ok_or_else(|| XMLError::XML("Could not find the plist dict".to_string()))?;
 
         // check for the resource-fork key
-        let resource_fork = outer_dict
+        let text = outer_dict
             .get_child("key")
-            .expect("Could not find resource-fork");
-        let text = resource_fork
-            .text
-            .clone()
-            .expect("Malformed resource-fork text");
-        assert_eq!(text, "resource-fork");
+            .and_then(|key| key.text.as_deref());
+        if text != Some("resource-fork") {
+            return Err(XMLError::XML("Could not find resource-fork".to_string()));
+        }
 
         //println!("out_dict: {:#?}", outer_dict);
 
-        // get the array that contains the blk data entries
-        let blk_array = outer_dict
+        // The resource-fork dictionary is made up of key / array pairs,
+        // one array per resource type. The blkx array holds the partitions.
+        let resource_dict = outer_dict
             .get_child("dict")
-            .unwrap()
-            .get_child("array")
-            .expect("Could not find blk data array");
+            .ok_or_else(|| XMLError::XML("Could not find resource-fork dict".to_string()))?;
+
+        let mut partitions = Vec::new();
+        let mut resources = BTreeMap::new();
+
+        for pair in resource_dict.children.chunks(2) {
+            let (key, array) = match pair {
+                [key, array] => (key, array),
+                _ => return Err(XMLError::XML("Resource type without an array".to_string())),
+            };
+
+            let kind = key.text.clone().unwrap_or_default();
+            if kind == "blkx" {
+                partitions = array
+                    .children
+                    .iter()
+                    .map(PartitionEntry::new)
+                    .collect::<Result<Vec<PartitionEntry>, XMLError>>()?;
+            } else {
+                let entries = array
+                    .children
+                    .iter()
+                    .map(ResourceEntry::new)
+                    .collect::<Result<Vec<ResourceEntry>, XMLError>>()?;
+                resources.insert(kind, entries);
+            }
+        }
 
-    //println!("blk array: {:#?}", blk_array);
+        Ok(PList { partitions, resources })
+    }
 
-    //    let plst_array = outer_dict
-    //         .get_child("dict")
+    /// Parse a bplist00 binary plist, with the same resource-fork
+    /// dictionary as the XML
+    pub fn from_bplist(data: &[u8]) -> Result<PList, XMLError> {
+        let root = bplist::read(data)?;
+
+        let resource_dict = match &root {
+            Value::Dictionary(root) => match root.get("resource-fork") {
+                Some(Value::Dictionary(resource_dict)) => resource_dict,
+                _ => return Err(XMLError::BPList("Could not find resource-fork dict".to_string())),
+            },
+            _ => return Err(XMLError::BPList("bplist root is not a dictionary".to_string())),
+        };
+
+        let mut partitions = Vec::new();
+        let mut resources = BTreeMap::new();
+
+        for (kind, array) in resource_dict {
+            let entries = match array {
+                Value::Array(values) => values
+                    .iter()
+                    .map(ResourceEntry::from_value)
+                    .collect::<Result<Vec<ResourceEntry>, XMLError>>()?,
+                _ => return Err(XMLError::BPList("Resource type without an array".to_string())),
+            };
+
+            if kind == "blkx" {
+                partitions = entries
+                    .iter()
+                    .map(PartitionEntry::from_resource)
+                    .collect::<Result<Vec<PartitionEntry>, XMLError>>()?;
+            } else {
+                resources.insert(kind.clone(), entries);
+            }
+        }
 
-        let partitions: Result<Vec<PartitionEntry>, XMLError> = blk_array
-            .children
+        Ok(PList { partitions, resources })
+    }
+
+    /// Serialize as a bplist00 binary plist
+    pub fn to_bplist(&self) -> Vec<u8> {
+        let mut resource_dict: BTreeMap<String, Value> = self
+            .resources
             .iter()
-            .map(|child| PartitionEntry::new(child))
+            .map(|(kind, entries)| (kind.clone(), Value::Array(entries.iter().map(ResourceEntry::to_value).collect())))
             .collect();
 
-        match partitions {
-            Ok(partitions) => Ok(PList { partitions }),
-            Err(e) => Err(e),
-        }
+        let partitions = self.partitions.iter().map(|p| {
+            let mut value = p.to_resource().to_value();
+            if let Value::Dictionary(dict) = &mut value {
+                dict.insert(String::from("CFName"), Value::String(p.name.clone()));
+            }
+            value
+        });
+        resource_dict.insert(String::from("blkx"), Value::Array(partitions.collect()));
+
+        let mut root = BTreeMap::new();
+        root.insert(String::from("resource-fork"), Value::Dictionary(resource_dict));
+
+        bplist::write(&Value::Dictionary(root))
+    }
+
+    /// Parse the classic resource fork used by images that predate the
+    /// XML plist, see KolyBlock::source_fork_offset
+    pub fn from_resource_fork(fork: &[u8]) -> Result<PList, XMLError> {
+        let mut resources = resource_fork::parse(fork)?;
+
+        let partitions = resources
+            .remove("blkx")
+            .unwrap_or_default()
+            .iter()
+            .map(PartitionEntry::from_resource)
+            .collect::<Result<Vec<PartitionEntry>, XMLError>>()?;
+
+        Ok(PList { partitions, resources })
+    }
+
+    /// Build a classic resource fork holding the partitions and the
+    /// other resources, the reverse of from_resource_fork
+    pub fn to_resource_fork(&self) -> Result<Vec<u8>, XMLError> {
+        let mut resources = self.resources.clone();
+        resources.insert(String::from("blkx"), self.partitions.iter().map(PartitionEntry::to_resource).collect());
+        resource_fork::build(&resources)
     }
 
     // Create an empty XML structure that looks something
@@ -127,34 +235,65 @@ impl PList {
 
     // Should not be affected by BE ordering. All data being passed in 
     // has already been converted to BE bytes
-    pub fn build(mish: String) -> Vec<u8> {
-        let mut base = PList::empty();
-        let partition = PList::partition(mish, 0);
+    pub fn build(mish: String, name: &str, resources: &BTreeMap<String, Vec<ResourceEntry>>) -> Vec<u8> {
+        PList::assemble(vec![PList::partition("0x0050", mish, 0, name)], resources)
+    }
+
+    /// Serialize as XML, with every partition and resource
+    pub fn to_xml(&self) -> Vec<u8> {
+        let partitions = self
+            .partitions
+            .iter()
+            .map(|p| PList::partition(&p.attributes, base64::encode(&p.data.clone().to_be_bytes()), p.id, &p.name))
+            .collect();
+
+        PList::assemble(partitions, &self.resources)
+    }
 
-        // insert our new partition
-        let blk_array = base.get_mut_child("dict")
-                            .unwrap().get_mut_child("dict")
-                            .unwrap().get_mut_child("array").unwrap();
-        blk_array.children.push(partition);
+    fn assemble(partitions: Vec<xmltree::Element>, resources: &BTreeMap<String, Vec<ResourceEntry>>) -> Vec<u8> {
+        let mut base = PList::empty();
 
-        println!("Built XML: {:#?}", base);
+        // insert our new partitions
+        let resource_dict = base.get_mut_child("dict")
+                            .unwrap().get_mut_child("dict").unwrap();
+        let blk_array = resource_dict.get_mut_child("array").unwrap();
+        blk_array.children = partitions;
+
+        // insert any other resources, such as a license agreement.
+        // Types already in the base structure (plst) are filled in place.
+        for (kind, entries) in resources {
+            let elements: Vec<xmltree::Element> = entries.iter().map(ResourceEntry::to_element).collect();
+
+            let existing = resource_dict
+                .children
+                .iter()
+                .position(|c| c.name == "key" && c.text.as_deref() == Some(kind.as_str()));
+
+            match existing {
+                Some(index) => resource_dict.children[index + 1].children = elements,
+                None => {
+                    resource_dict.children.push(PList::component(ElementType::KeyElm, kind.clone()));
+                    let mut array = xmltree::Element::new(ElementType::ArrayElm.to_str());
+                    array.children = elements;
+                    resource_dict.children.push(array);
+                }
+            }
+        }
 
-        base.write(std::fs::File::create("plist.xml").unwrap()).unwrap();
         let mut contents = Vec::new();
-        let mut ret = std::fs::File::open("plist.xml").unwrap();
-        ret.read_to_end(&mut contents).unwrap();
+        base.write(&mut contents).expect("Writing XML to a Vec can not fail");
 
         contents
     }
 
-    fn partition(mish: String, partition_id: u32) -> xmltree::Element {
+    fn partition(attributes: &str, mish: String, partition_id: i32, partition_name: &str) -> xmltree::Element {
         // partition dictionary
         let mut part = xmltree::Element::new("dict");
 
         // Attributes
         let attr = PList::component(ElementType::KeyElm, String::from("Attributes"));
         part.children.push(attr);
-        let attr_value = PList::component(ElementType::StringElm, String::from("0x0050"));
+        let attr_value = PList::component(ElementType::StringElm, String::from(attributes));
         part.children.push(attr_value);
 
         // Data
@@ -170,18 +309,18 @@ impl PList {
         // Name
         let name = PList::component(ElementType::KeyElm, String::from("Name"));
         part.children.push(name);
-        let name_val = PList::component(ElementType::StringElm, String::from("whole disk (unknown partition : 0)"));
+        let name_val = PList::component(ElementType::StringElm, String::from(partition_name));
         part.children.push(name_val);
         // CFName
         let cf = PList::component(ElementType::KeyElm, String::from("CFName"));
         part.children.push(cf);
-        let cf_val = PList::component(ElementType::StringElm, String::from("whole disk (unknown partition : 0)"));
+        let cf_val = PList::component(ElementType::StringElm, String::from(partition_name));
         part.children.push(cf_val);
 
         part
     }
 
-    fn component(element_type: ElementType, text: String) -> xmltree::Element {
+    pub fn component(element_type: ElementType, text: String) -> xmltree::Element {
         let mut c = xmltree::Element::new(element_type.to_str());
         c.text = Some(text);
         c
@@ -207,3 +346,74 @@ impl ElementType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plist(partition: &str) -> String {
+        format!(
+            "<plist version=\"1.0\"><dict><key>resource-fork</key><dict><key>blkx</key><array><dict>{}</dict></array></dict></dict></plist>",
+            partition
+        )
+    }
+
+    #[test]
+    fn missing_dict() {
+        assert!(PList::from_bytes(b"<plist version=\"1.0\"></plist>").is_err());
+    }
+
+    #[test]
+    fn missing_resource_fork_key() {
+        assert!(PList::from_bytes(b"<plist><dict><key>other</key><dict/></dict></plist>").is_err());
+        assert!(PList::from_bytes(b"<plist><dict><dict/></dict></plist>").is_err());
+    }
+
+    #[test]
+    fn missing_resource_fork_dict() {
+        assert!(PList::from_bytes(b"<plist><dict><key>resource-fork</key></dict></plist>").is_err());
+    }
+
+    #[test]
+    fn invalid_partition_id() {
+        let xml = plist("<key>Attributes</key><string>0x0050</string><key>Data</key><data>AAAA</data><key>ID</key><string>one</string><key>Name</key><string>disk</string>");
+        assert!(matches!(PList::from_bytes(xml.as_bytes()), Err(XMLError::Partition(_))));
+    }
+
+    #[test]
+    fn missing_partition_keys() {
+        let xml = plist("<key>Attributes</key><string>0x0050</string><key>Data</key><data>AAAA</data><key>Name</key><string>disk</string>");
+        assert!(matches!(PList::from_bytes(xml.as_bytes()), Err(XMLError::Partition(_))));
+
+        // a key with no value after it
+        let xml = plist("<key>Attributes</key><string>0x0050</string><key>Data</key><data>AAAA</data><key>Name</key><string>disk</string><key>ID</key>");
+        assert!(matches!(PList::from_bytes(xml.as_bytes()), Err(XMLError::Partition(_))));
+    }
+
+    #[test]
+    fn bplist_round_trip() {
+        let mish = crate::convert::build_mish(8, vec![crate::convert::final_blkx(8, 0)]).to_be_bytes();
+        let mut resources = BTreeMap::new();
+        resources.insert("TEXT".to_string(), vec![ResourceEntry {
+            attributes: "0x0000".to_string(),
+            data: b"Licence".to_vec(),
+            id: 5000,
+            name: "English".to_string(),
+        }]);
+
+        let xml = PList::from_bytes(&PList::build(base64::encode(&mish), "disk image", &resources)).unwrap();
+        let binary = xml.to_bplist();
+        assert!(binary.starts_with(bplist::BPLIST_MAGIC));
+        let parsed = PList::from_bytes(&binary).unwrap();
+
+        assert_eq!(parsed.partitions.len(), 1);
+        assert_eq!(parsed.partitions[0].id, xml.partitions[0].id);
+        assert_eq!(parsed.partitions[0].name, xml.partitions[0].name);
+        assert_eq!(parsed.partitions[0].attributes, xml.partitions[0].attributes);
+        assert_eq!(parsed.partitions[0].data.clone().to_be_bytes(), mish);
+
+        let text = &parsed.resources["TEXT"][0];
+        assert_eq!((text.id, text.name.as_str(), text.data.as_slice()), (5000, "English", &b"Licence"[..]));
+        assert_eq!(parsed.resources.keys().collect::<Vec<_>>(), xml.resources.keys().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use libdmg::builder::DmgBuilder;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("libdmg-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn disk() -> Vec<u8> {
+    (0..200 * 512u32).map(|i| (i / 512) as u8 ^ (i % 13) as u8).collect()
+}
+
+/// Write a DMG of disk() into dir
+fn dmg(dir: &std::path::Path) -> PathBuf {
+    let path = dir.join("image.dmg");
+    let disk = disk();
+    DmgBuilder::new()
+        .partition("disk image", &disk[..])
+        .write_to(std::fs::File::create(&path).unwrap())
+        .unwrap();
+    path
+}
+
+fn export(dir: &std::path::Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_libdmg"))
+        .current_dir(dir)
+        .arg("export")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn exports_flat_image() {
+    let dir = scratch_dir("export");
+    dmg(&dir);
+
+    let output = export(&dir, &["image.dmg", "image.cdr"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(dir.join("image.cdr")).unwrap(), disk());
+    assert!(!dir.join("image.cdr.partial").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_export_keeps_output() {
+    let dir = scratch_dir("export-failed");
+    let image = dmg(&dir);
+    std::fs::write(dir.join("existing.img"), b"existing").unwrap();
+
+    // not a DMG at all
+    std::fs::write(dir.join("garbage.dmg"), vec![0u8; 4096]).unwrap();
+    assert!(!export(&dir, &["garbage.dmg", "existing.img"]).status.success());
+    // a partition it doesn't have
+    assert!(!export(&dir, &[image.to_str().unwrap(), "existing.img", "--partition", "99"]).status.success());
+    // a DMG that doesn't exist
+    assert!(!export(&dir, &["missing.dmg", "existing.img"]).status.success());
+
+    assert_eq!(std::fs::read(dir.join("existing.img")).unwrap(), b"existing");
+    assert!(!dir.join("existing.img.partial").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
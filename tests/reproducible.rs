@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha1::{Digest, Sha1};
+
+const BLOCK_SIZE: usize = 4096;
+const TOTAL_BLOCKS: usize = 64;
+const README_SIZE: usize = 8 * BLOCK_SIZE - 100;
+
+/// Contents of the file on the volume, which doesn't compress well
+/// so the image can be split into segments
+fn readme() -> Vec<u8> {
+    let mut state: u32 = 1;
+    (0..README_SIZE)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+/// Big endian fork data, with a single extent
+fn fork(size: u64, start: u32, count: u32) -> Vec<u8> {
+    let mut fork = Vec::new();
+    fork.extend_from_slice(&size.to_be_bytes());
+    fork.extend_from_slice(&0u32.to_be_bytes());
+    fork.extend_from_slice(&count.to_be_bytes());
+    fork.extend_from_slice(&start.to_be_bytes());
+    fork.extend_from_slice(&count.to_be_bytes());
+    fork.resize(80, 0);
+    fork
+}
+
+/// Catalog key followed by a folder or file record
+fn record(parent: u32, name: &str, id: u32, date: u32, data: Option<(u64, u32, u32)>) -> Vec<u8> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let mut key = Vec::new();
+    key.extend_from_slice(&parent.to_be_bytes());
+    key.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        key.extend_from_slice(&unit.to_be_bytes());
+    }
+
+    let mut record = (key.len() as u16).to_be_bytes().to_vec();
+    record.append(&mut key);
+
+    let kind: u16 = if data.is_some() { 2 } else { 1 };
+    record.extend_from_slice(&kind.to_be_bytes());
+    record.extend_from_slice(&[0u8; 6]);
+    record.extend_from_slice(&id.to_be_bytes());
+    for _ in 0..5 {
+        record.extend_from_slice(&date.to_be_bytes());
+    }
+    record.resize(record.len() + 56, 0);
+
+    if let Some((size, start, count)) = data {
+        record.append(&mut fork(size, start, count));
+        record.append(&mut fork(0, 0, 0));
+    }
+    record
+}
+
+/// A tiny HFS+ volume with a file and a folder. The dates and the
+/// contents of unused space depend on seed, as they would for two
+/// builds of the same files made at different times. Shuffled lists the
+/// contents of the root folder out of order in the leaf node.
+fn hfs_image(seed: u8, shuffled: bool) -> Vec<u8> {
+    let date = 3_000_000_000 + u32::from(seed) * 1000;
+    let mut image: Vec<u8> = (0..BLOCK_SIZE * TOTAL_BLOCKS).map(|i| (i as u8) ^ seed).collect();
+
+    let mut records = [
+        record(1, "Test", 2, date, None),
+        record(2, "App.app", 16, date, None),
+        record(2, "README", 17, date, Some((README_SIZE as u64, 10, 8))),
+    ];
+    if shuffled {
+        records.swap(1, 2);
+    }
+
+    // allocation bitmap in block 1, catalog in blocks 2 and 3, file in blocks 10 - 17
+    let mut bitmap = vec![0u8; BLOCK_SIZE];
+    bitmap[0] = 0xF0;
+    bitmap[1] = 0x3F;
+    bitmap[2] = 0xC0;
+    bitmap[(TOTAL_BLOCKS - 1) / 8] |= 1;
+
+    let mut header = vec![0u8; BLOCK_SIZE];
+    header[8] = 1;
+    header[11] = 3;
+    let mut header_record = Vec::new();
+    header_record.extend_from_slice(&1u16.to_be_bytes());
+    header_record.extend_from_slice(&1u32.to_be_bytes());
+    header_record.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    header_record.extend_from_slice(&1u32.to_be_bytes());
+    header_record.extend_from_slice(&1u32.to_be_bytes());
+    header_record.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    header_record.extend_from_slice(&516u16.to_be_bytes());
+    header_record.extend_from_slice(&2u32.to_be_bytes());
+    header[14..14 + header_record.len()].copy_from_slice(&header_record);
+
+    let mut leaf = vec![0u8; BLOCK_SIZE];
+    leaf[8] = 0xFF;
+    leaf[9] = 1;
+    leaf[10..12].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    let mut offset = 14;
+    for (index, record) in records.iter().enumerate() {
+        leaf[offset..offset + record.len()].copy_from_slice(record);
+        let position = BLOCK_SIZE - 2 * (index + 1);
+        leaf[position..position + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+        offset += record.len();
+    }
+    // then the start of the free space
+    let position = BLOCK_SIZE - 2 * (records.len() + 1);
+    leaf[position..position + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+
+    let mut volume_header = Vec::new();
+    volume_header.extend_from_slice(b"H+");
+    volume_header.extend_from_slice(&4u16.to_be_bytes());
+    volume_header.resize(16, 0);
+    for _ in 0..4 {
+        volume_header.extend_from_slice(&date.to_be_bytes());
+    }
+    volume_header.extend_from_slice(&1u32.to_be_bytes());
+    volume_header.extend_from_slice(&2u32.to_be_bytes());
+    volume_header.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+    volume_header.extend_from_slice(&(TOTAL_BLOCKS as u32).to_be_bytes());
+    volume_header.resize(112, 0);
+    volume_header.append(&mut fork(BLOCK_SIZE as u64, 1, 1));
+    volume_header.append(&mut fork(0, 0, 0));
+    volume_header.append(&mut fork(2 * BLOCK_SIZE as u64, 2, 2));
+    volume_header.append(&mut fork(0, 0, 0));
+    volume_header.append(&mut fork(0, 0, 0));
+
+    image[0..1024].iter_mut().for_each(|b| *b = 0);
+    image[1024..1536].copy_from_slice(&volume_header);
+    let alternate = BLOCK_SIZE * TOTAL_BLOCKS - 1024;
+    image[alternate..alternate + 512].copy_from_slice(&volume_header);
+    image[BLOCK_SIZE..2 * BLOCK_SIZE].copy_from_slice(&bitmap);
+    image[2 * BLOCK_SIZE..3 * BLOCK_SIZE].copy_from_slice(&header);
+    image[3 * BLOCK_SIZE..4 * BLOCK_SIZE].copy_from_slice(&leaf);
+    image[10 * BLOCK_SIZE..10 * BLOCK_SIZE + README_SIZE].copy_from_slice(&readme());
+
+    image
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("libdmg-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run convert in dir, returning the SHA-1 of each file it wrote
+fn convert(dir: &Path, image: &str, dmg: &str, args: &[&str]) -> Vec<Vec<u8>> {
+    let status = Command::new(env!("CARGO_BIN_EXE_libdmg"))
+        .current_dir(dir)
+        .env_remove("SOURCE_DATE_EPOCH")
+        .arg("convert")
+        .arg(image)
+        .arg(dmg)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(status.status.success(), "{}", String::from_utf8_lossy(&status.stderr));
+
+    let stem = format!("{}.", dmg.trim_end_matches(".dmg"));
+    let mut outputs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| {
+            let name = p.file_name().unwrap().to_string_lossy();
+            name == dmg || (name.starts_with(&stem) && name.ends_with(".dmgpart"))
+        })
+        .collect();
+    outputs.sort();
+
+    outputs.iter().map(|p| Sha1::digest(std::fs::read(p).unwrap()).to_vec()).collect()
+}
+
+#[test]
+fn builds_twice_with_the_same_hash() {
+    let dir = scratch_dir("reproducible");
+    std::fs::write(dir.join("first.img"), hfs_image(1, false)).unwrap();
+    std::fs::write(dir.join("second.img"), hfs_image(2, true)).unwrap();
+
+    let first = convert(&dir, "first.img", "first.dmg", &["--timestamp", "1700000000"]);
+    let second = convert(&dir, "second.img", "second.dmg", &["--timestamp", "1700000000"]);
+    assert_eq!(first.len(), 1);
+    assert_eq!(first, second);
+
+    // segment ids are derived from the contents
+    let first = convert(&dir, "first.img", "first-seg.dmg", &["--timestamp", "1700000000", "--segment-size", "16k"]);
+    let second = convert(&dir, "second.img", "second-seg.dmg", &["--timestamp", "1700000000", "--segment-size", "16k"]);
+    assert!(first.len() > 1);
+    assert_eq!(first, second);
+
+    // without a timestamp the dates differ
+    let first = convert(&dir, "first.img", "first-dated.dmg", &[]);
+    let second = convert(&dir, "second.img", "second-dated.dmg", &[]);
+    assert_ne!(first, second);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn only_pins_hfs_volumes() {
+    let dir = scratch_dir("not-hfs");
+    std::fs::write(dir.join("blank.img"), vec![0u8; BLOCK_SIZE * TOTAL_BLOCKS]).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_libdmg"))
+        .current_dir(&dir)
+        .arg("convert")
+        .arg("blank.img")
+        .arg("blank.dmg")
+        .args(["--timestamp", "1700000000"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!dir.join("blank.dmg").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}